@@ -0,0 +1,93 @@
+//! Measures how long the first job of a burst waits between submission and
+//! starting to run, when bursts are separated by short idle gaps, and the
+//! CPU time the process uses meanwhile, under each `IdleStrategy`.
+//!
+//! Run with `cargo bench --bench dispatch_latency`. CPU time is read from
+//! `/proc/self/stat`, so is only reported on Linux.
+
+extern crate scoped_thread_pool;
+
+use std::fs;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use scoped_thread_pool::{IdleStrategy, Pool, PoolBuilder};
+
+const BURSTS: usize = 500;
+const JOBS: usize = 8;
+const GAP: Duration = Duration::from_micros(50);
+
+fn run(pool: &Pool) -> Duration {
+    let latencies = Mutex::new(Vec::with_capacity(BURSTS));
+
+    for _ in 0..BURSTS {
+        pool.scoped(|scope| {
+            // The first job of the burst finds every worker idle.
+            let submitted = Instant::now();
+            let latencies = &latencies;
+            scope.execute(move || latencies.lock().unwrap().push(submitted.elapsed()));
+
+            for _ in 1..JOBS {
+                scope.execute(|| {});
+            }
+        });
+
+        // Let the workers go idle before the next burst.
+        sleep(GAP);
+    }
+
+    let mut latencies = latencies.into_inner().unwrap();
+    latencies.sort();
+    latencies[latencies.len() / 2]
+}
+
+// User and system CPU time of the whole process, in clock ticks.
+fn cpu_ticks() -> Option<u64> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // Skip past the command name, which may contain spaces.
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 2..].split(' ').collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+fn main() {
+    let strategies = [
+        IdleStrategy::Park,
+        IdleStrategy::Yield(100),
+        IdleStrategy::Spin(100),
+        IdleStrategy::Yield(10_000),
+        IdleStrategy::Spin(10_000),
+    ];
+
+    for &strategy in &strategies {
+        let pool = PoolBuilder::new().size(4).idle_strategy(strategy).build();
+
+        // Warm up before measuring.
+        run(&pool);
+
+        let before = cpu_ticks();
+        let start = Instant::now();
+        let latency = run(&pool);
+        let wall = start.elapsed();
+
+        let cpu = match (before, cpu_ticks()) {
+            // Ticks are normally 100 a second.
+            (Some(before), Some(after)) => format!(
+                "{:.2} cores busy",
+                (after - before) as f64 / 100.0 / wall.as_secs_f64()
+            ),
+            _ => "CPU time unavailable".to_string(),
+        };
+
+        println!(
+            "{:<14} median dispatch latency {:>8.2?}, {}",
+            format!("{:?}", strategy),
+            latency,
+            cpu
+        );
+
+        pool.shutdown();
+    }
+}
@@ -0,0 +1,120 @@
+//! Measures recursive fork-join work on the default work stealing queue
+//! against a single shared FIFO queue: a parallel quicksort, and a tree of
+//! empty jobs where the cost of scheduling them is all there is.
+//!
+//! Ordered start makes the pool use one shared queue, with no selector to
+//! consult, so it is used here to get the FIFO baseline.
+//!
+//! Run with `cargo bench --bench fork_join`.
+
+extern crate rand;
+extern crate scoped_thread_pool;
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use scoped_thread_pool::{Pool, PoolBuilder, Scope};
+
+const ELEMENTS: usize = 1_000_000;
+const RUNS: usize = 5;
+
+// Below this size, sort sequentially rather than spawning more jobs.
+const CUTOFF: usize = 1_000;
+
+// Levels of the tree of empty jobs, which has twice this many jobs less one.
+const TREE_DEPTH: u32 = 14;
+
+fn quicksort<'a>(scope: &Scope<'a>, data: &'a mut [u64]) {
+    if data.len() <= CUTOFF {
+        data.sort_unstable();
+        return;
+    }
+
+    // Partition around the middle element.
+    let len = data.len();
+    data.swap(len / 2, len - 1);
+    let mut split = 0;
+    for i in 0..len - 1 {
+        if data[i] <= data[len - 1] {
+            data.swap(i, split);
+            split += 1;
+        }
+    }
+    data.swap(split, len - 1);
+
+    let (left, right) = data.split_at_mut(split);
+    let right = &mut right[1..];
+    scope.recurse(move |scope| quicksort(scope, left));
+    scope.recurse(move |scope| quicksort(scope, right));
+}
+
+// A binary tree of jobs doing next to no work, so scheduling dominates.
+fn tree<'a>(scope: &Scope<'a>, depth: u32, leaves: &'a AtomicUsize) {
+    if depth == 0 {
+        leaves.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    scope.recurse(move |scope| tree(scope, depth - 1, leaves));
+    scope.recurse(move |scope| tree(scope, depth - 1, leaves));
+}
+
+fn run_tree(pool: &Pool) -> Duration {
+    let mut total = Duration::ZERO;
+
+    for _ in 0..RUNS {
+        let leaves = AtomicUsize::new(0);
+
+        let start = Instant::now();
+        pool.scoped(|scope| tree(scope, TREE_DEPTH, &leaves));
+        total += start.elapsed();
+
+        assert_eq!(leaves.into_inner(), 1 << TREE_DEPTH);
+    }
+
+    total / RUNS as u32
+}
+
+fn run(pool: &Pool) -> Duration {
+    let mut total = Duration::ZERO;
+
+    for _ in 0..RUNS {
+        let mut data = (0..ELEMENTS).map(|_| rand::random()).collect::<Vec<u64>>();
+
+        let start = Instant::now();
+        pool.scoped(|scope| quicksort(scope, &mut data));
+        total += start.elapsed();
+
+        assert!(data.windows(2).all(|w| w[0] <= w[1]));
+        black_box(&data);
+    }
+
+    total / RUNS as u32
+}
+
+fn main() {
+    let stealing = Pool::new(4);
+    let shared = PoolBuilder::new().size(4).ordered_start(true).build();
+
+    // Warm up both pools before measuring.
+    run(&stealing);
+    run(&shared);
+
+    println!("{:<16} {:>10.2?} per sort", "work stealing", run(&stealing));
+    println!("{:<16} {:>10.2?} per sort", "shared fifo", run(&shared));
+
+    println!(
+        "{:<16} {:>10.2?} per tree",
+        "work stealing",
+        run_tree(&stealing)
+    );
+    println!(
+        "{:<16} {:>10.2?} per tree",
+        "shared fifo",
+        run_tree(&shared)
+    );
+
+    stealing.shutdown();
+    shared.shutdown();
+}
@@ -0,0 +1,56 @@
+//! Compares mapping a cheap operation with a job per element against
+//! `Scope::map_auto`, which uses about one job per worker.
+//!
+//! Run with `cargo bench --bench map_auto`.
+
+extern crate scoped_thread_pool;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use scoped_thread_pool::Pool;
+
+const ITEMS: usize = 100_000;
+const ROUNDS: u32 = 10;
+
+fn measure<F: FnMut()>(name: &str, mut run: F) {
+    // Warm up before measuring.
+    run();
+
+    let mut total = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        run();
+        total += start.elapsed();
+    }
+
+    println!(
+        "{}: {:.2?} per map of {} items",
+        name,
+        total / ROUNDS,
+        ITEMS
+    );
+}
+
+fn main() {
+    let pool = Pool::new(4);
+    let items: Vec<u64> = (0..ITEMS as u64).collect();
+
+    measure("per element", || {
+        let mut out = vec![0; ITEMS];
+        pool.scoped(|scope| {
+            for (slot, item) in out.iter_mut().zip(&items) {
+                scope.execute(move || *slot = black_box(item.wrapping_mul(31)));
+            }
+        });
+        black_box(out);
+    });
+
+    measure("map_auto", || {
+        let out =
+            pool.scoped(|scope| scope.map_auto(&items, |item| black_box(item.wrapping_mul(31))));
+        black_box(out);
+    });
+
+    pool.shutdown();
+}
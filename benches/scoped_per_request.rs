@@ -0,0 +1,81 @@
+//! Measures the allocations and time taken by a short `scoped` call per
+//! request, as a server would make, and by `scoped_reuse` with one group
+//! kept across requests.
+//!
+//! Run with `cargo bench --bench scoped_per_request`.
+
+extern crate scoped_thread_pool;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use scoped_thread_pool::{Pool, WaitGroup};
+
+const REQUESTS: usize = 100_000;
+
+// Counts every allocation made by the process.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn measure<F: Fn(usize) -> usize>(name: &str, handle: F) {
+    // Warm up before measuring.
+    for request in 0..1_000 {
+        handle(request);
+    }
+
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst);
+    let start = Instant::now();
+
+    for request in 0..REQUESTS {
+        black_box(handle(request));
+    }
+
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - allocations;
+
+    println!(
+        "{}: {:.2} allocations and {:.2?} per request",
+        name,
+        allocations as f64 / REQUESTS as f64,
+        elapsed / REQUESTS as u32
+    );
+}
+
+fn main() {
+    let pool = Pool::new(2);
+
+    measure("scoped", |request| {
+        let mut response = 0;
+        pool.scoped(|scope| scope.execute(|| response = black_box(request + 1)));
+        response
+    });
+
+    let group = Arc::new(WaitGroup::new());
+    measure("scoped_reuse", |request| {
+        let mut response = 0;
+        pool.scoped_reuse(&group, |scope| {
+            scope.execute(|| response = black_box(request + 1))
+        });
+        response
+    });
+
+    pool.shutdown();
+}
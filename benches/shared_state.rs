@@ -0,0 +1,78 @@
+//! Compares handing shared state to many jobs through `Scope::with_shared`
+//! against cloning an `Arc` into every job.
+//!
+//! Run with `cargo bench --bench shared_state`.
+
+extern crate scoped_thread_pool;
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use scoped_thread_pool::Pool;
+
+const BATCHES: usize = 200;
+const JOBS: usize = 1_000;
+
+struct State {
+    counter: AtomicUsize,
+}
+
+fn with_arc(pool: &Pool, state: &Arc<State>) -> Duration {
+    let start = Instant::now();
+
+    for _ in 0..BATCHES {
+        pool.scoped(|scope| {
+            for _ in 0..JOBS {
+                let state = state.clone();
+                scope.execute(move || {
+                    black_box(state.counter.load(Ordering::Relaxed));
+                });
+            }
+        });
+    }
+
+    start.elapsed()
+}
+
+fn with_shared(pool: &Pool, state: &State) -> Duration {
+    let start = Instant::now();
+
+    for _ in 0..BATCHES {
+        pool.scoped(|scope| {
+            let shared = scope.with_shared(state);
+            for _ in 0..JOBS {
+                shared.execute(|state| {
+                    black_box(state.counter.load(Ordering::Relaxed));
+                });
+            }
+        });
+    }
+
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let per_job = elapsed / (BATCHES * JOBS) as u32;
+    println!(
+        "{:<16} {:>10.2?} total, {:>8.2?} per job",
+        name, elapsed, per_job
+    );
+}
+
+fn main() {
+    let pool = Pool::new(4);
+    let state = Arc::new(State {
+        counter: AtomicUsize::new(0),
+    });
+
+    // Warm up before measuring.
+    with_arc(&pool, &state);
+    with_shared(&pool, &state);
+
+    report("Arc per job", with_arc(&pool, &state));
+    report("with_shared", with_shared(&pool, &state));
+
+    pool.shutdown();
+}
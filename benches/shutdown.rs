@@ -0,0 +1,37 @@
+//! Measures how long `Pool::shutdown` takes to stop pools of idle workers,
+//! as the number of workers grows.
+//!
+//! Every worker is sent its own Quit, so this should grow far slower than
+//! one hand-off per worker.
+//!
+//! Run with `cargo bench --bench shutdown`.
+
+extern crate scoped_thread_pool;
+
+use std::time::{Duration, Instant};
+
+use scoped_thread_pool::Pool;
+
+const ROUNDS: u32 = 20;
+
+// Time shutting down a pool of `workers` idle threads, averaged over rounds.
+fn measure(workers: usize) -> Duration {
+    let mut total = Duration::ZERO;
+
+    for _ in 0..ROUNDS {
+        let pool = Pool::new(workers);
+        pool.wait_ready(workers);
+
+        let start = Instant::now();
+        pool.shutdown();
+        total += start.elapsed();
+    }
+
+    total / ROUNDS
+}
+
+fn main() {
+    for &workers in &[1, 4, 16, 64] {
+        println!("{} workers: {:.2?} per shutdown", workers, measure(workers));
+    }
+}
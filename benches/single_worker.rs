@@ -0,0 +1,65 @@
+//! Measures the cost of dispatching tiny scoped jobs to a single worker,
+//! with a multi-worker pool for reference.
+//!
+//! A single worker fast path was proposed, with a plain `VecDeque` behind a
+//! `Mutex` and direct `Condvar` notification. Ordered start gives a pool
+//! exactly that queue, so "1 locked deque" measures the fast path against
+//! the general one. Any specialization of the queue or wait group for
+//! single worker pools should be justified against these numbers.
+//!
+//! Run with `cargo bench --bench single_worker`.
+
+extern crate scoped_thread_pool;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use scoped_thread_pool::{Pool, PoolBuilder};
+
+const BATCHES: usize = 2_000;
+const JOBS: usize = 100;
+
+fn run(pool: &Pool) -> Duration {
+    let start = Instant::now();
+
+    for _ in 0..BATCHES {
+        let mut buf = [0u64; JOBS];
+
+        pool.scoped(|scope| {
+            for i in &mut buf {
+                scope.execute(move || *i = black_box(*i + 1));
+            }
+        });
+
+        black_box(&buf);
+    }
+
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let per_job = elapsed / (BATCHES * JOBS) as u32;
+    println!(
+        "{:<16} {:>10.2?} total, {:>8.2?} per job",
+        name, elapsed, per_job
+    );
+}
+
+fn main() {
+    let single = Pool::new(1);
+    let locked = PoolBuilder::new().size(1).ordered_start(true).build();
+    let multi = Pool::new(4);
+
+    // Warm up the pools before measuring.
+    run(&single);
+    run(&locked);
+    run(&multi);
+
+    report("1 worker", run(&single));
+    report("1 locked deque", run(&locked));
+    report("4 workers", run(&multi));
+
+    single.shutdown();
+    locked.shutdown();
+    multi.shutdown();
+}
@@ -0,0 +1,92 @@
+//! Measures the CPU time a lightly loaded pool burns while idle, and how
+//! long its first job after a gap waits to start, with and without
+//! `StealBackoff::Exponential`.
+//!
+//! Run with `cargo bench --bench steal_backoff`. CPU time is read from
+//! `/proc/self/stat`, so is only reported on Linux.
+
+extern crate scoped_thread_pool;
+
+use std::fs;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use scoped_thread_pool::{IdleStrategy, Pool, PoolBuilder, StealBackoff};
+
+const JOBS: usize = 500;
+const GAP: Duration = Duration::from_micros(200);
+
+// Run single jobs separated by idle gaps, returning the median time from
+// submission to the job starting.
+fn run(pool: &Pool) -> Duration {
+    let latencies = Mutex::new(Vec::with_capacity(JOBS));
+
+    for _ in 0..JOBS {
+        pool.scoped(|scope| {
+            let submitted = Instant::now();
+            let latencies = &latencies;
+            scope.execute(move || latencies.lock().unwrap().push(submitted.elapsed()));
+        });
+
+        sleep(GAP);
+    }
+
+    let mut latencies = latencies.into_inner().unwrap();
+    latencies.sort();
+    latencies[latencies.len() / 2]
+}
+
+// User and system CPU time of the whole process, in clock ticks.
+fn cpu_ticks() -> Option<u64> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // Skip past the command name, which may contain spaces.
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 2..].split(' ').collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+fn main() {
+    let backoffs = [
+        StealBackoff::Constant,
+        StealBackoff::Exponential { max_pause: 64 },
+    ];
+
+    for &strategy in &[IdleStrategy::Spin(10_000), IdleStrategy::Yield(10_000)] {
+        for &backoff in &backoffs {
+            let pool = PoolBuilder::new()
+                .size(4)
+                .idle_strategy(strategy)
+                .steal_backoff(backoff)
+                .build();
+
+            // Warm up before measuring.
+            run(&pool);
+
+            let before = cpu_ticks();
+            let start = Instant::now();
+            let latency = run(&pool);
+            let wall = start.elapsed();
+
+            let cpu = match (before, cpu_ticks()) {
+                // Ticks are normally 100 a second.
+                (Some(before), Some(after)) => format!(
+                    "{:.2} cores busy",
+                    (after - before) as f64 / 100.0 / wall.as_secs_f64()
+                ),
+                _ => "CPU time unavailable".to_string(),
+            };
+
+            println!(
+                "{:<14} {:<36} median dispatch latency {:>8.2?}, {}",
+                format!("{:?}", strategy),
+                format!("{:?}", backoff),
+                latency,
+                cpu
+            );
+
+            pool.shutdown();
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Measures `WaitGroup::submit` and `complete` from many threads at once,
+//! on one shared group and on a group per thread.
+//!
+//! The difference between the two is the cost of contending on the shared
+//! group's counters, which is what sharding the pending count would save.
+//! The bench only compares the current code with itself; it doesn't measure
+//! the lock `finish_round` used to take on every round.
+//!
+//! Run with `cargo bench --bench wait_group_contention`.
+
+extern crate scoped_thread_pool;
+
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use scoped_thread_pool::WaitGroup;
+
+const PAIRS: u32 = 1_000_000;
+
+// Time `threads` threads each submitting and completing `PAIRS` times,
+// returning the time per pair. Every `complete` ends a round, as the last
+// job of a batch does, since nobody else holds the group open.
+fn measure(threads: usize, shared: bool) -> Duration {
+    let group = Arc::new(WaitGroup::new());
+    let barrier = Arc::new(Barrier::new(threads + 1));
+
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let group = if shared {
+                group.clone()
+            } else {
+                Arc::new(WaitGroup::new())
+            };
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..PAIRS {
+                    group.submit();
+                    group.complete();
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed() / (threads as u32 * PAIRS)
+}
+
+fn main() {
+    for threads in [1, 2, 4, 8] {
+        let shared = measure(threads, true);
+        let private = measure(threads, false);
+
+        println!(
+            "{} threads: shared {:.1?}, private {:.1?} per pair",
+            threads, shared, private
+        );
+    }
+}
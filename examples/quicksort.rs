@@ -23,11 +23,11 @@ fn do_quicksort<'a, T: Send + Sync + Ord>(scope: &Scope<'a>, data: &'a mut [T])
 
             let split = {
                 // Retrieve the pivot.
-                let mut iter = data.into_iter();
+                let mut iter = data.iter_mut();
                 let pivot = iter.next_back().unwrap();
 
                 // Partition the array.
-                itertools::partition(iter, |val| &*val <= &pivot)
+                itertools::partition(iter, |val| *val <= *pivot)
             };
 
             // Swap the pivot back in at the split point by putting
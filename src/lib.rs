@@ -7,17 +7,34 @@
 //!
 
 extern crate crossbeam;
-extern crate variance;
 
 #[macro_use]
 extern crate scopeguard;
 
-use crossbeam::channel::{Sender, Receiver, unbounded};
-use variance::InvariantLifetime as Id;
-
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Condvar, Mutex};
-use std::{mem, thread};
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+use crossbeam::queue::ArrayQueue;
+
+use std::any::Any;
+use std::cell::Cell;
+#[cfg(feature = "trace")]
+use std::collections::BTreeMap;
+#[cfg(feature = "remote")]
+use std::collections::HashMap;
+use std::collections::VecDeque;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "async")]
+use std::pin::{pin, Pin};
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+#[cfg(feature = "scope-tree")]
+use std::sync::Weak;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, RwLock};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+use std::{error, fmt, hint, io, mem, ptr, thread};
 
 /// A thread-pool providing scoped and unscoped threads.
 ///
@@ -27,6 +44,7 @@ use std::{mem, thread};
 #[derive(Clone, Default)]
 pub struct Pool {
     wait: Arc<WaitGroup>,
+    spawned: Arc<WaitGroup>,
     inner: Arc<PoolInner>,
 }
 
@@ -64,18 +82,10 @@ impl Pool {
     /// shut down the pool.
     #[inline]
     pub fn with_thread_config(size: usize, thread_config: ThreadConfig) -> Pool {
-        // Create an empty pool with configuration.
-        let pool = Pool {
-            inner: Arc::new(PoolInner::with_thread_config(thread_config)),
-            ..Pool::default()
-        };
-
-        // Start the requested number of threads.
-        for _ in 0..size {
-            pool.expand();
-        }
-
-        pool
+        PoolBuilder::new()
+            .size(size)
+            .thread_config(thread_config)
+            .build()
     }
 
     /// Create an empty Pool, with no threads.
@@ -96,6 +106,145 @@ impl Pool {
         self.wait.waiting()
     }
 
+    /// Block until at least `n` worker threads are running.
+    ///
+    /// `workers` counts a thread as soon as it is spawned, but the thread may
+    /// take a while to start, so right after `Pool::new` some workers may not
+    /// yet be taking jobs. This waits for `n` of them to have started their
+    /// loop, which is useful to tests and benchmarks which need the full
+    /// pool in place. It says nothing about whether the workers are idle.
+    ///
+    /// Blocks forever if the pool never has `n` workers running.
+    pub fn wait_ready(&self, n: usize) {
+        let mut workers = self.inner.workers.lock().unwrap();
+
+        while workers.len() < n {
+            workers = self.inner.worker_ready.wait(workers).unwrap();
+        }
+    }
+
+    /// Block until at most `target` worker threads are running.
+    ///
+    /// This complements `wait_ready`, for staged teardown: after `contract`
+    /// or `compact`, it waits for the surplus threads to actually exit, as
+    /// counted by `workers`. The waiter sleeps until workers exit rather
+    /// than polling. Threads started concurrently, by `expand` or the
+    /// autoscaler, can keep the count from ever reaching the target.
+    pub fn wait_workers(&self, target: usize) {
+        let mut workers = self.inner.workers.lock().unwrap();
+
+        while self.workers() > target {
+            workers = self.inner.worker_ready.wait(workers).unwrap();
+        }
+    }
+
+    /// Like `wait_workers`, but gives up after `timeout`.
+    ///
+    /// Returns whether the count got down to `target` in time.
+    pub fn wait_workers_timeout(&self, target: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut workers = self.inner.workers.lock().unwrap();
+
+        while self.workers() > target {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+
+            workers = self
+                .inner
+                .worker_ready
+                .wait_timeout(workers, deadline - now)
+                .unwrap()
+                .0;
+        }
+
+        true
+    }
+
+    /// How many worker threads the pool is meant to have.
+    ///
+    /// This is the size the pool was built with, adjusted by every `expand`
+    /// and `contract`, and zeroed by shutdown. Threads restarted after a
+    /// panic don't change it, so a persistent difference from `workers`
+    /// points at threads dying or failing to start.
+    #[inline]
+    pub fn configured_workers(&self) -> usize {
+        self.inner.configured.load(Ordering::SeqCst)
+    }
+
+    /// Each worker's fraction of time spent running jobs, in `[0, 1]`.
+    ///
+    /// The window for each worker runs from the previous call to this method,
+    /// or from when the worker started, until now. Restarted workers start
+    /// again with fresh counters, and workers are listed in the order they
+    /// started.
+    ///
+    /// Only available with the `utilization` feature, since timing every job
+    /// adds overhead to the workers.
+    #[cfg(feature = "utilization")]
+    pub fn worker_utilization(&self) -> Vec<f64> {
+        let workers = self.inner.workers.lock().unwrap();
+        workers.iter().map(|worker| worker.clock.sample()).collect()
+    }
+
+    /// The label of the job each worker is running, in the order the workers
+    /// started.
+    ///
+    /// Jobs are labeled with `Scope::execute_labeled`. Idle workers and
+    /// workers running unlabeled jobs show `None`. This is a snapshot, so
+    /// workers may have moved on by the time it returns.
+    pub fn current_jobs(&self) -> Vec<Option<&'static str>> {
+        let workers = self.inner.workers.lock().unwrap();
+        workers
+            .iter()
+            .map(|worker| {
+                let current = worker.current.lock().unwrap();
+                current.as_ref().and_then(|job| job.label)
+            })
+            .collect()
+    }
+
+    /// Set a callback to run each time the pool becomes idle, replacing any
+    /// previous one.
+    ///
+    /// The pool is idle when no jobs are queued or running. The callback runs
+    /// once per transition from busy to idle, on the worker which finished
+    /// the last job, after that job's scope has been notified of completion.
+    ///
+    /// Work submitted concurrently with the last job finishing races with
+    /// the callback: by the time it runs, the pool may be busy again. Like a
+    /// job, the callback must not panic.
+    pub fn on_idle(&self, callback: Box<dyn Fn() + Send + Sync>) {
+        *self.inner.idle_callback.write().unwrap() = Some(Arc::from(callback));
+    }
+
+    /// How many jobs are waiting in the queue, not counting running jobs.
+    #[inline]
+    pub fn queued_jobs(&self) -> usize {
+        self.inner.queued.load(Ordering::SeqCst)
+    }
+
+    /// The most jobs which have ever run at once on this pool.
+    ///
+    /// Compare it to `workers` to see whether the pool is ever fully used,
+    /// or to the limit from `PoolBuilder::max_concurrent_tasks`.
+    #[inline]
+    pub fn peak_concurrency(&self) -> usize {
+        self.inner.peak_running.load(Ordering::SeqCst)
+    }
+
+    /// Whether more than `threshold` jobs are waiting in the queue.
+    ///
+    /// This is a cheap check for admission control, so callers can turn
+    /// work away early rather than queue it behind a backlog. Jobs are
+    /// queued and taken concurrently, so the answer is only an estimate
+    /// which may be stale by the time it's returned.
+    #[inline]
+    pub fn is_overloaded(&self, threshold: usize) -> bool {
+        self.queued_jobs() > threshold
+    }
+
     /// Spawn a `'static'` job to be run on this pool.
     ///
     /// We do not wait on the job to complete.
@@ -103,8 +252,163 @@ impl Pool {
     /// Panics in the job will propogate to the calling thread.
     #[inline]
     pub fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.detach(Lane::Normal, job)
+    }
+
+    /// Like `spawn`, but the job goes ahead of all work queued normally.
+    ///
+    /// The next idle worker takes the oldest urgent job before any other,
+    /// including jobs on its own deque, so this suits short, latency
+    /// sensitive work like flushing or cancelling. Urgent jobs run in the
+    /// order they were spawned, though several may run at once on different
+    /// workers. A shutdown still goes ahead of urgent jobs spawned before it,
+    /// which then run as the queue is drained.
+    #[inline]
+    pub fn spawn_urgent<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.detach(Lane::Urgent, job)
+    }
+
+    /// Spawn a serializable task, shipping it through the pool's transport
+    /// if it has one.
+    ///
+    /// With no transport, or if the transport refuses the task, the task is
+    /// `spawn`ed on this pool instead. Results and panics of remote tasks
+    /// are the business of the remote side; like `spawn`, nothing waits for
+    /// them here.
+    ///
+    /// Only available with the `remote` feature.
+    #[cfg(feature = "remote")]
+    pub fn spawn_remote<T: SerializableTask>(&self, task: T) {
+        if let Some(ref transport) = self.inner.transport {
+            let encoded = EncodedTask {
+                name: T::NAME.to_string(),
+                payload: task.serialize(),
+            };
+
+            if transport.send(encoded).is_ok() {
+                return;
+            }
+        }
+
+        self.spawn(move || task.run())
+    }
+
+    fn detach<F: FnOnce() + Send + 'static>(&self, lane: Lane, job: F) {
+        self.check_open();
+
         // Run the job on a scope which lasts forever, and won't block.
-        Scope::forever(self.clone()).execute(job)
+        // The scope shares the pool's WaitGroup for spawned jobs, so
+        // they can be tracked by `is_spawned_work_complete`.
+        let scope = Scope {
+            pool: self.clone(),
+            wait: self.spawned.clone(),
+            depth: 0,
+            id: next_scope_id(),
+            parent: None,
+            _scope: Id::default(),
+        };
+
+        match self.inner.detached_panic_policy {
+            DetachedPanicPolicy::Propagate => scope.submit(0, None, lane, job),
+            // The panic hook has already reported the panic by the time it
+            // is caught.
+            DetachedPanicPolicy::CatchAndLog => scope.submit(0, None, lane, move || {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }),
+        }
+    }
+
+    /// Queue a job which completes a caller-supplied WaitGroup.
+    ///
+    /// This is a low level escape hatch for coordinating completion with
+    /// your own groups, across many submissions or several pools. The
+    /// accounting is up to the caller: `wait.submit()` must be called once
+    /// for each job *before* it is passed here, and the pool then completes
+    /// the group once the job has run, or poisons it if the job panics.
+    /// Failing to submit first lets a `join` return early, or makes the
+    /// group's count underflow. Like `Scope::execute`, this panics if the
+    /// pool is dead, or shut down with no workers left to run the job.
+    pub fn submit_raw(&self, task: Box<dyn FnOnce() + Send + 'static>, wait: Arc<WaitGroup>) {
+        let scope = Scope {
+            pool: self.clone(),
+            wait,
+            depth: 0,
+            id: next_scope_id(),
+            parent: None,
+            _scope: Id::default(),
+        };
+
+        let bytes = mem::size_of_val(&*task);
+        scope.admit(bytes);
+        scope.enqueue(bytes, 0, None, Lane::Normal, task)
+    }
+
+    /// Spawn a `'static'` future to be driven to completion on this pool.
+    ///
+    /// Like `Scope::execute_async`, a worker blocks on the future until it
+    /// completes, so this suits CPU-bound futures which rarely wait. Futures
+    /// which spend most of their time pending each tie up a worker while
+    /// they do. Otherwise this behaves like `spawn`.
+    ///
+    /// This is what `futures_task::Spawn::spawn_obj` would do, but the crate
+    /// doesn't depend on `futures-task`, so `Pool` doesn't implement that
+    /// trait. Code expecting a `Spawn` needs a wrapper which forwards here.
+    ///
+    /// Only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn spawn_future<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn(move || block_on(future))
+    }
+
+    /// Run a blocking job on the pool, returning a future which resolves
+    /// with its output.
+    ///
+    /// This is the bridge for async code offloading CPU-bound work: the
+    /// calling task is woken once the job finishes, and no thread is blocked
+    /// waiting meanwhile. A panic in the job is propogated when the future
+    /// is polled instead of poisoning the pool. Dropping the future doesn't
+    /// cancel the job, which still runs, with its output or panic discarded.
+    ///
+    /// Only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn spawn_blocking<F, T>(&self, job: F) -> SpawnBlocking<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(BlockingSlot {
+            output: None,
+            waker: None,
+        }));
+        let sender = slot.clone();
+
+        self.spawn(move || {
+            let output = panic::catch_unwind(AssertUnwindSafe(job));
+
+            // Wake outside the lock, so the task can poll straight away.
+            let waker = {
+                let mut slot = sender.lock().unwrap();
+                slot.output = Some(output);
+                slot.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        });
+
+        SpawnBlocking { slot }
+    }
+
+    /// Whether every job `spawn`ed on this pool has finished.
+    ///
+    /// This never blocks. The answer is only a snapshot: a concurrent
+    /// `spawn` may make it stale by the time it is returned.
+    #[inline]
+    pub fn is_spawned_work_complete(&self) -> bool {
+        self.spawned.waiting() == 0
     }
 
     /// Create a Scope for scheduling a group of jobs in `'scope'`.
@@ -119,684 +423,10081 @@ impl Pool {
     where
         F: FnOnce(&Scope<'scope>) -> R,
     {
-        // Zoom to the correct scope, then run the scheduler.
-        Scope::forever(self.clone()).zoom(scheduler)
+        self.check_open();
+
+        // Zoom to the correct scope, then run the scheduler. The forever
+        // scope is only a stepping stone, so the new scope has no parent.
+        let mut scope = unsafe { Scope::forever(self.clone()).refine() };
+        scope.parent = None;
+
+        defer!(scope.join());
+        scheduler(&scope)
     }
 
-    /// Shutdown the Pool.
+    /// Create a Scope like `scoped`, with the panic handling of
+    /// `std::thread::scope`.
     ///
-    /// WARNING: Extreme care should be taken to not call shutdown concurrently
-    /// with any scoped calls, or deadlock can occur.
+    /// Every job has finished before this returns, however the scheduler
+    /// exits. If the scheduler panics, the jobs are still joined first, and
+    /// then the scheduler's panic is propogated, taking precedence over any
+    /// panic in the jobs. `scoped` instead joins while unwinding, so a job
+    /// panicking as well aborts the process.
     ///
-    /// All threads will be shut down eventually, but only threads started before the
-    /// call to shutdown are guaranteed to be shut down before the call to shutdown
-    /// returns.
-    #[inline]
-    pub fn shutdown(&self) {
-        // Start the shutdown process.
-        self.inner.queue.push(PoolMessage::Quit);
+    /// As with `scoped`, `Scope` being invariant in `'scope` means jobs can
+    /// only borrow data which outlives the call, so borrows are checked at
+    /// compile time. Jobs can't borrow the scheduler's locals:
+    ///
+    /// ```compile_fail
+    /// # use scoped_thread_pool::Pool;
+    /// let pool = Pool::new(1);
+    /// pool.scope_structured(|scope| {
+    ///     let data = vec![1, 2, 3];
+    ///     scope.execute(|| println!("{:?}", data));
+    /// });
+    /// ```
+    ///
+    /// Nor can borrowed data be freed while the jobs may still use it:
+    ///
+    /// ```compile_fail
+    /// # use scoped_thread_pool::Pool;
+    /// let pool = Pool::new(1);
+    /// let data = vec![1, 2, 3];
+    /// pool.scope_structured(|scope| {
+    ///     scope.execute(|| println!("{:?}", data));
+    ///     drop(data);
+    /// });
+    /// ```
+    pub fn scope_structured<'scope, F, R>(&self, scheduler: F) -> R
+    where
+        F: FnOnce(&Scope<'scope>) -> R,
+    {
+        self.check_open();
 
-        // Wait for it to complete.
-        self.wait.join()
+        let mut scope = unsafe { Scope::forever(self.clone()).refine() };
+        scope.parent = None;
+
+        let scheduled = panic::catch_unwind(AssertUnwindSafe(|| scheduler(&scope)));
+        let joined = panic::catch_unwind(AssertUnwindSafe(|| scope.join()));
+
+        match (scheduled, joined) {
+            (Err(payload), _) | (Ok(_), Err(payload)) => panic::resume_unwind(payload),
+            (Ok(output), Ok(())) => output,
+        }
     }
 
-    /// Expand the Pool by spawning an additional thread.
+    /// Like `scoped`, but tracks the jobs with a caller-provided WaitGroup.
     ///
-    /// Can accelerate the completion of running jobs.
-    #[inline]
-    pub fn expand(&self) {
-        let pool = self.clone();
+    /// This is a lower level alternative to `scoped` for hot loops, which
+    /// keeps one group across many calls instead of taking one from the
+    /// pool each time. The group must be idle on entry, with no pending
+    /// submits, and this panics if it isn't. A group poisoned by a panicking
+    /// job stays poisoned, so `reset` it before reusing it. Values stored
+    /// with `Scope::insert` are kept in the group, and so outlive the call.
+    pub fn scoped_reuse<'scope, F, R>(&self, group: &Arc<WaitGroup>, scheduler: F) -> R
+    where
+        F: FnOnce(&Scope<'scope>) -> R,
+    {
+        self.check_open();
+        assert_eq!(group.waiting(), 0, "scoped_reuse needs an idle WaitGroup");
+
+        let scope = Scope {
+            pool: self.clone(),
+            wait: group.clone(),
+            depth: 1,
+            id: next_scope_id(),
+            parent: None,
+            _scope: Id::default(),
+        };
 
-        // Submit the new thread to the thread waitgroup.
-        pool.wait.submit();
+        // Join the scope either on completion of the scheduler or panic.
+        defer!(scope.join());
+        scheduler(&scope)
+    }
 
-        let thread_number = self.inner.thread_counter.fetch_add(1, Ordering::SeqCst);
+    /// Like `scoped`, but also measures the batch of jobs.
+    ///
+    /// Alongside the result, returns the wall time from the start of the
+    /// call until every job had finished, and per-job running times. Only
+    /// jobs run on this scope itself are timed, including `recurse`d ones but
+    /// not those of subscopes, and a job which panics isn't counted. Timing
+    /// costs each job two clock reads; plain `scoped` doesn't pay for them.
+    pub fn scoped_timed<'scope, F, R>(&self, scheduler: F) -> (R, BatchStats)
+    where
+        F: FnOnce(&Scope<'scope>) -> R,
+    {
+        self.check_open();
+        let start = Instant::now();
+        let timings = Arc::new(BatchTimings::default());
+
+        let mut wait = self.inner.wait_group();
+        // The group is new, so nothing else refers to it yet.
+        Arc::get_mut(&mut wait).unwrap().timings = Some(timings.clone());
+
+        let scope = Scope {
+            pool: self.clone(),
+            wait,
+            depth: 1,
+            id: next_scope_id(),
+            parent: None,
+            _scope: Id::default(),
+        };
 
-        // Deal with thread configuration.
-        let mut builder = thread::Builder::new();
-        if let Some(ref prefix) = self.inner.thread_config.prefix {
-            let name = format!("{}{}", prefix, thread_number);
-            builder = builder.name(name);
-        }
-        if let Some(stack_size) = self.inner.thread_config.stack_size {
-            builder = builder.stack_size(stack_size);
-        }
+        let result = {
+            defer!(scope.join());
+            scheduler(&scope)
+        };
 
-        // Start the actual thread.
-        builder.spawn(move || pool.run_thread()).unwrap();
+        (result, timings.stats(start.elapsed()))
     }
 
-    fn run_thread(self) {
-        // Create a sentinel to capture panics on this thread.
-        let mut thread_sentinel = ThreadSentinel(Some(self.clone()));
-
-        loop {
-            match self.inner.queue.pop() {
-                // On Quit, repropogate and quit.
-                PoolMessage::Quit => {
-                    // Repropogate the Quit message to other threads.
-                    self.inner.queue.push(PoolMessage::Quit);
+    /// Like `scoped`, but catches a panic from the scheduler or any job and
+    /// returns it as an `Err` instead of propogating it.
+    ///
+    /// The scope is still joined before returning, so every job has finished
+    /// by the time the result is available.
+    #[inline]
+    pub fn try_scoped<'scope, F, R>(&self, scheduler: F) -> thread::Result<R>
+    where
+        F: FnOnce(&Scope<'scope>) -> R,
+    {
+        panic::catch_unwind(AssertUnwindSafe(|| self.scoped(scheduler)))
+    }
 
-                    // Cancel the thread sentinel so we don't panic waiting
-                    // shutdown threads, and don't restart the thread.
-                    thread_sentinel.cancel();
+    /// Run a parallel search, returning the first result any job reports.
+    ///
+    /// The scheduler is handed a `Finder` alongside the scope, which jobs
+    /// clone and `report` their result to. The first report wins and aborts
+    /// the scope, so its jobs which haven't started are skipped. Jobs which
+    /// are already running aren't stopped, and this returns once they have
+    /// finished; long jobs should check `Finder::is_found` to give up early.
+    /// Returns `None` if no job reports anything.
+    pub fn scoped_find<'scope, T, F>(&self, scheduler: F) -> Option<T>
+    where
+        T: Send,
+        F: FnOnce(&Scope<'scope>, &Finder<T>),
+    {
+        let found = Arc::new(Mutex::new(None));
+
+        self.scoped(|scope| {
+            let finder = Finder {
+                abort: scope.abort_handle(),
+                found: found.clone(),
+            };
+            scheduler(scope, &finder)
+        });
 
-                    // Terminate the thread.
-                    break;
-                }
+        let result = found.lock().unwrap().take();
+        result
+    }
 
-                // On Task, run the task then complete the WaitGroup.
-                PoolMessage::Task(job, wait) => {
-                    let sentinel = Sentinel(self.clone(), Some(wait.clone()));
-                    job.run();
-                    sentinel.cancel();
-                }
-            }
+    /// Run a batch of jobs, waiting for them only until `deadline`.
+    ///
+    /// The scheduler runs first, then its jobs are joined as with `scoped`.
+    /// If they have not all finished by the deadline, `Err(Timeout)` is
+    /// returned and the remaining jobs are left to complete in the
+    /// background; running jobs are never forcibly stopped. The `Timeout`
+    /// carries the scheduler's result and the scope's `CancelToken`, for
+    /// skipping the jobs which haven't started yet.
+    ///
+    /// Because jobs may outlive the call, they are scheduled on a
+    /// `Scope<'static>` and can't borrow from the caller.
+    pub fn scoped_deadline<F, R>(&self, deadline: Instant, scheduler: F) -> Result<R, Timeout<R>>
+    where
+        F: FnOnce(&Scope<'static>) -> R,
+    {
+        self.check_open();
+        let scope = Scope::forever(self.clone());
+        let result = scheduler(&scope);
+
+        if scope.join_deadline(deadline) {
+            Ok(result)
+        } else {
+            Err(Timeout {
+                result,
+                token: scope.cancel_token(),
+            })
         }
     }
-}
 
-struct BlockingQueue<T> {
-    sender: Sender<T>,
-    receiver: Receiver<T>,
-}
+    /// Run `f` once on each worker thread, returning when all have done so.
+    ///
+    /// This is for setting up thread locals or warming caches on every
+    /// worker. Each worker which runs `f` then waits for the others to get to
+    /// it, so no worker can take a second turn, which means `broadcast` waits
+    /// for every worker to finish whatever it was running first. If called
+    /// from one of the pool's own workers, that worker runs `f` directly.
+    ///
+    /// `f` runs on the workers present when `broadcast` is called, or on as
+    /// many of them as remain if some retire or are shut down part way
+    /// through. A worker started in the meantime may take the place of one
+    /// which doesn't get to run `f`. Panics in `f` propogate as they would
+    /// from `scoped`.
+    pub fn broadcast<F>(&self, f: F)
+    where
+        F: Fn() + Sync,
+    {
+        let current = thread::current().id();
+        let on_worker = self
+            .inner
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|worker| worker.thread == current);
+
+        // Count threads rather than registered workers, which includes any
+        // which are still starting up.
+        let total = self.workers();
+
+        let broadcast = Broadcast {
+            ran: Mutex::new(Vec::new()),
+            total,
+        };
 
-impl<T> BlockingQueue<T> {
-    fn new() -> BlockingQueue<T> {
-        let (tx, rx) = unbounded();
-        BlockingQueue {
-            sender: tx,
-            receiver: rx,
+        // This worker can't wait for a turn while it waits for the others.
+        if on_worker {
+            broadcast.ran.lock().unwrap().push(current);
+            f();
         }
-    }
 
-    fn pop(&self) -> T {
-        self.receiver.recv().unwrap()
+        self.scoped(|scope| {
+            for _ in on_worker as usize..total {
+                scope.execute(|| broadcast.run(self, &f));
+            }
+        });
     }
 
-    fn push(&self, message: T) {
-        self.sender.send(message).unwrap();
+    /// Have every worker run one trivial job, so the first real batch
+    /// doesn't wait for threads to start.
+    ///
+    /// Threads spawned by `Pool::new` may not have been scheduled yet, and
+    /// the first job on each pays for starting it, faulting in its stack and
+    /// setting up its thread locals. This takes that cost up front: like
+    /// `broadcast`, which it is built on, each worker runs exactly one
+    /// warm-up job, and this returns once they all have. It is best effort,
+    /// since the OS can still deschedule or page out an idle thread later,
+    /// and threads started afterwards, by `expand` or restarts, are cold.
+    pub fn prewarm(&self) {
+        self.broadcast(|| {
+            // Touch a page of stack.
+            hint::black_box([0u8; 4096]);
+        });
     }
-}
-
-struct PoolInner {
-    queue: BlockingQueue<PoolMessage>,
-    thread_config: ThreadConfig,
-    thread_counter: AtomicUsize,
-}
 
-impl PoolInner {
-    fn with_thread_config(thread_config: ThreadConfig) -> Self {
-        PoolInner {
-            thread_config,
-            ..Self::default()
+    /// Create a scope which is joined when the returned guard is dropped.
+    ///
+    /// This is for scheduling onto a scope directly, as with
+    /// `Scope::forever`, without having to remember the join. Since a guard
+    /// can be leaked without being dropped, it can't promise to join before
+    /// borrowed data goes away, so the scope is `'static`; use `scoped` for
+    /// jobs which borrow from the stack.
+    pub fn scope_guard(&self) -> ScopeGuard {
+        self.check_open();
+        ScopeGuard {
+            scope: Scope::forever(self.clone()),
         }
     }
-}
 
-impl Default for PoolInner {
-    fn default() -> Self {
-        PoolInner {
-            queue: BlockingQueue::new(),
-            thread_config: ThreadConfig::default(),
-            thread_counter: AtomicUsize::new(1),
-        }
+    /// Take the trace recorded so far, leaving an empty one to continue
+    /// recording into.
+    ///
+    /// Returns `None` unless the pool was built with
+    /// `PoolBuilder::record_trace`. Only available with the `trace` feature.
+    #[cfg(feature = "trace")]
+    pub fn take_trace(&self) -> Option<Trace> {
+        let recording = self.inner.recording.as_ref()?;
+        let order = mem::take(&mut *recording.lock().unwrap());
+        Some(Trace { order })
     }
-}
-
-/// Thread configuration. Provides detailed control over the properties and behavior of new
-/// threads.
-#[derive(Default)]
-pub struct ThreadConfig {
-    prefix: Option<String>,
-    stack_size: Option<usize>,
-}
 
-impl ThreadConfig {
-    /// Generates the base configuration for spawning a thread, from which configuration methods
-    /// can be chained.
-    pub fn new() -> ThreadConfig {
-        ThreadConfig {
-            prefix: None,
-            stack_size: None,
+    /// Create a TypedPool, which runs jobs returning `T` on this pool's
+    /// workers and streams their results on one channel.
+    #[inline]
+    pub fn typed<T: Send + 'static>(&self) -> TypedPool<T> {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        TypedPool {
+            pool: self.clone(),
+            sender,
+            receiver,
         }
     }
 
-    /// Name prefix of spawned threads. Thread number will be appended to this prefix to form each
-    /// thread's unique name. Currently the name is used for identification only in panic
-    /// messages.
-    pub fn prefix<S: Into<String>>(self, prefix: S) -> ThreadConfig {
-        ThreadConfig {
-            prefix: Some(prefix.into()),
-            ..self
+    /// Create a Subpool, which shares this pool's worker threads but tracks
+    /// and shuts down its own work independently.
+    #[inline]
+    pub fn subpool(&self) -> Subpool {
+        Subpool {
+            pool: self.clone(),
+            wait: self.inner.wait_group(),
+            closed: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    /// Sets the size of the stack for the new thread.
-    pub fn stack_size(self, stack_size: usize) -> ThreadConfig {
-        ThreadConfig {
-            stack_size: Some(stack_size),
-            ..self
+    /// Discard every job waiting in the queue, returning how many there were.
+    ///
+    /// The discarded jobs never run, and are marked complete so `join`s
+    /// waiting on them don't hang. Jobs which are already running are not
+    /// affected, and a pending shutdown is preserved. `queued_jobs` falls as
+    /// each job is discarded, so it can be read while this runs.
+    pub fn clear_queue(&self) -> usize {
+        let removed = self
+            .inner
+            .queue
+            .remove_where(|message| matches!(*message, PoolMessage::Task(_)));
+
+        let count = removed.len();
+        for message in removed {
+            if let PoolMessage::Task(job) = message {
+                self.inner.discard(job);
+            }
         }
+
+        count
     }
-}
 
-/// An execution scope, represents a set of jobs running on a Pool.
-///
-/// ## Understanding Scope lifetimes
-///
-/// Besides `Scope<'static>`, all `Scope` objects are accessed behind a
-/// reference of the form `&'scheduler Scope<'scope>`.
-///
-/// `'scheduler` is the lifetime associated with the *body* of the
-/// "scheduler" function (functions passed to `zoom`/`scoped`).
-///
-/// `'scope` is the lifetime which data captured in `execute` or `recurse`
-/// closures must outlive - in other words, `'scope` is the maximum lifetime
-/// of all jobs scheduler on a `Scope`.
-///
-/// Note that since `'scope: 'scheduler` (`'scope` outlives `'scheduler`)
-/// `&'scheduler Scope<'scope>` can't be captured in an `execute` closure;
-/// this is the reason for the existence of the `recurse` API, which will
-/// inject the same scope with a new `'scheduler` lifetime (this time set
-/// to the body of the function passed to `recurse`).
-pub struct Scope<'scope> {
-    pool: Pool,
-    wait: Arc<WaitGroup>,
-    _scope: Id<'scope>,
-}
-
-impl<'scope> Scope<'scope> {
-    /// Create a Scope which lasts forever.
+    /// Shutdown the Pool.
+    ///
+    /// Before exiting, each worker runs every job still in the queue, including
+    /// jobs submitted after the call to shutdown, so scoped calls running
+    /// concurrently with shutdown still complete. New batches and spawns are
+    /// refused with a panic as soon as shutdown starts, as are jobs submitted
+    /// once every worker has exited.
+    ///
+    /// All threads will be shut down eventually, but only threads started before the
+    /// call to shutdown are guaranteed to be shut down before the call to shutdown
+    /// returns.
     #[inline]
-    pub fn forever(pool: Pool) -> Scope<'static> {
-        Scope {
-            pool,
-            wait: Arc::new(WaitGroup::new()),
-            _scope: Id::default(),
-        }
+    pub fn shutdown(&self) {
+        self.shutdown_with(ShutdownPolicy::Drain);
     }
 
-    /// Add a job to this scope.
+    /// Shutdown the Pool, handling queued jobs according to `policy`.
     ///
-    /// Subsequent calls to `join` will wait for this job to complete.
-    pub fn execute<F>(&self, job: F)
-    where
-        F: FnOnce() + Send + 'scope,
-    {
-        // Submit the job *before* submitting it to the queue.
-        self.wait.submit();
+    /// Every job is either run or discarded, and discarded jobs are marked
+    /// complete, so no `join` is left waiting on the pool. Jobs which are
+    /// already running are never interrupted, so this can wait past a
+    /// `DrainTimeout` deadline for them to finish. Workers which panic while
+    /// shutting down aren't replaced, and if none are left, the jobs they
+    /// didn't get to are discarded too. As with `shutdown`, only threads
+    /// started before the call are guaranteed to have exited when it returns.
+    pub fn shutdown_with(&self, policy: ShutdownPolicy) -> ShutdownSummary {
+        // Stop resizing before the workers start exiting.
+        if let Some(ref control) = self.inner.autoscaler {
+            if control.stop() {
+                control.running.join();
+            }
+        }
 
-        let task = unsafe {
-            // Safe because we will ensure the task finishes executing before
-            // 'scope via joining before the resolution of `'scope`.
-            mem::transmute::<Box<dyn Task + Send + 'scope>, Box<dyn Task + Send + 'static>>(
-                Box::new(job),
-            )
-        };
+        // The idle monitor, or the slow task handler, may be the one
+        // shutting down, so neither is waited for.
+        if let Some(ref control) = self.inner.idle_monitor {
+            control.stop();
+        }
+        if let Some(ref control) = self.inner.slow_task_monitor {
+            control.stop();
+        }
 
-        // Submit the task to be executed.
-        self.pool
-            .inner
-            .queue
-            .push(PoolMessage::Task(task, self.wait.clone()));
-    }
+        // Nor is the joiner, which exits once it has called back every
+        // scope it is watching.
+        self.inner.joiner.stop();
 
-    /// Add a job to this scope which itself will get access to the scope.
-    ///
-    /// Like with `execute`, subsequent calls to `join` will wait for this
-    /// job (and all jobs scheduled on the scope it receives) to complete.
-    pub fn recurse<F>(&self, job: F)
-    where
-        F: FnOnce(&Self) + Send + 'scope,
-    {
-        // Create another scope with the *same* lifetime.
-        let this = unsafe { self.clone() };
+        self.inner.shut_down.store(true, Ordering::SeqCst);
 
-        self.execute(move || job(&this));
-    }
+        let ran_before = self.inner.jobs_run.load(Ordering::SeqCst);
+        let mut dropped = 0;
 
-    /// Create a new subscope, bound to a lifetime smaller than our existing Scope.
-    ///
-    /// The subscope has a different job set, and is joined before zoom returns.
-    pub fn zoom<'smaller, F, R>(&self, scheduler: F) -> R
-    where
-        F: FnOnce(&Scope<'smaller>) -> R,
-        'scope: 'smaller,
-    {
-        let scope = unsafe { self.refine() };
+        match policy {
+            ShutdownPolicy::Immediate => dropped += self.clear_queue(),
+            // A job a worker takes while this runs is let finish, like
+            // one already running.
+            ShutdownPolicy::CancelSpawned => dropped += self.inner.cancel(&self.spawned),
+            _ => {}
+        }
 
-        // Join the scope either on completion of the scheduler or panic.
-        defer!(scope.join());
+        // Start the shutdown process. The Quits go ahead of queued work so
+        // a stream of urgent spawns can't hold them back; the workers taking
+        // them run the rest of the queue on the way out. There's one for
+        // each worker, each used up by the worker taking it, so they all
+        // stop at once and the queue is left empty. Quits only stay queued
+        // when there are fewer workers to take them: the one pushed with no
+        // workers running, kept for the next thread started, and those of
+        // workers which retire or panic before taking theirs.
+        self.inner.configured.store(0, Ordering::SeqCst);
+        for _ in 0..self.workers().max(1) {
+            self.inner.queue.push_urgent(PoolMessage::Quit);
+        }
 
-        // Schedule all tasks then join all tasks
-        scheduler(&scope)
+        // Wait for it to complete, abandoning the queue at the deadline.
+        if let ShutdownPolicy::DrainTimeout(timeout) = policy {
+            if !self.wait.join_timeout(timeout) {
+                dropped += self.clear_queue();
+            }
+        }
+        let joined = panic::catch_unwind(AssertUnwindSafe(|| self.wait.join()));
+
+        // Workers which panic while draining aren't replaced, so if they
+        // all did, the jobs they didn't get to would never run.
+        dropped += self.clear_queue();
+
+        // No jobs are left to time out.
+        let watchdog = &self.inner.watchdog;
+        let started = {
+            let mut timers = watchdog.timers.lock().unwrap();
+            timers.stopped = true;
+            watchdog.wake.notify_all();
+            timers.started
+        };
+        if started {
+            watchdog.running.join();
+        }
+
+        if let Err(payload) = joined {
+            panic::resume_unwind(payload)
+        }
+
+        ShutdownSummary {
+            ran: self.inner.jobs_run.load(Ordering::SeqCst) - ran_before,
+            dropped,
+        }
     }
 
-    /// Awaits all jobs submitted on this Scope to be completed.
+    /// Whether `shutdown` or `shutdown_with` has been called on this pool.
     ///
-    /// Only guaranteed to join jobs which where `execute`d logically
-    /// prior to `join`. Jobs `execute`d concurrently with `join` may
-    /// or may not be completed before `join` returns.
+    /// Once it has, `scoped` and its variants, `scope_guard` and `spawn`
+    /// panic instead of queueing work which might never run.
     #[inline]
-    pub fn join(&self) {
-        self.wait.join()
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.shut_down.load(Ordering::SeqCst)
     }
 
+    /// Whether a worker panicked in a pool built with `fail_fast`.
+    ///
+    /// A dead pool stays dead: it runs no more jobs, and `scoped` and its
+    /// variants, `scope_guard`, `spawn` and `Scope::execute` all panic.
     #[inline]
-    unsafe fn clone(&self) -> Self {
-        Scope {
-            pool: self.pool.clone(),
-            wait: self.wait.clone(),
-            _scope: Id::default(),
-        }
+    pub fn is_dead(&self) -> bool {
+        self.inner.dead.load(Ordering::SeqCst)
     }
 
-    // Create a new scope with a smaller lifetime on the same pool.
-    #[inline]
-    unsafe fn refine<'other>(&self) -> Scope<'other>
-    where
-        'scope: 'other,
-    {
-        Scope {
-            pool: self.pool.clone(),
-            wait: Arc::new(WaitGroup::new()),
-            _scope: Id::default(),
+    fn check_open(&self) {
+        if self.is_dead() {
+            panic!("Pool is dead!")
+        }
+
+        if self.is_shutdown() {
+            panic!("Pool is shut down!")
         }
     }
-}
 
-enum PoolMessage {
-    Quit,
-    Task(Box<dyn Task + Send>, Arc<WaitGroup>),
-}
+    // Put a `fail_fast` pool into its dead state, failing every queued job
+    // and stopping the workers.
+    fn kill(&self) {
+        self.inner.dead.store(true, Ordering::SeqCst);
+        self.inner.shut_down.store(true, Ordering::SeqCst);
+        self.inner.configured.store(0, Ordering::SeqCst);
 
-/// A synchronization primitive for awaiting a set of actions.
-///
-/// Adding new jobs is done with `submit`, jobs are completed with `complete`,
-/// and any thread may wait for all jobs to be `complete`d with `join`.
-pub struct WaitGroup {
-    pending: AtomicUsize,
-    poisoned: AtomicBool,
-    lock: Mutex<()>,
-    cond: Condvar,
-}
+        let removed = self
+            .inner
+            .queue
+            .remove_where(|message| matches!(*message, PoolMessage::Task(_)));
+        for message in removed {
+            if let PoolMessage::Task(job) = message {
+                self.inner.fail(job);
+            }
+        }
 
-impl Default for WaitGroup {
-    fn default() -> Self {
-        WaitGroup {
-            pending: AtomicUsize::new(0),
-            poisoned: AtomicBool::new(false),
-            lock: Mutex::new(()),
-            cond: Condvar::new(),
+        for _ in 0..self.workers().max(1) {
+            self.inner.queue.push_urgent(PoolMessage::Quit);
         }
     }
-}
 
-impl WaitGroup {
-    /// Create a new empty WaitGroup.
+    /// Expand the Pool by spawning an additional thread.
+    ///
+    /// Can accelerate the completion of running jobs.
+    ///
+    /// Panics if the thread can't be spawned; use `try_expand` to handle
+    /// that instead.
     #[inline]
-    pub fn new() -> Self {
-        WaitGroup::default()
+    pub fn expand(&self) {
+        self.try_expand().expect("failed to spawn a worker thread")
     }
 
-    /// How many submitted tasks are waiting for completion.
-    #[inline]
-    pub fn waiting(&self) -> usize {
-        self.pending.load(Ordering::SeqCst)
+    /// Expand the Pool by one thread if more than `threshold` of its threads
+    /// are busy, returning whether it did.
+    ///
+    /// Busy threads are those running jobs, as a fraction of `workers`, so
+    /// a `threshold` of `0.75` grows a pool of four threads once all four
+    /// are busy. A pool with no threads always grows. This is the guard an
+    /// autoscaler applies, for callers which would rather resize the pool
+    /// themselves. Jobs start and finish concurrently, so the utilization is
+    /// a snapshot, and concurrent calls may each see the pool busy and all
+    /// expand it. Returns false if the thread can't be spawned.
+    pub fn expand_if_busy(&self, threshold: f64) -> bool {
+        let workers = self
+            .workers()
+            .saturating_sub(self.inner.retiring.load(Ordering::SeqCst));
+        let running = self
+            .inner
+            .in_flight
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.queued_jobs());
+
+        let busy = workers == 0 || running.min(workers) as f64 / workers as f64 > threshold;
+        busy && self.try_expand().is_ok()
     }
 
-    /// Submit to this WaitGroup, causing `join` to wait
-    /// for an additional `complete`.
-    #[inline]
-    pub fn submit(&self) {
-        self.pending.fetch_add(1, Ordering::SeqCst);
+    /// Like `expand`, but returns the error if the thread can't be spawned,
+    /// such as when the process is at its thread limit.
+    ///
+    /// The pool is left as it was when this fails.
+    pub fn try_expand(&self) -> io::Result<()> {
+        self.inner.configured.fetch_add(1, Ordering::SeqCst);
+        self.spawn_worker().inspect_err(|_| {
+            self.inner.configured.fetch_sub(1, Ordering::SeqCst);
+        })
     }
 
-    /// Complete a previous `submit`.
-    #[inline]
-    pub fn complete(&self) {
-        // Mark the current job complete.
-        let old = self.pending.fetch_sub(1, Ordering::SeqCst);
+    // Start a worker thread, without changing the configured size.
+    fn spawn_worker(&self) -> io::Result<()> {
+        let pool = self.clone();
 
-        // If that was the last job, wake joiners.
-        if old == 1 {
-            let _lock = self.lock.lock().unwrap();
-            self.cond.notify_all()
+        // Submit the new thread to the thread waitgroup.
+        pool.wait.submit();
+
+        let thread_number = self.inner.thread_counter.fetch_add(1, Ordering::SeqCst);
+
+        // Deal with thread configuration.
+        let config = &self.inner.thread_config;
+        let name = config
+            .prefix
+            .as_ref()
+            .map(|prefix| format!("{}{}", prefix, thread_number));
+
+        // Start the actual thread, or take back its submit.
+        let result = self.inner.spawner.spawn(
+            name,
+            config.stack_size,
+            Box::new(move || pool.run_thread(thread_number)),
+        );
+
+        if result.is_err() {
+            self.wait.complete();
+            self.inner.worker_exited();
         }
+
+        result
     }
 
-    /// Poison the WaitGroup so all `join`ing threads panic.
+    /// Contract the Pool by stopping one thread.
+    ///
+    /// The thread stops once it has finished its current job and taken the
+    /// request from the queue, so `workers` counts it until then. If the pool
+    /// has no threads, the next thread to start stops instead. Jobs left on
+    /// the stopping thread's own deque are moved to the shared queue as it
+    /// exits, so they still run on the other threads.
     #[inline]
-    pub fn poison(&self) {
-        // Poison the waitgroup.
-        self.poisoned.store(true, Ordering::SeqCst);
+    pub fn contract(&self) {
+        let _ = self
+            .inner
+            .configured
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        self.inner.retiring.fetch_add(1, Ordering::SeqCst);
+        self.inner.queue.push_global(PoolMessage::Retire);
+    }
 
-        // Mark the current job complete.
-        let old = self.pending.fetch_sub(1, Ordering::SeqCst);
+    /// Reclaim memory after a burst of work, by draining the queue, shrinking
+    /// the pool and freeing cached allocations.
+    ///
+    /// This blocks until no jobs are queued, which needs workers to run them,
+    /// then `contract`s the pool down to the size set with
+    /// `PoolBuilder::min_workers`, and finally frees the wait groups kept for
+    /// reuse by later scopes. Like with `contract`, the surplus threads stop
+    /// soon after rather than before this returns. Throughput may dip briefly
+    /// while compacting, until the pool is expanded again.
+    ///
+    /// Without workers nothing can drain the queue, so if the pool has none,
+    /// or they all exit while this waits, it stops waiting and compacts with
+    /// the jobs still queued.
+    pub fn compact(&self) {
+        // Let the workers drain the queue, while there are any.
+        {
+            let mut workers = self.inner.workers.lock().unwrap();
+            self.inner.drain_waiters.fetch_add(1, Ordering::SeqCst);
+            defer!({
+                self.inner.drain_waiters.fetch_sub(1, Ordering::SeqCst);
+            });
 
-        // If that was the last job, wake joiners.
-        if old == 1 {
-            let _lock = self.lock.lock().unwrap();
-            self.cond.notify_all()
+            while self.queued_jobs() > 0 && self.workers() > 0 {
+                workers = self.inner.worker_ready.wait(workers).unwrap();
+            }
+        }
+
+        for _ in self.inner.min_workers..self.configured_workers() {
+            self.contract();
         }
+
+        while self.inner.free_wait_groups.pop().is_some() {}
     }
 
-    /// Wait for `submit`s to this WaitGroup to be `complete`d.
-    ///
-    /// Submits occuring completely before joins will always be waited on.
+    /// Run `f` with `extra` more threads in the pool, removing them after.
     ///
-    /// Submits occuring concurrently with a `join` may or may not
-    /// be waited for.
-    ///
-    /// Before submitting, `join` will always return immediately.
-    #[inline]
-    pub fn join(&self) {
-        let mut lock = self.lock.lock().unwrap();
-
-        while self.pending.load(Ordering::SeqCst) > 0 {
-            lock = self.cond.wait(lock).unwrap();
+    /// The pool is `expand`ed before `f` runs and `contract`ed by the same
+    /// amount when it returns or panics, for CPU-heavy phases which need the
+    /// extra throughput. The extra threads can oversubscribe the machine's
+    /// cores while they run. As with `contract`, threads stop once they reach
+    /// the request in the queue, so they may outlive the call briefly, and
+    /// other threads may be the ones to stop.
+    pub fn boosted<R, F>(&self, extra: usize, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        for _ in 0..extra {
+            self.expand();
         }
 
-        if self.poisoned.load(Ordering::SeqCst) {
-            panic!("WaitGroup explicitly poisoned!")
+        defer!(for _ in 0..extra {
+            self.contract();
+        });
+
+        f()
+    }
+
+    // Start a background thread watching the pool, tracked by `running`.
+    fn spawn_monitor<F>(&self, running: &WaitGroup, role: &str, run: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        running.submit();
+        self.spawn_helper(role, run).unwrap();
+    }
+
+    // Start a thread which isn't a worker, named for its role.
+    fn spawn_helper<F>(&self, role: &str, run: F) -> io::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let name = self
+            .inner
+            .thread_config
+            .prefix
+            .as_ref()
+            .map(|prefix| format!("{}{}", prefix, role));
+
+        self.inner.spawner.spawn(name, None, Box::new(run))
+    }
+
+    // Start timing a watchdog job, setting `expired` if it runs past
+    // `timeout`. The watchdog thread starts with the first timer.
+    fn arm_watchdog(&self, timeout: Duration, expired: &Arc<AtomicBool>) {
+        let watchdog = &self.inner.watchdog;
+
+        let start = {
+            let mut timers = watchdog.timers.lock().unwrap();
+            timers
+                .armed
+                .push((Instant::now() + timeout, expired.clone()));
+            watchdog.wake.notify_all();
+            !mem::replace(&mut timers.started, true)
+        };
+
+        if start {
+            let pool = self.clone();
+            self.spawn_monitor(&watchdog.running, "watchdog", move || pool.run_watchdog());
         }
     }
-}
 
-// Poisons the given pool on drop unless canceled.
-//
-// Used to ensure panic propogation between jobs and waiting threads.
-struct Sentinel(Pool, Option<Arc<WaitGroup>>);
+    fn disarm_watchdog(&self, expired: &Arc<AtomicBool>) {
+        let mut timers = self.inner.watchdog.timers.lock().unwrap();
+        timers.armed.retain(|(_, flag)| !Arc::ptr_eq(flag, expired));
+    }
 
-impl Sentinel {
-    fn cancel(mut self) {
-        if let Some(wait) = self.1.take() {
-            wait.complete()
+    // Flag watchdog jobs as their timeouts pass, until shutdown.
+    fn run_watchdog(self) {
+        let watchdog = &self.inner.watchdog;
+        defer!(watchdog.running.complete());
+
+        let mut timers = watchdog.timers.lock().unwrap();
+        while !timers.stopped {
+            let now = Instant::now();
+            timers.armed.retain(|(deadline, expired)| {
+                if *deadline <= now {
+                    expired.store(true, Ordering::SeqCst);
+                }
+                *deadline > now
+            });
+
+            timers = match timers.armed.iter().map(|&(deadline, _)| deadline).min() {
+                Some(next) => watchdog.wake.wait_timeout(timers, next - now).unwrap().0,
+                None => watchdog.wake.wait(timers).unwrap(),
+            };
         }
     }
-}
 
-impl Drop for Sentinel {
-    fn drop(&mut self) {
-        if let Some(wait) = self.1.take() {
-            wait.poison()
+    // Shut the pool down once it has been idle for `timeout`.
+    fn run_idle_monitor(self, timeout: Duration) {
+        let control = self.inner.idle_monitor.as_ref().unwrap();
+        defer!(control.running.complete());
+
+        // Sampling can miss short jobs, so any job run since the last sample
+        // also counts as activity.
+        let interval = (timeout / 4).max(Duration::from_millis(1));
+        let mut idle_since = Instant::now();
+        let mut jobs_run = self.inner.jobs_run.load(Ordering::SeqCst);
+
+        let mut stopped = control.stopped.lock().unwrap();
+        loop {
+            stopped = control.wake.wait_timeout(stopped, interval).unwrap().0;
+            if *stopped {
+                return;
+            }
+
+            let now = self.inner.jobs_run.load(Ordering::SeqCst);
+            if now != jobs_run || self.inner.in_flight.load(Ordering::SeqCst) > 0 {
+                jobs_run = now;
+                idle_since = Instant::now();
+            } else if idle_since.elapsed() >= timeout {
+                break;
+            }
         }
+
+        // Mark ourselves stopped, so the shutdown doesn't wait for us.
+        *stopped = true;
+        drop(stopped);
+        self.inner.idle_timed_out.store(true, Ordering::SeqCst);
+        self.shutdown();
     }
-}
 
-struct ThreadSentinel(Option<Pool>);
+    // Report jobs running for longer than `threshold`, until shutdown.
+    fn run_slow_task_monitor(self, threshold: Duration) {
+        let control = self.inner.slow_task_monitor.as_ref().unwrap();
+        defer!(control.running.complete());
+
+        let handler = self.inner.on_slow_task.as_ref().unwrap();
+        let interval = (threshold / 4).max(Duration::from_millis(1));
+
+        let mut stopped = control.stopped.lock().unwrap();
+        loop {
+            stopped = control.wake.wait_timeout(stopped, interval).unwrap().0;
+            if *stopped {
+                return;
+            }
+
+            // Collect the slow jobs first, so the handler runs without
+            // holding up the workers.
+            let mut slow = Vec::new();
+            for worker in self.inner.workers.lock().unwrap().iter() {
+                if let Some(ref mut job) = *worker.current.lock().unwrap() {
+                    let elapsed = job.started.elapsed();
+                    if !job.reported && elapsed >= threshold {
+                        job.reported = true;
+                        slow.push((job.label, elapsed));
+                    }
+                }
+            }
+
+            // The handler may shut the pool down, which stops this monitor.
+            drop(stopped);
+            for (label, elapsed) in slow {
+                handler(label, elapsed);
+            }
+            stopped = control.stopped.lock().unwrap();
+        }
+    }
+
+    // Periodically resize the pool to keep the queue near its target depth.
+    fn run_autoscaler(self, config: Autoscale) {
+        let control = self.inner.autoscaler.as_ref().unwrap();
+        defer!(control.running.complete());
+
+        let mut stopped = control.stopped.lock().unwrap();
+        while !*stopped {
+            stopped = control
+                .wake
+                .wait_timeout(stopped, config.interval)
+                .unwrap()
+                .0;
+            if *stopped {
+                break;
+            }
+
+            // Workers about to retire don't count.
+            let workers = self
+                .workers()
+                .saturating_sub(self.inner.retiring.load(Ordering::SeqCst));
+            let queued = self.queued_jobs();
+            let running = self
+                .inner
+                .in_flight
+                .load(Ordering::SeqCst)
+                .saturating_sub(queued);
+
+            if workers < config.min || (queued > config.target_queue_depth && workers < config.max)
+            {
+                // A failed spawn is retried on a later sample.
+                let _ = self.try_expand();
+            } else if workers > config.min && queued == 0 && running < workers {
+                self.contract();
+            }
+        }
+    }
+
+    /// Run jobs on the current thread, as one of the pool's workers, until
+    /// the pool is shut down.
+    ///
+    /// This donates the caller's thread to the pool, so a pool made with
+    /// `empty` can run without starting any threads of its own, with work
+    /// submitted from elsewhere. It suits single threaded environments,
+    /// deterministic tests, and event loops which own the main thread.
+    /// `workers` counts the thread while it's here, though it isn't part of
+    /// `configured_workers`.
+    ///
+    /// Returns once the thread takes a shutdown from the queue, having run
+    /// what was queued, as any worker does, or once `contract` retires it.
+    /// A panicking job unwinds out of this call, poisoning the pool as a
+    /// panicking worker would, but nothing is restarted in its place.
+    pub fn run_on_current_thread(&self) {
+        self.wait.submit();
+        let thread_number = self.inner.thread_counter.fetch_add(1, Ordering::SeqCst);
+        self.clone().work(thread_number, false)
+    }
+
+    fn run_thread(self, thread_number: usize) {
+        self.work(thread_number, true)
+    }
+
+    // The worker loop, restarting the thread if it panics when `restart`.
+    fn work(self, thread_number: usize, restart: bool) {
+        // Create a sentinel to capture panics on this thread.
+        let mut thread_sentinel = ThreadSentinel(Some(self.clone()), restart);
+
+        let id = WorkerId(thread_number);
+        if let Some(ref hook) = self.inner.on_worker_start {
+            hook(id);
+        }
+
+        // Make this worker visible to the pool while it runs. A restarted
+        // thread registers afresh.
+        let worker = self.inner.register(thread_number);
+
+        // Report the stop before any restart. Unless the loop exits
+        // normally, the worker panicked.
+        let mut stop = scopeguard::guard(StopReason::Panicked, |reason| {
+            if let Some(ref hook) = self.inner.on_worker_stop {
+                hook(id, reason);
+            }
+        });
+
+        // Take a local deque, if the queue has them. Declared after the
+        // sentinel so leftover jobs are handed back before any restart.
+        let _worker = self.inner.queue.register();
+
+        loop {
+            match self.inner.pop() {
+                // On Quit, drain the queue, repropogate and quit.
+                PoolMessage::Quit => {
+                    // Quits meant for other workers, taken while draining.
+                    // Ours is used up, but theirs are repropogated, so each
+                    // worker only ever takes one. That happens even if a
+                    // drained job panics, or the other workers would wait
+                    // for a Quit which never comes.
+                    let mut quits = scopeguard::guard(0, |quits| {
+                        for _ in 0..quits {
+                            self.inner.queue.push_global(PoolMessage::Quit);
+                        }
+                    });
+
+                    // Run everything still queued, so jobs submitted around
+                    // the shutdown are not abandoned with joiners waiting.
+                    while let Some(message) = self.inner.queue.try_pop() {
+                        match message {
+                            PoolMessage::Task(job) => self.take_job(&worker, job),
+                            // Every worker is quitting anyway.
+                            PoolMessage::Retire => {
+                                self.inner.retiring.fetch_sub(1, Ordering::SeqCst);
+                            }
+                            // Other Quits are handed back.
+                            PoolMessage::Quit => *quits += 1,
+                        }
+                    }
+
+                    // Nothing more is coming, so don't hold back any jobs.
+                    #[cfg(feature = "trace")]
+                    if let Some(ref replay) = self.inner.replay {
+                        replay.finish();
+                        self.run_replayed(&worker, replay);
+                    }
+
+                    drop(quits);
+
+                    *stop = if self.inner.idle_timed_out.load(Ordering::SeqCst) {
+                        StopReason::IdleTimeout
+                    } else {
+                        StopReason::Quit
+                    };
+
+                    // Cancel the thread sentinel so we don't panic waiting
+                    // shutdown threads, and don't restart the thread.
+                    thread_sentinel.cancel();
+
+                    // Terminate the thread.
+                    break;
+                }
+
+                // On Retire, quit without stopping the other workers.
+                PoolMessage::Retire => {
+                    self.inner.retiring.fetch_sub(1, Ordering::SeqCst);
+                    *stop = StopReason::Retired;
+                    thread_sentinel.cancel();
+                    break;
+                }
+
+                // On Task, run the task then complete the WaitGroup.
+                PoolMessage::Task(job) => self.take_job(&worker, job),
+            }
+        }
+    }
+
+    // Run a job taken from the queue, unless a replay holds it back.
+    fn take_job(&self, worker: &WorkerState, job: Job) {
+        #[cfg(feature = "trace")]
+        if let Some(ref replay) = self.inner.replay {
+            replay.hold(job);
+            self.run_replayed(worker, replay);
+            return;
+        }
+
+        self.run_job(worker, job)
+    }
+
+    // Run held jobs for as long as the trace's next one is available.
+    #[cfg(feature = "trace")]
+    fn run_replayed(&self, worker: &WorkerState, replay: &Replay) {
+        while let Some(job) = replay.next() {
+            self.run_job(worker, job);
+        }
+    }
+
+    fn run_job(&self, worker: &WorkerState, job: Job) {
+        // Skip the remaining jobs of an aborted or cancelled batch.
+        if job.wait.aborted.load(Ordering::SeqCst) || job.wait.locals.is_cancelled() {
+            return self.inner.discard(job);
+        }
+
+        self.inner.dequeued();
+
+        #[cfg(feature = "trace")]
+        if let Some(ref recording) = self.inner.recording {
+            recording.lock().unwrap().push(job.seq);
+        }
+
+        // The task has left the queue, so release its share of the budget.
+        if let Some(ref budget) = self.inner.memory_budget {
+            budget.release(job.bytes);
+        }
+
+        // Wait for a slot under the concurrency limit, then count the job
+        // as running.
+        let _permit = self
+            .inner
+            .concurrency_limit
+            .as_ref()
+            .map(Semaphore::acquire);
+        let running = self.inner.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.inner.peak_running.fetch_max(running, Ordering::SeqCst);
+
+        *worker.current.lock().unwrap() = Some(RunningJob {
+            label: job.label,
+            started: Instant::now(),
+            reported: false,
+        });
+
+        // Count the job as done once it has completed, even by panicking.
+        let (wait, epoch) = (&job.wait, job.epoch);
+        defer!({
+            wait.leave_epoch(epoch);
+            self.inner.running.fetch_sub(1, Ordering::SeqCst);
+            *worker.current.lock().unwrap() = None;
+            self.inner.jobs_run.fetch_add(1, Ordering::SeqCst);
+            self.inner.finish_job();
+        });
+
+        #[cfg(feature = "utilization")]
+        let _busy = worker.clock.busy();
+
+        let sentinel = Sentinel(Some(job.wait.clone()), self.inner.panic_behavior);
+
+        // A panic dooms a `cancel_on_panic` scope, so drop the rest of its
+        // jobs before the poison is seen.
+        let _cancel = scopeguard::guard_on_unwind((), |_| {
+            if wait.cancel_on_panic.load(Ordering::SeqCst) {
+                wait.aborted.store(true, Ordering::SeqCst);
+                self.inner.cancel(wait);
+            }
+        });
+
+        match job.wait.timings {
+            // Record the time before completing, so the joiner sees it.
+            Some(ref timings) => {
+                let start = Instant::now();
+                job.task.run();
+                timings.record(start.elapsed());
+            }
+            None => job.task.run(),
+        }
+        sentinel.cancel();
+    }
+}
+
+/// A logical subsystem of a `Pool`, sharing the pool's queue and workers.
+///
+/// A Subpool has its own lifecycle: `join` waits only for work submitted
+/// through it, and `shutdown` stops it accepting new work without stopping
+/// any worker threads. Shutting down the parent `Pool` still stops all
+/// workers, including those serving subpools.
+///
+/// Like `Pool`, a Subpool can be freely cloned, and clones share the same
+/// work and shutdown state.
+#[derive(Clone)]
+pub struct Subpool {
+    pool: Pool,
+    wait: Arc<WaitGroup>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Subpool {
+    /// Spawn a `'static'` job to be run on the parent pool's workers.
+    ///
+    /// The job is waited on by `join` and `shutdown`, and a panic in it
+    /// will propogate to the thread calling them.
+    ///
+    /// Panics if this subpool has been shut down.
+    #[inline]
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.check_open();
+
+        Scope {
+            pool: self.pool.clone(),
+            wait: self.wait.clone(),
+            depth: 0,
+            id: next_scope_id(),
+            parent: None,
+            _scope: Id::default(),
+        }
+        .execute(job)
+    }
+
+    /// Create a Scope for scheduling a group of jobs in `'scope'`.
+    ///
+    /// Behaves like `Pool::scoped`, and `join` or `shutdown` on this subpool
+    /// also wait for the call to return.
+    ///
+    /// Panics if this subpool has been shut down.
+    #[inline]
+    pub fn scoped<'scope, F, R>(&self, scheduler: F) -> R
+    where
+        F: FnOnce(&Scope<'scope>) -> R,
+    {
+        self.check_open();
+
+        // Count the whole call as outstanding work of the subpool.
+        self.wait.submit();
+        defer!(self.wait.complete());
+
+        self.pool.scoped(scheduler)
+    }
+
+    /// Wait for all work submitted through this subpool to complete.
+    #[inline]
+    pub fn join(&self) {
+        self.wait.join()
+    }
+
+    /// Stop accepting new work, then wait for outstanding work to complete.
+    ///
+    /// The parent pool's workers keep running.
+    #[inline]
+    pub fn shutdown(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.join()
+    }
+
+    /// Whether `shutdown` has been called on this subpool.
+    #[inline]
+    pub fn is_shutdown(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// The pool whose workers run this subpool's jobs.
+    #[inline]
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    fn check_open(&self) {
+        if self.is_shutdown() {
+            panic!("Subpool is shut down!")
+        }
+    }
+}
+
+/// A view of a `Pool` for jobs which all produce a `T`, created by
+/// `Pool::typed`.
+///
+/// Every job's result is sent on a single channel, in the order the jobs
+/// finish, so a pipeline can consume them as a stream without a handle or
+/// downcast per job. The channel stays open while the TypedPool lives, so
+/// receive as many results as jobs were submitted rather than waiting for
+/// it to close. Clones share the same channel.
+pub struct TypedPool<T> {
+    pool: Pool,
+    sender: crossbeam::channel::Sender<T>,
+    receiver: crossbeam::channel::Receiver<T>,
+}
+
+impl<T> Clone for TypedPool<T> {
+    fn clone(&self) -> Self {
+        TypedPool {
+            pool: self.pool.clone(),
+            sender: self.sender.clone(),
+            receiver: self.receiver.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> TypedPool<T> {
+    /// Spawn a job whose result is sent on the results channel, returning
+    /// the channel.
+    ///
+    /// Like `Pool::spawn`, nothing waits for the job and a panic in it
+    /// propogates as usual, sending no result.
+    pub fn submit<F>(&self, job: F) -> crossbeam::channel::Receiver<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let sender = self.sender.clone();
+        self.pool.spawn(move || {
+            // The results can only be dropped along with every receiver.
+            let _ = sender.send(job());
+        });
+
+        self.receiver.clone()
+    }
+
+    /// The channel every job's result is sent on.
+    #[inline]
+    pub fn results(&self) -> &crossbeam::channel::Receiver<T> {
+        &self.receiver
+    }
+
+    /// The pool whose workers run the jobs.
+    #[inline]
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+}
+
+// There's only one queue per pool, so variant size doesn't matter.
+#[allow(clippy::large_enum_variant)]
+enum BlockingQueue<T> {
+    // Per-worker deques with work stealing, used unless the pool needs to
+    // look at queued messages before popping them.
+    //
+    // Messages pushed by a worker go to its own deque, which it pops newest
+    // first while their data is still hot. Everything else goes through the
+    // shared injector. Idle workers take the oldest message from the
+    // injector, then steal the oldest message from other workers' deques,
+    // which balances load and bounds how much is stolen at once.
+    Stealing {
+        injector: Injector<T>,
+        // Messages pushed with `push_urgent`, taken before anything else.
+        urgent: Injector<T>,
+        stealers: RwLock<Vec<(usize, Stealer<T>)>>,
+        next_worker: AtomicUsize,
+        sleep: Sleep,
+    },
+
+    // A deque behind a lock, which lets consumers inspect the front of
+    // the queue and pop from anywhere in it.
+    Deque {
+        state: Mutex<DequeState<T>>,
+        ready: Condvar,
+    },
+}
+
+struct DequeState<T> {
+    items: VecDeque<T>,
+    // How many items at the front were pushed with `push_urgent`.
+    urgent: usize,
+    // How many consumers are blocked waiting for an item, so pushes only
+    // pay for a notification when someone is actually asleep.
+    sleepers: usize,
+}
+
+impl<T> DequeState<T> {
+    // Remove the item `select` picks, unless an urgent item must go first.
+    fn remove_with<S>(&mut self, select: S) -> Option<T>
+    where
+        S: Fn(&VecDeque<T>) -> usize,
+    {
+        if self.urgent > 0 {
+            self.urgent -= 1;
+            return self.items.pop_front();
+        }
+
+        let index = select(&self.items);
+        self.items.remove(index)
+    }
+}
+
+// Parks idle consumers of the stealing queue until a push wakes them.
+struct Sleep {
+    // Consumers asleep and not yet woken. Only changed under the lock, but
+    // read without it so pushes are cheap when nobody is asleep.
+    sleepers: AtomicUsize,
+    // Wakeups sent but not yet taken by a woken consumer.
+    wakeups: Mutex<usize>,
+    ready: Condvar,
+}
+
+impl Sleep {
+    fn new() -> Sleep {
+        Sleep {
+            sleepers: AtomicUsize::new(0),
+            wakeups: Mutex::new(0),
+            ready: Condvar::new(),
+        }
+    }
+
+    // Wake a sleeping consumer, if there is one. Called after every push.
+    fn notify(&self) {
+        // Pairs with the fence in `wait_for`: either the sleeper's final
+        // check sees the pushed item, or we see the sleeper.
+        atomic::fence(Ordering::SeqCst);
+
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let mut wakeups = self.wakeups.lock().unwrap();
+
+            // Take the sleeper off the count as we wake it, so pushes made
+            // before it gets to run don't wake it again.
+            if self.sleepers.load(Ordering::SeqCst) > 0 {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                *wakeups += 1;
+                self.ready.notify_one();
+            }
+        }
+    }
+
+    // Block until `find` produces an item.
+    fn wait_for<T, F>(&self, mut find: F) -> T
+    where
+        F: FnMut() -> Option<T>,
+    {
+        let mut wakeups = self.wakeups.lock().unwrap();
+
+        loop {
+            // Announce ourselves before the final check, so a push racing
+            // with it will wake us.
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            atomic::fence(Ordering::SeqCst);
+
+            if let Some(item) = find() {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return item;
+            }
+
+            wakeups = self.ready.wait(wakeups).unwrap();
+
+            // Spurious wakeups have to take themselves off the count.
+            if *wakeups > 0 {
+                *wakeups -= 1;
+            } else {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+thread_local! {
+    // The deque of the worker running on this thread, along with the
+    // address of the queue it belongs to.
+    static LOCAL_DEQUE: Cell<(usize, *const ())> = const { Cell::new((0, ptr::null())) };
+}
+
+// Registers the current thread as a worker consuming a queue, giving it a
+// local deque in a stealing queue. Leftover messages are handed back to
+// the queue on drop, including when the worker panics.
+struct QueueWorker<'a, T> {
+    queue: &'a BlockingQueue<T>,
+    local: Option<(usize, Box<Worker<T>>)>,
+    previous: (usize, *const ()),
+}
+
+impl<'a, T> Drop for QueueWorker<'a, T> {
+    fn drop(&mut self) {
+        LOCAL_DEQUE.with(|local| local.set(self.previous));
+
+        let (id, local) = match self.local.take() {
+            Some(local) => local,
+            None => return,
+        };
+
+        if let BlockingQueue::Stealing {
+            ref injector,
+            ref stealers,
+            ref sleep,
+            ..
+        } = *self.queue
+        {
+            stealers.write().unwrap().retain(|&(other, _)| other != id);
+
+            // Move what's left to the injector, oldest first.
+            let leftovers = local.stealer();
+            while let Some(message) = steal(&leftovers) {
+                injector.push(message);
+                sleep.notify();
+            }
+        }
+    }
+}
+
+// Take from `source` until it succeeds or is empty.
+fn take<T>(source: &Injector<T>) -> Option<T> {
+    loop {
+        match source.steal() {
+            Steal::Success(message) => return Some(message),
+            Steal::Empty => return None,
+            Steal::Retry => {}
+        }
+    }
+}
+
+// Steal from `source` until it succeeds or is empty.
+fn steal<T>(source: &Stealer<T>) -> Option<T> {
+    loop {
+        match source.steal() {
+            Steal::Success(message) => return Some(message),
+            Steal::Empty => return None,
+            Steal::Retry => {}
+        }
+    }
+}
+
+impl<T> BlockingQueue<T> {
+    fn new() -> BlockingQueue<T> {
+        BlockingQueue::Stealing {
+            injector: Injector::new(),
+            urgent: Injector::new(),
+            stealers: RwLock::new(Vec::new()),
+            next_worker: AtomicUsize::new(0),
+            sleep: Sleep::new(),
+        }
+    }
+
+    fn deque() -> BlockingQueue<T> {
+        BlockingQueue::Deque {
+            state: Mutex::new(DequeState {
+                items: VecDeque::new(),
+                urgent: 0,
+                sleepers: 0,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    // Register the current thread as a worker of this queue, until the
+    // returned guard is dropped.
+    fn register(&self) -> QueueWorker<'_, T> {
+        let previous = LOCAL_DEQUE.with(Cell::get);
+
+        let local = match *self {
+            BlockingQueue::Stealing {
+                ref stealers,
+                ref next_worker,
+                ..
+            } => {
+                let local = Box::new(Worker::new_lifo());
+                let id = next_worker.fetch_add(1, Ordering::SeqCst);
+                stealers.write().unwrap().push((id, local.stealer()));
+
+                let address = &*local as *const Worker<T> as *const ();
+                LOCAL_DEQUE.with(|current| current.set((self.address(), address)));
+
+                Some((id, local))
+            }
+            BlockingQueue::Deque { .. } => None,
+        };
+
+        QueueWorker {
+            queue: self,
+            local,
+            previous,
+        }
+    }
+
+    fn address(&self) -> usize {
+        self as *const BlockingQueue<T> as usize
+    }
+
+    // The deque of the current thread, if it is a worker of this queue.
+    fn local(&self) -> Option<&Worker<T>> {
+        let (owner, local) = LOCAL_DEQUE.with(Cell::get);
+
+        if owner == self.address() {
+            // Safe because the deque is only registered for this queue while
+            // the QueueWorker which owns it is alive on this thread.
+            Some(unsafe { &*(local as *const Worker<T>) })
+        } else {
+            None
+        }
+    }
+
+    // Find a message in a stealing queue without blocking.
+    fn find(
+        &self,
+        urgent: &Injector<T>,
+        injector: &Injector<T>,
+        stealers: &RwLock<Vec<(usize, Stealer<T>)>>,
+    ) -> Option<T> {
+        if let Some(message) = take(urgent) {
+            return Some(message);
+        }
+
+        if let Some(message) = self.local().and_then(Worker::pop) {
+            return Some(message);
+        }
+
+        if let Some(message) = take(injector) {
+            return Some(message);
+        }
+
+        stealers
+            .read()
+            .unwrap()
+            .iter()
+            .find_map(|(_, stealer)| steal(stealer))
+    }
+
+    // Pop a message, letting `select` choose its index among the queued
+    // messages when the queue supports it. The stealing queue ignores it.
+    fn pop_with<S>(&self, select: S) -> T
+    where
+        S: Fn(&VecDeque<T>) -> usize,
+    {
+        match *self {
+            BlockingQueue::Stealing {
+                ref injector,
+                ref urgent,
+                ref stealers,
+                ref sleep,
+                ..
+            } => match self.find(urgent, injector, stealers) {
+                Some(message) => message,
+                None => sleep.wait_for(|| self.find(urgent, injector, stealers)),
+            },
+            BlockingQueue::Deque {
+                ref state,
+                ref ready,
+            } => {
+                let mut state = state.lock().unwrap();
+
+                while state.items.is_empty() {
+                    state.sleepers += 1;
+                    state = ready.wait(state).unwrap();
+                    state.sleepers -= 1;
+                }
+
+                state.remove_with(select).unwrap()
+            }
+        }
+    }
+
+    fn try_pop(&self) -> Option<T> {
+        self.try_pop_with(|_| 0)
+    }
+
+    // Like `pop_with`, but returns `None` rather than blocking.
+    fn try_pop_with<S>(&self, select: S) -> Option<T>
+    where
+        S: Fn(&VecDeque<T>) -> usize,
+    {
+        match *self {
+            BlockingQueue::Stealing {
+                ref injector,
+                ref urgent,
+                ref stealers,
+                ..
+            } => self.find(urgent, injector, stealers),
+            BlockingQueue::Deque { ref state, .. } => {
+                let mut state = state.lock().unwrap();
+
+                if state.items.is_empty() {
+                    return None;
+                }
+
+                state.remove_with(select)
+            }
+        }
+    }
+
+    // Remove and return every queued message matching `pred`, keeping the
+    // rest in order.
+    //
+    // The stealing queue's deques can't be edited in place, so this isn't
+    // atomic there: the messages kept are out of the queue until they are
+    // put back, and a worker looking meanwhile may find nothing and sleep
+    // until they are. `queued_jobs` is unaffected, as it follows the count
+    // of queued jobs rather than the queue. Messages kept from a worker's
+    // own deque go back on the shared queue, losing their place in its
+    // newest first order, and run oldest first like submissions from
+    // outside the pool.
+    fn remove_where<P>(&self, mut pred: P) -> Vec<T>
+    where
+        P: FnMut(&T) -> bool,
+    {
+        match *self {
+            BlockingQueue::Stealing {
+                ref injector,
+                ref urgent,
+                ref stealers,
+                ref sleep,
+                ..
+            } => {
+                // Urgent messages keep their lane.
+                let mut taken = Vec::new();
+                while let Some(message) = take(urgent) {
+                    taken.push(message);
+                }
+                let (mut removed, kept): (Vec<T>, Vec<T>) = taken.into_iter().partition(&mut pred);
+                for message in kept {
+                    urgent.push(message);
+                    sleep.notify();
+                }
+
+                // The deques can't be edited in place, so take everything and
+                // put back what we keep.
+                let mut taken = Vec::new();
+                while let Some(message) = take(injector) {
+                    taken.push(message);
+                }
+                for (_, stealer) in stealers.read().unwrap().iter() {
+                    while let Some(message) = steal(stealer) {
+                        taken.push(message);
+                    }
+                }
+
+                let (others, kept): (Vec<T>, Vec<T>) = taken.into_iter().partition(pred);
+                removed.extend(others);
+
+                for message in kept {
+                    injector.push(message);
+                    sleep.notify();
+                }
+
+                removed
+            }
+            BlockingQueue::Deque { ref state, .. } => {
+                let mut state = state.lock().unwrap();
+
+                let mut removed = Vec::new();
+                let urgent = mem::take(&mut state.urgent);
+                for (index, message) in mem::take(&mut state.items).into_iter().enumerate() {
+                    if pred(&message) {
+                        removed.push(message);
+                    } else {
+                        if index < urgent {
+                            state.urgent += 1;
+                        }
+                        state.items.push_back(message);
+                    }
+                }
+
+                removed
+            }
+        }
+    }
+
+    // Push a message, onto the current worker's own deque if it has one.
+    fn push(&self, message: T) {
+        match *self {
+            BlockingQueue::Stealing {
+                ref injector,
+                ref sleep,
+                ..
+            } => {
+                match self.local() {
+                    Some(local) => local.push(message),
+                    None => injector.push(message),
+                }
+
+                sleep.notify();
+            }
+            BlockingQueue::Deque { .. } => self.push_global(message),
+        }
+    }
+
+    // Push a message to be taken next, behind any urgent messages. Without
+    // a worker deque to push onto, it goes behind other shared messages.
+    fn push_front(&self, message: T) {
+        match *self {
+            BlockingQueue::Stealing { .. } => self.push(message),
+            BlockingQueue::Deque {
+                ref state,
+                ref ready,
+            } => {
+                let mut state = state.lock().unwrap();
+                let index = state.urgent;
+                state.items.insert(index, message);
+
+                if state.sleepers > 0 {
+                    ready.notify_one();
+                }
+            }
+        }
+    }
+
+    // Push a message to be taken before any pushed normally, in FIFO order
+    // with other urgent messages.
+    fn push_urgent(&self, message: T) {
+        match *self {
+            BlockingQueue::Stealing {
+                ref urgent,
+                ref sleep,
+                ..
+            } => {
+                urgent.push(message);
+                sleep.notify();
+            }
+            BlockingQueue::Deque {
+                ref state,
+                ref ready,
+            } => {
+                let mut state = state.lock().unwrap();
+                let index = state.urgent;
+                state.items.insert(index, message);
+                state.urgent += 1;
+
+                if state.sleepers > 0 {
+                    ready.notify_one();
+                }
+            }
+        }
+    }
+
+    // Push a message where every worker will see it, in FIFO order.
+    fn push_global(&self, message: T) {
+        match *self {
+            BlockingQueue::Stealing {
+                ref injector,
+                ref sleep,
+                ..
+            } => {
+                injector.push(message);
+                sleep.notify();
+            }
+            BlockingQueue::Deque {
+                ref state,
+                ref ready,
+            } => {
+                let mut state = state.lock().unwrap();
+                state.items.push_back(message);
+
+                if state.sleepers > 0 {
+                    ready.notify_one();
+                }
+            }
+        }
+    }
+}
+
+struct PoolInner {
+    queue: BlockingQueue<PoolMessage>,
+    thread_config: ThreadConfig,
+    thread_counter: AtomicUsize,
+    memory_budget: Option<MemoryBudget>,
+    spawner: Box<dyn Spawn>,
+    task_selector: Option<Box<TaskSelector>>,
+    idle_strategy: IdleStrategy,
+    steal_backoff: StealBackoff,
+    workers: Mutex<Vec<Arc<WorkerState>>>,
+    // Notified as workers register and exit, for `Pool::wait_ready` and
+    // `Pool::wait_workers`, as the queue empties for `Pool::compact`, and as
+    // workers take their turn in a `Pool::broadcast`.
+    worker_ready: Condvar,
+    // Threads in `Pool::compact` waiting for the queue to empty.
+    drain_waiters: AtomicUsize,
+    poison_message: Option<Arc<str>>,
+    task_context: Option<Box<TaskContext>>,
+    on_worker_start: Option<Box<WorkerStartHook>>,
+    on_worker_stop: Option<Box<WorkerStopHook>>,
+    // Set when the idle monitor shuts the pool down.
+    idle_timed_out: AtomicBool,
+    // Jobs queued or running.
+    in_flight: AtomicUsize,
+    // Jobs which have been run, ever.
+    jobs_run: AtomicUsize,
+    // Jobs running right now, and the most there have ever been.
+    running: AtomicUsize,
+    peak_running: AtomicUsize,
+    // Permits for running jobs, with `PoolBuilder::max_concurrent_tasks`.
+    concurrency_limit: Option<Semaphore>,
+    // Jobs waiting in the queue.
+    queued: AtomicUsize,
+    // Workers asked to retire which haven't yet.
+    retiring: AtomicUsize,
+    // The intended number of workers.
+    configured: AtomicUsize,
+    // Set once shutdown starts, to turn away new work.
+    shut_down: AtomicBool,
+    // WaitGroups of finished scopes, ready to be reused.
+    free_wait_groups: ArrayQueue<Arc<WaitGroup>>,
+    autoscaler: Option<MonitorControl>,
+    idle_monitor: Option<MonitorControl>,
+    slow_task_monitor: Option<MonitorControl>,
+    on_slow_task: Option<Box<SlowTaskHandler>>,
+    watchdog: Watchdog,
+    joiner: Arc<Joiner>,
+    max_scope_depth: Option<usize>,
+    recursion_order: Option<RecursionOrder>,
+    // The `Scope::cancel_on_panic` setting new scopes start with.
+    cancel_on_panic: bool,
+    // The size `Pool::compact` shrinks to.
+    min_workers: usize,
+    detached_panic_policy: DetachedPanicPolicy,
+    panic_behavior: PanicBehavior,
+    fail_fast: bool,
+    // Set once a worker panics in a `fail_fast` pool.
+    dead: AtomicBool,
+    idle_callback: RwLock<Option<Arc<IdleCallback>>>,
+    #[cfg(feature = "remote")]
+    transport: Option<Box<dyn Transport>>,
+    // Jobs submitted, ever, numbering them for traces.
+    #[cfg(feature = "trace")]
+    submitted: AtomicU64,
+    // Submission indices in the order jobs were started, when recording.
+    #[cfg(feature = "trace")]
+    recording: Option<Mutex<Vec<u64>>>,
+    #[cfg(feature = "trace")]
+    replay: Option<Replay>,
+}
+
+type IdleCallback = dyn Fn() + Send + Sync;
+type WorkerStartHook = dyn Fn(WorkerId) + Send + Sync;
+type WorkerStopHook = dyn Fn(WorkerId, StopReason) + Send + Sync;
+type SlowTaskHandler = dyn Fn(Option<&'static str>, Duration) + Send + Sync;
+
+impl PoolInner {
+    // Create a WaitGroup for jobs on this pool.
+    fn wait_group(&self) -> Arc<WaitGroup> {
+        self.new_wait_group(None)
+    }
+
+    // Create a WaitGroup for a subscope, which sees its parent's locals.
+    fn child_wait_group(&self, parent: &WaitGroup) -> Arc<WaitGroup> {
+        let wait = self.new_wait_group(Some(parent.locals.clone()));
+
+        // A weak link keeps the child from being recycled, so it's dead
+        // once the subscope and its jobs are gone.
+        #[cfg(feature = "scope-tree")]
+        {
+            let mut children = parent.children.lock().unwrap();
+            children.retain(|child| child.strong_count() > 0);
+            children.push(Arc::downgrade(&wait));
+        }
+
+        wait
+    }
+
+    fn new_wait_group(&self, parent: Option<Arc<ScopeLocals>>) -> Arc<WaitGroup> {
+        // Reuse a group from a finished scope if there is one. Recycled
+        // groups are only ever referenced by the free list.
+        if let Some(mut wait) = self.free_wait_groups.pop() {
+            if let Some(group) = Arc::get_mut(&mut wait) {
+                if let Some(locals) = Arc::get_mut(&mut group.locals) {
+                    locals.parent = parent;
+                    *group.cancel_on_panic.get_mut() = self.cancel_on_panic;
+                    return wait;
+                }
+            }
+        }
+
+        Arc::new(WaitGroup {
+            poison_message: self.poison_message.clone(),
+            cancel_on_panic: AtomicBool::new(self.cancel_on_panic),
+            locals: Arc::new(ScopeLocals {
+                values: Mutex::new(Vec::new()),
+                cancelled: AtomicBool::new(false),
+                parent,
+            }),
+            ..WaitGroup::default()
+        })
+    }
+
+    // Keep a dropped scope's WaitGroup for reuse, if nothing else refers
+    // to it. The free list's reference is the only one left once the scope's
+    // own is dropped.
+    fn recycle_wait_group(&self, wait: &mut Arc<WaitGroup>) {
+        if let Some(group) = Arc::get_mut(wait) {
+            if group.clear() {
+                // If the free list is full, the group is just dropped.
+                let _ = self.free_wait_groups.push(wait.clone());
+            }
+        }
+    }
+
+    // Wake `Pool::wait_workers` once a worker has completed its submit.
+    fn worker_exited(&self) {
+        let _workers = self.workers.lock().unwrap();
+        self.worker_ready.notify_all();
+    }
+
+    // Register the current thread as a worker, until the guard is dropped.
+    fn register(&self, thread_number: usize) -> WorkerRegistration<'_> {
+        let state = Arc::new(WorkerState {
+            thread_number,
+            thread: thread::current().id(),
+            current: Mutex::new(None),
+            #[cfg(feature = "utilization")]
+            clock: WorkerClock::new(),
+        });
+
+        self.workers.lock().unwrap().push(state.clone());
+        self.worker_ready.notify_all();
+        WorkerRegistration { inner: self, state }
+    }
+
+    // Pop the next message for a worker, spinning or yielding briefly
+    // before blocking if the idle strategy says to.
+    fn pop(&self) -> PoolMessage {
+        let (budget, yielding) = match self.idle_strategy {
+            IdleStrategy::Park => (0, false),
+            IdleStrategy::Yield(n) => (n, true),
+            IdleStrategy::Spin(n) => (n, false),
+        };
+
+        // The budget counts pauses, which are spent one per check unless
+        // backing off spaces the checks out.
+        let mut pause = 1;
+        let mut spent = 0;
+        while spent < budget {
+            if let Some(message) = self.queue.try_pop_with(|items| self.select(items)) {
+                return message;
+            }
+
+            for _ in 0..pause.min(budget - spent) {
+                if yielding {
+                    thread::yield_now();
+                } else {
+                    hint::spin_loop();
+                }
+            }
+
+            spent += pause;
+            if let StealBackoff::Exponential { max_pause } = self.steal_backoff {
+                pause = (pause * 2).min(max_pause.max(1));
+            }
+        }
+
+        self.queue.pop_with(|items| self.select(items))
+    }
+
+    // Discard the queued jobs of one scope, returning how many there were.
+    fn cancel(&self, wait: &Arc<WaitGroup>) -> usize {
+        let removed = self.queue.remove_where(|message| match *message {
+            PoolMessage::Task(ref job) => Arc::ptr_eq(&job.wait, wait),
+            _ => false,
+        });
+
+        let count = removed.len();
+        for message in removed {
+            if let PoolMessage::Task(job) = message {
+                self.discard(job);
+            }
+        }
+
+        count
+    }
+
+    // Move the queued jobs of one scope to the urgent lane, keeping their
+    // order, returning how many there were.
+    fn boost(&self, wait: &Arc<WaitGroup>) -> usize {
+        let removed = self.queue.remove_where(|message| match *message {
+            PoolMessage::Task(ref job) => Arc::ptr_eq(&job.wait, wait),
+            _ => false,
+        });
+
+        let count = removed.len();
+        for message in removed {
+            self.queue.push_urgent(message);
+        }
+
+        count
+    }
+
+    // Drop a job which was removed from the queue without running it.
+    fn discard(&self, job: Job) {
+        self.drop_job(job, false)
+    }
+
+    // Discard a queued job, poisoning its scope as if it had panicked.
+    fn fail(&self, job: Job) {
+        self.drop_job(job, true)
+    }
+
+    fn drop_job(&self, job: Job, poison: bool) {
+        self.dequeued();
+
+        if let Some(ref budget) = self.memory_budget {
+            budget.release(job.bytes);
+        }
+
+        // Drop the job before completing it, so nothing it borrows is
+        // touched after a joiner is released.
+        let Job {
+            task, wait, epoch, ..
+        } = job;
+        drop(task);
+        wait.leave_epoch(epoch);
+        if poison {
+            wait.poison();
+        } else {
+            wait.complete();
+        }
+        self.finish_job();
+    }
+
+    // Account for a job leaving the queue, waking `Pool::compact` if it was
+    // the last. As with `WaitGroup::finish_round`, waiters count themselves
+    // under the lock before checking the queue, so none miss the wakeup.
+    fn dequeued(&self) {
+        let old = self.queued.fetch_sub(1, Ordering::SeqCst);
+
+        if old == 1 && self.drain_waiters.load(Ordering::SeqCst) > 0 {
+            let _workers = self.workers.lock().unwrap();
+            self.worker_ready.notify_all();
+        }
+    }
+
+    // Account for a job leaving the pool, run or discarded, calling the idle
+    // callback if it was the last one.
+    fn finish_job(&self) {
+        if self.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let callback = self.idle_callback.read().unwrap().clone();
+            if let Some(callback) = callback {
+                callback();
+            }
+        }
+    }
+
+    // Choose the index of the next message, consulting the task selector.
+    fn select(&self, items: &VecDeque<PoolMessage>) -> usize {
+        let selector = match self.task_selector {
+            Some(ref selector) => selector,
+            None => return 0,
+        };
+
+        // The window is the run of tasks at the front of the queue;
+        // anything behind a Quit is left for after the shutdown.
+        let mut window = [PeekedTask::default(); SELECTOR_WINDOW];
+        let mut len = 0;
+
+        for message in items.iter().take(SELECTOR_WINDOW) {
+            match *message {
+                PoolMessage::Task(ref job) => {
+                    window[len] = PeekedTask {
+                        tag: job.tag,
+                        size: job.bytes,
+                    };
+                    len += 1;
+                }
+                PoolMessage::Quit | PoolMessage::Retire => break,
+            }
+        }
+
+        if len <= 1 {
+            return 0;
+        }
+
+        // Fall back to FIFO if the selector picks outside the window.
+        let index = selector(&window[..len]);
+        if index < len {
+            index
+        } else {
+            0
+        }
+    }
+}
+
+// How many WaitGroups a pool keeps for reuse.
+const FREE_WAIT_GROUPS: usize = 64;
+
+impl Default for PoolInner {
+    fn default() -> Self {
+        PoolInner {
+            queue: BlockingQueue::new(),
+            thread_config: ThreadConfig::default(),
+            thread_counter: AtomicUsize::new(1),
+            memory_budget: None,
+            spawner: Box::new(StdSpawn),
+            task_selector: None,
+            idle_strategy: IdleStrategy::Park,
+            steal_backoff: StealBackoff::Constant,
+            workers: Mutex::new(Vec::new()),
+            worker_ready: Condvar::new(),
+            drain_waiters: AtomicUsize::new(0),
+            poison_message: None,
+            task_context: None,
+            on_worker_start: None,
+            on_worker_stop: None,
+            idle_timed_out: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            jobs_run: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+            peak_running: AtomicUsize::new(0),
+            concurrency_limit: None,
+            queued: AtomicUsize::new(0),
+            retiring: AtomicUsize::new(0),
+            configured: AtomicUsize::new(0),
+            shut_down: AtomicBool::new(false),
+            free_wait_groups: ArrayQueue::new(FREE_WAIT_GROUPS),
+            autoscaler: None,
+            idle_monitor: None,
+            slow_task_monitor: None,
+            on_slow_task: None,
+            watchdog: Watchdog::default(),
+            joiner: Arc::default(),
+            max_scope_depth: None,
+            recursion_order: None,
+            cancel_on_panic: false,
+            min_workers: 0,
+            detached_panic_policy: DetachedPanicPolicy::Propagate,
+            panic_behavior: PanicBehavior::Unwind,
+            fail_fast: false,
+            dead: AtomicBool::new(false),
+            idle_callback: RwLock::new(None),
+            #[cfg(feature = "remote")]
+            transport: None,
+            #[cfg(feature = "trace")]
+            submitted: AtomicU64::new(0),
+            #[cfg(feature = "trace")]
+            recording: None,
+            #[cfg(feature = "trace")]
+            replay: None,
+        }
+    }
+}
+
+// The state of one worker thread, shared with the rest of the pool.
+struct WorkerState {
+    // The number this worker's thread was named with, unique in the pool.
+    thread_number: usize,
+    thread: thread::ThreadId,
+    // The job this worker is running, if any.
+    current: Mutex<Option<RunningJob>>,
+    #[cfg(feature = "utilization")]
+    clock: WorkerClock,
+}
+
+// A job as seen by `Pool::current_jobs` and the slow task monitor.
+struct RunningJob {
+    label: Option<&'static str>,
+    started: Instant,
+    // Set once the slow task handler has been told about the job.
+    reported: bool,
+}
+
+// Keeps a worker in the pool's list while its thread runs.
+struct WorkerRegistration<'a> {
+    inner: &'a PoolInner,
+    state: Arc<WorkerState>,
+}
+
+impl<'a> std::ops::Deref for WorkerRegistration<'a> {
+    type Target = WorkerState;
+
+    fn deref(&self) -> &WorkerState {
+        &self.state
+    }
+}
+
+impl<'a> Drop for WorkerRegistration<'a> {
+    fn drop(&mut self) {
+        let number = self.state.thread_number;
+        let mut workers = self.inner.workers.lock().unwrap();
+        workers.retain(|worker| worker.thread_number != number);
+    }
+}
+
+// Tracks how long a worker spends running jobs.
+#[cfg(feature = "utilization")]
+struct WorkerClock {
+    started: Instant,
+    // Nanoseconds spent in jobs which have finished.
+    busy: AtomicU64,
+    // When the running job started, in nanoseconds since `started`, or
+    // `IDLE` between jobs.
+    running_since: AtomicU64,
+    // The elapsed and busy totals at the previous sample.
+    sampled: Mutex<(u64, u64)>,
+}
+
+#[cfg(feature = "utilization")]
+const IDLE: u64 = u64::MAX;
+
+#[cfg(feature = "utilization")]
+impl WorkerClock {
+    fn new() -> WorkerClock {
+        WorkerClock {
+            started: Instant::now(),
+            busy: AtomicU64::new(0),
+            running_since: AtomicU64::new(IDLE),
+            sampled: Mutex::new((0, 0)),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64
+    }
+
+    // Count the time until the returned guard is dropped as busy.
+    fn busy(&self) -> impl Drop + '_ {
+        self.running_since.store(self.now(), Ordering::SeqCst);
+
+        scopeguard::guard(self, |clock| {
+            let since = clock.running_since.swap(IDLE, Ordering::SeqCst);
+            let spent = clock.now().saturating_sub(since);
+            clock.busy.fetch_add(spent, Ordering::SeqCst);
+        })
+    }
+
+    // The busy fraction since the previous sample.
+    fn sample(&self) -> f64 {
+        let now = self.now();
+
+        // Include the running job so far. A job finishing concurrently can
+        // be briefly missed, which the clamp covers.
+        let mut busy = self.busy.load(Ordering::SeqCst);
+        let since = self.running_since.load(Ordering::SeqCst);
+        if since != IDLE {
+            busy += now.saturating_sub(since);
+        }
+
+        let mut sampled = self.sampled.lock().unwrap();
+        let (last_now, last_busy) = *sampled;
+        *sampled = (now, busy.max(last_busy));
+
+        let elapsed = now.saturating_sub(last_now);
+        if elapsed == 0 {
+            return 0.0;
+        }
+
+        (busy.saturating_sub(last_busy) as f64 / elapsed as f64).min(1.0)
+    }
+}
+
+/// The number of queued tasks a task selector chooses between.
+pub const SELECTOR_WINDOW: usize = 8;
+
+/// Chooses which of a window of queued tasks a worker runs next.
+pub type TaskSelector = dyn Fn(&[PeekedTask]) -> usize + Send + Sync;
+
+/// Captures context on the thread submitting a job, returning a function
+/// which runs the job within that context on the worker.
+pub type TaskContext = dyn Fn() -> Box<EnterContext> + Send + Sync;
+
+/// Runs a job, passed as the argument, within some captured context.
+pub type EnterContext = dyn FnOnce(&mut dyn FnMut()) + Send;
+
+/// A view of a queued task, offered to a task selector.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeekedTask {
+    tag: u64,
+    size: usize,
+}
+
+impl PeekedTask {
+    /// The tag given to the task by `Scope::execute_tagged`, or zero.
+    #[inline]
+    pub fn tag(&self) -> u64 {
+        self.tag
+    }
+
+    /// The size in bytes of the task's closure.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+// Limits the total size of closures waiting in the queue.
+struct MemoryBudget {
+    limit: usize,
+    queued: AtomicUsize,
+    // Submitters waiting for room.
+    blocked: Mutex<usize>,
+    cond: Condvar,
+}
+
+impl MemoryBudget {
+    fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            queued: AtomicUsize::new(0),
+            blocked: Mutex::new(0),
+            cond: Condvar::new(),
+        }
+    }
+
+    // Block until `bytes` fit in the budget, then claim them.
+    //
+    // An empty queue always admits the next closure, however large, so a
+    // single closure bigger than the whole budget can't block forever.
+    fn acquire(&self, bytes: usize) {
+        let mut blocked = self.blocked.lock().unwrap();
+        let mut waited = false;
+
+        loop {
+            let queued = self.queued.load(Ordering::SeqCst);
+
+            if queued == 0 || queued.saturating_add(bytes) <= self.limit {
+                if waited {
+                    *blocked -= 1;
+                }
+                self.queued.fetch_add(bytes, Ordering::SeqCst);
+                return;
+            }
+
+            // Count ourselves blocked once, waking anyone watching for it.
+            if !waited {
+                waited = true;
+                *blocked += 1;
+                self.cond.notify_all();
+            }
+
+            blocked = self.cond.wait(blocked).unwrap();
+        }
+    }
+
+    // Return `bytes` to the budget, waking blocked submitters.
+    fn release(&self, bytes: usize) {
+        self.queued.fetch_sub(bytes, Ordering::SeqCst);
+
+        let _lock = self.blocked.lock().unwrap();
+        self.cond.notify_all();
+    }
+}
+
+/// Pool configuration. Collects the options used to construct a `Pool`, from
+/// which configuration methods can be chained.
+#[derive(Default)]
+pub struct PoolBuilder {
+    size: usize,
+    thread_config: ThreadConfig,
+    queue_memory_limit: Option<usize>,
+    spawner: Option<Box<dyn Spawn>>,
+    task_selector: Option<Box<TaskSelector>>,
+    idle_strategy: IdleStrategy,
+    steal_backoff: StealBackoff,
+    poison_message: Option<String>,
+    task_context: Option<Box<TaskContext>>,
+    on_worker_start: Option<Box<WorkerStartHook>>,
+    on_worker_stop: Option<Box<WorkerStopHook>>,
+    ordered_start: bool,
+    autoscale: Option<Autoscale>,
+    auto_shutdown: Option<Duration>,
+    slow_task: Option<(Duration, Box<SlowTaskHandler>)>,
+    max_scope_depth: Option<usize>,
+    recursion_order: Option<RecursionOrder>,
+    cancel_on_panic: bool,
+    min_workers: Option<usize>,
+    max_concurrent_tasks: Option<usize>,
+    detached_panic_policy: DetachedPanicPolicy,
+    panic_behavior: PanicBehavior,
+    fail_fast: bool,
+    #[cfg(feature = "remote")]
+    transport: Option<Box<dyn Transport>>,
+    #[cfg(feature = "trace")]
+    record_trace: bool,
+    #[cfg(feature = "trace")]
+    replay_trace: Option<Trace>,
+}
+
+impl PoolBuilder {
+    /// Generates the base configuration for a pool with no threads.
+    pub fn new() -> PoolBuilder {
+        PoolBuilder::default()
+    }
+
+    /// Sets the number of threads started by `build`.
+    pub fn size(self, size: usize) -> PoolBuilder {
+        PoolBuilder { size, ..self }
+    }
+
+    /// Sets the configuration used for every thread the pool spawns.
+    pub fn thread_config(self, thread_config: ThreadConfig) -> PoolBuilder {
+        PoolBuilder {
+            thread_config,
+            ..self
+        }
+    }
+
+    /// Limits the total size in bytes of the closures waiting in the queue.
+    ///
+    /// Each queued job counts for the `size_of` its closure, and `execute`
+    /// blocks while adding the job would exceed the limit. This bounds memory
+    /// when jobs are large, where a limit on the number of jobs would not.
+    ///
+    /// A closure larger than the whole limit is still admitted once the queue
+    /// is empty, rather than blocking forever.
+    ///
+    /// NOTE: Jobs which `execute` further jobs can deadlock if every worker is
+    /// blocked waiting for space in the queue.
+    pub fn queue_memory_limit(self, bytes: usize) -> PoolBuilder {
+        PoolBuilder {
+            queue_memory_limit: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Sets the `Spawn` implementation used to start worker threads.
+    ///
+    /// Defaults to `StdSpawn`, which uses `std::thread`.
+    pub fn spawner<S: Spawn + 'static>(self, spawner: S) -> PoolBuilder {
+        PoolBuilder {
+            spawner: Some(Box::new(spawner)),
+            ..self
+        }
+    }
+
+    /// Sets a function which chooses the next task for an idle worker.
+    ///
+    /// Rather than always taking the oldest task, the worker offers the
+    /// selector up to `SELECTOR_WINDOW` of the oldest queued tasks, oldest
+    /// first, and runs the one at the returned index. This allows policies
+    /// such as earliest-deadline-first, using tags attached with
+    /// `Scope::execute_tagged`. An index outside the window selects the
+    /// oldest task, as does having no selector at all.
+    ///
+    /// The selector is called while the queue is locked, so it should be
+    /// quick and must not panic or submit jobs.
+    pub fn task_selector(self, selector: Box<TaskSelector>) -> PoolBuilder {
+        PoolBuilder {
+            task_selector: Some(selector),
+            ..self
+        }
+    }
+
+    /// Sets a function which carries context, such as a tracing span, from
+    /// the thread submitting a job to the worker running it.
+    ///
+    /// `capture` is called on the submitting thread for every job, and
+    /// returns a function which the worker calls with the job, so it can
+    /// re-enter the captured context around it. For example, with `tracing`:
+    ///
+    /// ```ignore
+    /// PoolBuilder::new().task_context(Box::new(|| {
+    ///     let span = tracing::Span::current();
+    ///     Box::new(move |job| span.in_scope(job))
+    /// }))
+    /// ```
+    ///
+    /// Without a task context, jobs run exactly as submitted.
+    pub fn task_context(self, capture: Box<TaskContext>) -> PoolBuilder {
+        PoolBuilder {
+            task_context: Some(capture),
+            ..self
+        }
+    }
+
+    /// Sets a hook run on each worker thread as it starts, before it takes
+    /// any jobs.
+    ///
+    /// Restarted and donated threads count as new workers, with new ids.
+    /// Like a job, the hook must not panic.
+    pub fn on_worker_start(self, hook: Box<dyn Fn(WorkerId) + Send + Sync>) -> PoolBuilder {
+        PoolBuilder {
+            on_worker_start: Some(hook),
+            ..self
+        }
+    }
+
+    /// Sets a hook run on each worker thread as it stops, saying why.
+    ///
+    /// A panicked worker reports `StopReason::Panicked` before its
+    /// replacement starts, so counting those reasons tracks restarts. Like
+    /// a job, the hook must not panic.
+    pub fn on_worker_stop(
+        self,
+        hook: Box<dyn Fn(WorkerId, StopReason) + Send + Sync>,
+    ) -> PoolBuilder {
+        PoolBuilder {
+            on_worker_stop: Some(hook),
+            ..self
+        }
+    }
+
+    /// Sets whether jobs are started in the order they were submitted.
+    ///
+    /// By default, jobs submitted from a worker go to that worker's own
+    /// deque and are run newest first, while other workers steal the oldest,
+    /// so the order jobs start in is unpredictable. With ordered start, every
+    /// job goes through a single FIFO queue, and workers take jobs from it
+    /// strictly in submission order, at some cost to throughput.
+    ///
+    /// Only the start order is guaranteed: once taken, jobs run concurrently,
+    /// so a job can finish, or even reach its first line, before a job which
+    /// was started earlier on another worker. Submission order between
+    /// threads is the order their submissions reach the queue. Ordered start
+    /// overrides any task selector.
+    ///
+    /// Jobs explicitly asked to go ahead are the exception. Those spawned
+    /// with `Pool::spawn_urgent`, or moved by `Scope::boost` and
+    /// `Scope::join_boosted`, are taken before any others, oldest first.
+    /// Under `RecursionOrder::DepthFirst`, jobs added with `Scope::recurse`
+    /// go to the front of the queue, behind the urgent ones, so the newest
+    /// is taken first.
+    pub fn ordered_start(self, ordered: bool) -> PoolBuilder {
+        PoolBuilder {
+            ordered_start: ordered,
+            ..self
+        }
+    }
+
+    /// Sets whether the pool records the order jobs start in, to be taken
+    /// with `Pool::take_trace`.
+    ///
+    /// Each job is identified by its submission index, counting every job
+    /// submitted to the pool from zero. Recording takes a lock on every job
+    /// started, which serializes workers briefly and may perturb the timing
+    /// of the bug being chased. Only available with the `trace` feature.
+    #[cfg(feature = "trace")]
+    pub fn record_trace(self, record: bool) -> PoolBuilder {
+        PoolBuilder {
+            record_trace: record,
+            ..self
+        }
+    }
+
+    /// Replay a recorded trace, running jobs one at a time in its order.
+    ///
+    /// The pool gets a single worker, whatever its size or autoscale
+    /// settings, which holds back each job until every job before it in the
+    /// trace has run. Jobs beyond the end of the trace run in submission
+    /// order. This makes a failure seen in a parallel run reproducible, as
+    /// long as the program submits jobs in the same order; a job list which
+    /// diverges from the trace can leave the worker waiting for a job which
+    /// never comes, until the pool is shut down.
+    ///
+    /// Held back jobs are out of reach of `Pool::clear_queue` and
+    /// `Scope::cancel_pending`. Only available with the `trace` feature.
+    #[cfg(feature = "trace")]
+    pub fn replay_trace(self, trace: Trace) -> PoolBuilder {
+        PoolBuilder {
+            replay_trace: Some(trace),
+            ..self
+        }
+    }
+
+    /// Sets the pool to resize itself within `config`'s bounds according to
+    /// its backlog.
+    ///
+    /// A background thread, started by the pool's spawner, samples the queue
+    /// every interval. It adds a thread while more jobs are queued than the
+    /// target depth, and removes one while nothing is queued and some thread
+    /// is idle, one thread per sample. It stops when the pool is shut down.
+    /// The initial size is clamped to the configured bounds.
+    pub fn autoscale(self, config: Autoscale) -> PoolBuilder {
+        PoolBuilder {
+            autoscale: Some(config),
+            ..self
+        }
+    }
+
+    /// Sets the pool to shut itself down once it has been idle for `timeout`.
+    ///
+    /// The pool is idle while no jobs are queued or running. A background
+    /// thread, started by the pool's spawner, checks for this several times
+    /// per timeout, and when the pool has been idle throughout it calls
+    /// `shutdown`, so workers exit and don't keep the process alive. Since
+    /// the pool may have shut down by the time it's next used, this suits
+    /// pools used in bursts whose owner may never get to call `shutdown`.
+    ///
+    /// Idleness is all that counts: clones of the `Pool` may still be held,
+    /// and open scopes whose schedulers go quiet for longer than the timeout
+    /// lose their workers too. Afterwards every clone sees `is_shutdown`,
+    /// and scheduling onto it panics as after any other shutdown.
+    pub fn auto_shutdown_after(self, timeout: Duration) -> PoolBuilder {
+        PoolBuilder {
+            auto_shutdown: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Sets a handler called when a job has been running for longer than
+    /// `threshold`, with the job's label, if it has one, and how long it had
+    /// been running.
+    ///
+    /// A background thread, started by the pool's spawner, checks the
+    /// running jobs several times per threshold, so a slow job is reported
+    /// a little after it passes the threshold, and once at most. This
+    /// surfaces hung or pathologically slow jobs while they still run. The
+    /// handler runs on the monitoring thread and is only advisory: the job
+    /// carries on regardless. Like a job, the handler must not panic.
+    pub fn slow_task_threshold(
+        self,
+        threshold: Duration,
+        handler: Box<dyn Fn(Option<&'static str>, Duration) + Send + Sync>,
+    ) -> PoolBuilder {
+        PoolBuilder {
+            slow_task: Some((threshold, handler)),
+            ..self
+        }
+    }
+
+    /// Sets how deeply `zoom`, `zoom_deferred` and `recurse` can nest.
+    ///
+    /// The scope passed to `Pool::scoped` is at depth 1, and each nested
+    /// scope or recursed job is one deeper than the scope it came from.
+    ///
+    /// Nesting past the maximum panics with a message saying so, turning
+    /// accidentally unbounded recursion into an error rather than a stack
+    /// overflow or a hang. The panic happens on the thread doing the nesting,
+    /// and within a job propogates to the scope as usual. By default there is
+    /// no limit.
+    pub fn max_scope_depth(self, depth: usize) -> PoolBuilder {
+        PoolBuilder {
+            max_scope_depth: Some(depth),
+            ..self
+        }
+    }
+
+    /// Sets where jobs added with `Scope::recurse` are queued, for scopes
+    /// which don't choose with `Scope::with_recursion_order`.
+    ///
+    /// By default they are queued like any other job.
+    pub fn recursion_order(self, order: RecursionOrder) -> PoolBuilder {
+        PoolBuilder {
+            recursion_order: Some(order),
+            ..self
+        }
+    }
+
+    /// Sets whether a panicking job cancels the rest of its scope's jobs,
+    /// for scopes which don't choose with `Scope::cancel_on_panic`.
+    ///
+    /// Jobs `spawn`ed on the pool are unrelated to each other, so they always
+    /// carry on after a panic. By default scopes run every job.
+    pub fn cancel_on_panic(self, cancel: bool) -> PoolBuilder {
+        PoolBuilder {
+            cancel_on_panic: cancel,
+            ..self
+        }
+    }
+
+    /// Limits how many jobs run at once, however many workers there are.
+    ///
+    /// This is for when the bottleneck is some external resource rather than
+    /// the CPU: surplus workers wait for a running job to finish before
+    /// starting theirs. A job which waits on other jobs of the pool, as
+    /// with `zoom`, keeps its slot while it waits, so enough of them can
+    /// deadlock the pool.
+    pub fn max_concurrent_tasks(self, n: usize) -> PoolBuilder {
+        PoolBuilder {
+            max_concurrent_tasks: Some(n),
+            ..self
+        }
+    }
+
+    /// Sets the number of threads `Pool::compact` shrinks the pool to.
+    ///
+    /// The default is the minimum set with `autoscale`, or no threads at all
+    /// without it.
+    pub fn min_workers(self, min: usize) -> PoolBuilder {
+        PoolBuilder {
+            min_workers: Some(min),
+            ..self
+        }
+    }
+
+    /// Sets what happens when a job started with `Pool::spawn`,
+    /// `spawn_urgent` or `spawn_future` panics.
+    ///
+    /// Jobs on scopes and subpools are unaffected, since something is
+    /// waiting to hear about their panics. The default is
+    /// `DetachedPanicPolicy::Propagate`.
+    pub fn detached_panic_policy(self, policy: DetachedPanicPolicy) -> PoolBuilder {
+        PoolBuilder {
+            detached_panic_policy: policy,
+            ..self
+        }
+    }
+
+    /// Sets what happens when any job run by the pool panics.
+    ///
+    /// With `PanicBehavior::Abort`, a panicking job aborts the process
+    /// instead of unwinding into the pool, for services which would rather
+    /// stop than carry on past possibly corrupted state. Panics caught
+    /// within a job, as by `execute_retry` or
+    /// `DetachedPanicPolicy::CatchAndLog`, are unaffected. The default is
+    /// `PanicBehavior::Unwind`.
+    pub fn panic_behavior(self, behavior: PanicBehavior) -> PoolBuilder {
+        PoolBuilder {
+            panic_behavior: behavior,
+            ..self
+        }
+    }
+
+    /// Sets whether a panicking worker kills the whole pool, rather than
+    /// being restarted.
+    ///
+    /// Once a job panics on a `fail_fast` pool, the pool is dead for good:
+    /// queued jobs are dropped unrun, poisoning their scopes so every join
+    /// unblocks with a panic, the workers stop, and new work is refused
+    /// with a panic. `Pool::is_dead` tells whether this has happened. Panics
+    /// caught within a job don't count. The default is false.
+    pub fn fail_fast(self, fail_fast: bool) -> PoolBuilder {
+        PoolBuilder { fail_fast, ..self }
+    }
+
+    /// Sets where `Pool::spawn_remote` ships tasks, instead of running them
+    /// on the pool's own workers.
+    ///
+    /// Only available with the `remote` feature.
+    #[cfg(feature = "remote")]
+    pub fn remote_transport(self, transport: Box<dyn Transport>) -> PoolBuilder {
+        PoolBuilder {
+            transport: Some(transport),
+            ..self
+        }
+    }
+
+    /// Sets how many times an idle worker checks for a new task, spinning
+    /// between checks, before blocking on the queue.
+    ///
+    /// Spinning avoids the cost of parking and waking a worker when tasks
+    /// arrive in bursts, at the price of burning CPU while idle. The default
+    /// of zero blocks immediately. This is shorthand for
+    /// `idle_strategy(IdleStrategy::Spin(iters))`.
+    pub fn spin_before_park(self, iters: usize) -> PoolBuilder {
+        self.idle_strategy(IdleStrategy::Spin(iters))
+    }
+
+    /// Sets what an idle worker does when it finds the queue empty, before
+    /// blocking on it. The default is `IdleStrategy::Park`.
+    ///
+    /// `benches/dispatch_latency.rs` compares the dispatch latency and CPU
+    /// time of each strategy.
+    pub fn idle_strategy(self, idle_strategy: IdleStrategy) -> PoolBuilder {
+        PoolBuilder {
+            idle_strategy,
+            ..self
+        }
+    }
+
+    /// Sets how an idle worker spaces out its checks of the queue, within
+    /// the spins or yields its `IdleStrategy` allows. The default is
+    /// `StealBackoff::Constant`.
+    ///
+    /// Each check looks at every worker's deque, which costs far more than
+    /// a spin, so backing off makes idling much cheaper when the pool is
+    /// lightly loaded, at the price of noticing new work a little later.
+    /// `benches/steal_backoff.rs` compares the two.
+    pub fn steal_backoff(self, steal_backoff: StealBackoff) -> PoolBuilder {
+        PoolBuilder {
+            steal_backoff,
+            ..self
+        }
+    }
+
+    /// Sets the message a join panics with when a job in the scope panicked.
+    ///
+    /// Counts of the jobs which completed and failed are appended to it. The
+    /// default is "WaitGroup explicitly poisoned!".
+    pub fn poison_message(self, message: impl Into<String>) -> PoolBuilder {
+        PoolBuilder {
+            poison_message: Some(message.into()),
+            ..self
+        }
+    }
+
+    /// Create the configured Pool and start its threads.
+    pub fn build(self) -> Pool {
+        let (size, autoscale) = (self.size, self.autoscale);
+
+        // A replay is serialized on a single worker.
+        #[cfg(feature = "trace")]
+        let (size, autoscale) = match self.replay_trace {
+            Some(_) => (1, None),
+            None => (size, autoscale),
+        };
+
+        // Selecting tasks means looking into the queue, and ordering them
+        // means sharing one, neither of which per-worker deques can do.
+        let queue = if self.task_selector.is_some() || self.ordered_start {
+            BlockingQueue::deque()
+        } else {
+            BlockingQueue::new()
+        };
+
+        let task_selector = if self.ordered_start {
+            None
+        } else {
+            self.task_selector
+        };
+
+        let slow_task_threshold = self.slow_task.as_ref().map(|&(threshold, _)| threshold);
+
+        let inner = Arc::new(PoolInner {
+            queue,
+            task_selector,
+            idle_strategy: self.idle_strategy,
+            steal_backoff: self.steal_backoff,
+            thread_config: self.thread_config,
+            memory_budget: self.queue_memory_limit.map(MemoryBudget::new),
+            spawner: self.spawner.unwrap_or_else(|| Box::new(StdSpawn)),
+            poison_message: self.poison_message.map(Arc::from),
+            task_context: self.task_context,
+            on_worker_start: self.on_worker_start,
+            on_worker_stop: self.on_worker_stop,
+            max_scope_depth: self.max_scope_depth,
+            recursion_order: self.recursion_order,
+            cancel_on_panic: self.cancel_on_panic,
+            concurrency_limit: self.max_concurrent_tasks.map(Semaphore::new),
+            min_workers: self
+                .min_workers
+                .or(autoscale.as_ref().map(|config| config.min))
+                .unwrap_or(0),
+            detached_panic_policy: self.detached_panic_policy,
+            panic_behavior: self.panic_behavior,
+            fail_fast: self.fail_fast,
+            #[cfg(feature = "remote")]
+            transport: self.transport,
+            #[cfg(feature = "trace")]
+            recording: if self.record_trace {
+                Some(Mutex::new(Vec::new()))
+            } else {
+                None
+            },
+            #[cfg(feature = "trace")]
+            replay: self.replay_trace.as_ref().map(Replay::new),
+            autoscaler: autoscale.map(|_| MonitorControl::new()),
+            idle_monitor: self.auto_shutdown.map(|_| MonitorControl::new()),
+            slow_task_monitor: self.slow_task.as_ref().map(|_| MonitorControl::new()),
+            on_slow_task: self.slow_task.map(|(_, handler)| handler),
+            ..PoolInner::default()
+        });
+
+        // Spawned jobs carry on after one panics, whatever scopes do.
+        let spawned = inner.wait_group();
+        spawned.cancel_on_panic.store(false, Ordering::SeqCst);
+
+        let pool = Pool {
+            spawned,
+            inner,
+            ..Pool::default()
+        };
+
+        let size = match autoscale {
+            Some(ref config) => size.clamp(config.min, config.max.max(config.min)),
+            None => size,
+        };
+
+        // Start the requested number of threads.
+        for _ in 0..size {
+            pool.expand();
+        }
+
+        if let Some(config) = autoscale {
+            let autoscaler = pool.clone();
+            let control = pool.inner.autoscaler.as_ref().unwrap();
+            pool.spawn_monitor(&control.running, "autoscaler", move || {
+                autoscaler.run_autoscaler(config)
+            });
+        }
+
+        if let Some(timeout) = self.auto_shutdown {
+            let monitor = pool.clone();
+            let control = pool.inner.idle_monitor.as_ref().unwrap();
+            pool.spawn_monitor(&control.running, "idle-monitor", move || {
+                monitor.run_idle_monitor(timeout)
+            });
+        }
+
+        if let Some(threshold) = slow_task_threshold {
+            let monitor = pool.clone();
+            let control = pool.inner.slow_task_monitor.as_ref().unwrap();
+            pool.spawn_monitor(&control.running, "slow-task-monitor", move || {
+                monitor.run_slow_task_monitor(threshold)
+            });
+        }
+
+        pool
+    }
+}
+
+/// Bounds and tuning for a pool which resizes itself, set with
+/// `PoolBuilder::autoscale`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Autoscale {
+    min: usize,
+    max: usize,
+    target_queue_depth: usize,
+    interval: Duration,
+}
+
+impl Autoscale {
+    /// Keep between `min` and `max` threads, targeting an empty queue and
+    /// sampling every 100ms.
+    pub fn new(min: usize, max: usize) -> Autoscale {
+        Autoscale {
+            min,
+            max,
+            target_queue_depth: 0,
+            interval: Duration::from_millis(100),
+        }
+    }
+
+    /// Sets how many queued jobs are tolerated before adding a thread.
+    pub fn target_queue_depth(self, depth: usize) -> Autoscale {
+        Autoscale {
+            target_queue_depth: depth,
+            ..self
+        }
+    }
+
+    /// Sets how often the queue is sampled.
+    pub fn interval(self, interval: Duration) -> Autoscale {
+        Autoscale { interval, ..self }
+    }
+}
+
+// The timers of running watchdog jobs, and the thread which expires them.
+#[derive(Default)]
+struct Watchdog {
+    timers: Mutex<WatchdogTimers>,
+    wake: Condvar,
+    running: WaitGroup,
+}
+
+#[derive(Default)]
+struct WatchdogTimers {
+    started: bool,
+    stopped: bool,
+    // Deadlines along with the flags to set once they pass.
+    armed: Vec<(Instant, Arc<AtomicBool>)>,
+}
+
+// The thread which calls back `Scope::on_join`, started with the first, and
+// the scopes it is watching.
+#[derive(Default)]
+struct Joiner {
+    state: Mutex<JoinerState>,
+    wake: Condvar,
+}
+
+#[derive(Default)]
+struct JoinerState {
+    started: bool,
+    stopped: bool,
+    // Set when a watched group may have settled, to check the scopes again.
+    changed: bool,
+    waiting: Vec<(Scope<'static>, JoinCallback)>,
+}
+
+type JoinCallback = Box<dyn FnOnce(bool) + Send>;
+
+impl Joiner {
+    // Have the thread check its scopes again.
+    fn poke(&self) {
+        self.state.lock().unwrap().changed = true;
+        self.wake.notify_all();
+    }
+
+    // Let the thread exit once it has nothing left to call back.
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.wake.notify_all();
+    }
+
+    // Call back each scope as it settles, until stopped with none left.
+    fn run(self: Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if !state.changed {
+                if state.stopped && state.waiting.is_empty() {
+                    state.started = false;
+                    return;
+                }
+
+                state = self.wake.wait(state).unwrap();
+                continue;
+            }
+
+            // Check outside the lock, since releasing `execute_finally`
+            // jobs can end a round and poke us.
+            state.changed = false;
+            let scopes = mem::take(&mut state.waiting);
+            drop(state);
+
+            let mut unsettled = Vec::new();
+            for (scope, callback) in scopes {
+                if !scope.settle_for(&self) {
+                    unsettled.push((scope, callback));
+                    continue;
+                }
+
+                // Settled, so this doesn't block. As on a thread of its
+                // own, a panicking callback is only reported by the hook.
+                let clean = panic::catch_unwind(AssertUnwindSafe(|| scope.join())).is_ok();
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| callback(clean)));
+            }
+
+            state = self.state.lock().unwrap();
+            state.waiting.extend(unsettled);
+        }
+    }
+}
+
+// Lets shutdown stop a monitor thread, like the autoscaler, and wait for it.
+struct MonitorControl {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+    running: WaitGroup,
+}
+
+impl MonitorControl {
+    fn new() -> MonitorControl {
+        MonitorControl {
+            stopped: Mutex::new(false),
+            wake: Condvar::new(),
+            running: WaitGroup::new(),
+        }
+    }
+
+    // Stop the thread, returning false if it was already stopped.
+    fn stop(&self) -> bool {
+        let mut stopped = self.stopped.lock().unwrap();
+        if *stopped {
+            return false;
+        }
+
+        *stopped = true;
+        self.wake.notify_all();
+        true
+    }
+}
+
+/// Thread configuration. Provides detailed control over the properties and behavior of new
+/// threads.
+#[derive(Default)]
+pub struct ThreadConfig {
+    prefix: Option<String>,
+    stack_size: Option<usize>,
+}
+
+impl ThreadConfig {
+    /// Generates the base configuration for spawning a thread, from which configuration methods
+    /// can be chained.
+    pub fn new() -> ThreadConfig {
+        ThreadConfig {
+            prefix: None,
+            stack_size: None,
+        }
+    }
+
+    /// Name prefix of spawned threads. Thread number will be appended to this prefix to form each
+    /// thread's unique name. Currently the name is used for identification only in panic
+    /// messages.
+    pub fn prefix<S: Into<String>>(self, prefix: S) -> ThreadConfig {
+        ThreadConfig {
+            prefix: Some(prefix.into()),
+            ..self
+        }
+    }
+
+    /// Sets the size of the stack for the new thread.
+    pub fn stack_size(self, stack_size: usize) -> ThreadConfig {
+        ThreadConfig {
+            stack_size: Some(stack_size),
+            ..self
+        }
+    }
+}
+
+/// Collects every error returned by jobs submitted with
+/// `Scope::execute_collecting`.
+///
+/// Only the handle the jobs share is allocated up front; the storage for
+/// the errors is set up when the first one is recorded.
+pub struct ErrorCollector<E> {
+    errors: Arc<OnceLock<Mutex<Vec<E>>>>,
+}
+
+impl<E> ErrorCollector<E> {
+    /// Take the errors recorded so far, in the order they were recorded.
+    ///
+    /// Call this once the scope has been joined, so that every job has had
+    /// the chance to record its error.
+    pub fn into_vec(self) -> Vec<E> {
+        match self.errors.get() {
+            Some(errors) => mem::take(&mut *errors.lock().unwrap()),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// The pending joins of subscopes created by `Scope::zoom_deferred`.
+///
+/// Dropping the guard joins any subscopes which have not been joined yet,
+/// unless the thread is already panicking.
+#[derive(Default)]
+pub struct DeferredJoin {
+    waits: Vec<Arc<WaitGroup>>,
+}
+
+impl DeferredJoin {
+    /// Create an empty guard, to merge deferred subscopes into.
+    pub fn new() -> DeferredJoin {
+        DeferredJoin::default()
+    }
+
+    /// Take over the subscopes of `other`, to be joined along with ours.
+    pub fn merge(&mut self, mut other: DeferredJoin) {
+        self.waits.append(&mut other.waits);
+    }
+
+    /// Wait for the jobs of every subscope in this guard to complete.
+    ///
+    /// Panics if any of those jobs panicked.
+    pub fn join_all(mut self) {
+        for wait in mem::take(&mut self.waits) {
+            wait.join();
+        }
+    }
+}
+
+impl Drop for DeferredJoin {
+    fn drop(&mut self) {
+        // A panic here would abort; the parent scope's join reports it.
+        if thread::panicking() {
+            return;
+        }
+
+        for wait in mem::take(&mut self.waits) {
+            wait.join();
+        }
+    }
+}
+
+/// Stops a scope's batch of jobs, created with `Scope::abort_handle`.
+///
+/// Handles can be cloned and shared freely, and outliving the scope is
+/// harmless.
+#[derive(Clone)]
+pub struct AbortHandle {
+    pool: Pool,
+    wait: Arc<WaitGroup>,
+}
+
+impl AbortHandle {
+    /// Stop the scope's jobs which haven't started yet.
+    ///
+    /// Queued jobs are discarded, and any submitted later, or still on their
+    /// way to a worker, are skipped when they would start. Skipped jobs count
+    /// as completed, so `join` returns once running jobs finish. Running jobs
+    /// aren't interrupted, but can check `is_aborted` to stop early. The
+    /// scope stays aborted for the rest of its life.
+    pub fn abort(&self) {
+        self.wait.aborted.store(true, Ordering::SeqCst);
+        self.pool.inner.cancel(&self.wait);
+    }
+
+    /// Whether the scope has been aborted.
+    #[inline]
+    pub fn is_aborted(&self) -> bool {
+        self.wait.aborted.load(Ordering::SeqCst)
+    }
+}
+
+/// Records the result of a parallel search, created by `Pool::scoped_find`.
+///
+/// Handles can be cloned into jobs freely. The first value reported wins,
+/// and aborts the search's scope.
+pub struct Finder<T> {
+    abort: AbortHandle,
+    found: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> Clone for Finder<T> {
+    fn clone(&self) -> Self {
+        Finder {
+            abort: self.abort.clone(),
+            found: self.found.clone(),
+        }
+    }
+}
+
+impl<T> Finder<T> {
+    /// Report a result, unless one has been already.
+    ///
+    /// The first report aborts the scope, so its queued jobs are skipped.
+    /// Returns whether this was the first report.
+    pub fn report(&self, value: T) -> bool {
+        let mut found = self.found.lock().unwrap();
+        if found.is_some() {
+            return false;
+        }
+
+        *found = Some(value);
+        drop(found);
+
+        self.abort.abort();
+        true
+    }
+
+    /// Whether a result has been reported, for jobs to check so they can
+    /// stop searching early.
+    #[inline]
+    pub fn is_found(&self) -> bool {
+        self.abort.is_aborted()
+    }
+}
+
+/// Cancels a scope along with every subscope nested in it, created with
+/// `Scope::cancel_token`.
+///
+/// Tokens form a tree following `zoom`: cancelling a scope cancels the
+/// scopes zoomed from it, but not the scope it was zoomed from. Scopes
+/// handed out by `recurse` are the same scope, so share its token. Tokens
+/// can be cloned and shared freely, and outliving the scope is harmless.
+#[derive(Clone)]
+pub struct CancelToken {
+    locals: Arc<ScopeLocals>,
+}
+
+impl CancelToken {
+    /// Cancel the scope and its subscopes.
+    ///
+    /// Their jobs which haven't started yet are skipped when they would
+    /// start, counting as completed, so joins return once running jobs
+    /// finish. Running jobs aren't interrupted, but can check
+    /// `is_cancelled` to stop early. Unlike `AbortHandle::abort`, queued
+    /// jobs stay in the queue until a worker reaches them.
+    pub fn cancel(&self) {
+        self.locals.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the scope, or any scope enclosing it, has been cancelled.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.locals.is_cancelled()
+    }
+}
+
+/// The results of `Scope::map_bounded`, in the order they complete.
+pub struct BoundedMap<'a, 'scope, T, U> {
+    scope: &'a Scope<'scope>,
+    items: Box<dyn Iterator<Item = T> + 'a>,
+    f: Arc<MapFn<'scope, T, U>>,
+    buffer: Arc<MapBuffer<U>>,
+    max_buffered: usize,
+    exhausted: bool,
+}
+
+type MapFn<'scope, T, U> = dyn Fn(T) -> U + Send + Sync + 'scope;
+
+struct MapBuffer<U> {
+    state: Mutex<MapState<U>>,
+    ready: Condvar,
+}
+
+struct MapState<U> {
+    results: VecDeque<U>,
+    running: usize,
+    // Set once the consumer has gone, so results are dropped.
+    closed: bool,
+}
+
+impl<'a, 'scope, T, U> BoundedMap<'a, 'scope, T, U>
+where
+    T: Send + 'scope,
+    U: Send + 'scope,
+{
+    // Start jobs until the buffer's capacity is taken up.
+    fn schedule(&mut self) {
+        while !self.exhausted {
+            {
+                let mut state = self.buffer.state.lock().unwrap();
+                if state.running + state.results.len() >= self.max_buffered {
+                    return;
+                }
+                state.running += 1;
+            }
+
+            let item = match self.items.next() {
+                Some(item) => item,
+                None => {
+                    self.exhausted = true;
+                    self.buffer.state.lock().unwrap().running -= 1;
+                    return;
+                }
+            };
+
+            let (f, buffer) = (self.f.clone(), self.buffer.clone());
+            self.scope.execute(move || {
+                // Free the slot even if `f` panics, so the consumer isn't
+                // left waiting for it.
+                let mut result = scopeguard::guard(None, move |result| {
+                    let mut state = buffer.state.lock().unwrap();
+                    state.running -= 1;
+                    if let (Some(result), false) = (result, state.closed) {
+                        state.results.push_back(result);
+                    }
+                    buffer.ready.notify_all();
+                });
+                *result = Some(f(item));
+            });
+        }
+    }
+}
+
+impl<'a, 'scope, T, U> Iterator for BoundedMap<'a, 'scope, T, U>
+where
+    T: Send + 'scope,
+    U: Send + 'scope,
+{
+    type Item = U;
+
+    fn next(&mut self) -> Option<U> {
+        self.schedule();
+
+        let mut state = self.buffer.state.lock().unwrap();
+        loop {
+            if let Some(result) = state.results.pop_front() {
+                return Some(result);
+            }
+
+            if state.running == 0 {
+                // Nothing running and nothing buffered means nothing left,
+                // once the items are exhausted.
+                drop(state);
+                if self.exhausted {
+                    return None;
+                }
+                self.schedule();
+                state = self.buffer.state.lock().unwrap();
+                continue;
+            }
+
+            state = self.buffer.ready.wait(state).unwrap();
+        }
+    }
+}
+
+impl<'a, 'scope, T, U> Drop for BoundedMap<'a, 'scope, T, U> {
+    fn drop(&mut self) {
+        let mut state = self.buffer.state.lock().unwrap();
+        state.closed = true;
+        state.results.clear();
+    }
+}
+
+/// A join over the jobs of two scopes, created with `Scope::merge`.
+pub struct MergedJoin {
+    waits: [Arc<WaitGroup>; 2],
+}
+
+impl MergedJoin {
+    /// Wait for the jobs of both scopes to complete.
+    ///
+    /// Both scopes are waited for before any panic from their jobs is
+    /// propogated, so neither is left running when this returns or panics.
+    /// As with `Scope::join`, jobs submitted concurrently with the call may
+    /// or may not be waited for.
+    pub fn join(&self) {
+        for wait in &self.waits {
+            wait.settle();
+        }
+
+        for wait in &self.waits {
+            wait.join();
+        }
+    }
+}
+
+/// The eventual output of a job submitted with `Scope::execute_owned`.
+pub struct JobHandle<T> {
+    slot: Arc<JobSlot<T>>,
+}
+
+struct JobSlot<T> {
+    state: Mutex<JobState<T>>,
+    ready: Condvar,
+}
+
+enum JobState<T> {
+    Pending,
+    Done(T),
+    Panicked,
+}
+
+impl<T> JobSlot<T> {
+    fn finish(&self, state: JobState<T>) {
+        *self.state.lock().unwrap() = state;
+        self.ready.notify_all();
+    }
+}
+
+impl<T> JobHandle<T> {
+    /// Whether the job has finished, so `join` would not block.
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.slot.state.lock().unwrap(), JobState::Pending)
+    }
+
+    /// Wait for the job to finish and take its output.
+    ///
+    /// Panics if the job panicked.
+    pub fn join(self) -> T {
+        let mut state = self.slot.state.lock().unwrap();
+
+        loop {
+            match mem::replace(&mut *state, JobState::Panicked) {
+                JobState::Pending => {
+                    *state = JobState::Pending;
+                    state = self.slot.ready.wait(state).unwrap();
+                }
+                JobState::Done(value) => return value,
+                JobState::Panicked => panic!("Owned job panicked!"),
+            }
+        }
+    }
+
+    /// Like `join`, but give up after `timeout`, handing the handle back so
+    /// the caller can retry or abandon it.
+    ///
+    /// On timeout the job keeps running in the background, and its scope
+    /// still waits for it as usual.
+    ///
+    /// Panics if the job panicked.
+    pub fn join_timeout(self, timeout: Duration) -> Result<T, JobHandle<T>> {
+        let deadline = Instant::now() + timeout;
+
+        {
+            let mut state = self.slot.state.lock().unwrap();
+
+            loop {
+                match mem::replace(&mut *state, JobState::Panicked) {
+                    JobState::Pending => {
+                        *state = JobState::Pending;
+
+                        let now = Instant::now();
+                        if now >= deadline {
+                            break;
+                        }
+
+                        state = self
+                            .slot
+                            .ready
+                            .wait_timeout(state, deadline - now)
+                            .unwrap()
+                            .0;
+                    }
+                    JobState::Done(value) => return Ok(value),
+                    JobState::Panicked => panic!("Owned job panicked!"),
+                }
+            }
+        }
+
+        Err(self)
+    }
+}
+
+impl<'scope> Drop for Scope<'scope> {
+    fn drop(&mut self) {
+        self.pool.inner.recycle_wait_group(&mut self.wait);
+    }
+}
+
+impl Scope<'static> {
+    /// Run `callback` once this scope's jobs are done, without blocking.
+    ///
+    /// The scope moves to the pool's joiner thread, which joins it once its
+    /// jobs have settled and then calls `callback`, bridging completion to
+    /// callback-style code. The callback receives `true` if every job
+    /// completed cleanly, and `false` if any panicked, in which case the
+    /// panic isn't propogated any further. The scope must be `'static`, as
+    /// from `Scope::forever`, since nothing holds the caller's borrows until
+    /// the join happens.
+    ///
+    /// One joiner thread serves every scope of the pool, started by the
+    /// first call and kept until `shutdown` once every scope it is watching
+    /// has been called back. Callbacks run on it one at a time, so a slow
+    /// one delays the rest. Returns the spawner's error if the thread can't
+    /// be started, in which case `callback` is dropped without being called.
+    pub fn on_join<F>(self, callback: F) -> io::Result<()>
+    where
+        F: FnOnce(bool) + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        let joiner = &pool.inner.joiner;
+        let wait = self.wait.clone();
+
+        let start = {
+            let mut state = joiner.state.lock().unwrap();
+            state.waiting.push((self, Box::new(callback)));
+            state.changed = true;
+            joiner.wake.notify_all();
+            !mem::replace(&mut state.started, true)
+        };
+
+        if start {
+            let thread = joiner.clone();
+            if let Err(err) = pool.spawn_helper("joiner", move || thread.run()) {
+                // Take ours back; any others wait for the next call to start it.
+                let mut state = joiner.state.lock().unwrap();
+                state.started = false;
+                state
+                    .waiting
+                    .retain(|(scope, _)| !Arc::ptr_eq(&scope.wait, &wait));
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Whether `join` would return without waiting, releasing held back
+    // `execute_finally` jobs once the rest have settled, as `join` would.
+    // The joiner is poked when a round of the scope ends.
+    fn settle_for(&self, joiner: &Arc<Joiner>) -> bool {
+        if !self.wait.settled_for(joiner) {
+            return false;
+        }
+
+        let finally = mem::take(&mut *self.wait.finally.lock().unwrap());
+        if finally.is_empty() {
+            return true;
+        }
+
+        for job in finally {
+            self.execute(job);
+        }
+        false
+    }
+}
+
+/// A `'static` Scope which is joined on drop, created by `Pool::scope_guard`.
+///
+/// Derefs to the `Scope`. Dropping the guard panics if a job panicked,
+/// unless the thread is already panicking.
+///
+/// Jobs can't borrow from the stack, since the guard might be leaked:
+///
+/// ```compile_fail
+/// # use scoped_thread_pool::Pool;
+/// let pool = Pool::new(1);
+/// let data = vec![1, 2, 3];
+///
+/// let scope = pool.scope_guard();
+/// scope.execute(|| println!("{:?}", data));
+/// ```
+pub struct ScopeGuard {
+    scope: Scope<'static>,
+}
+
+impl std::ops::Deref for ScopeGuard {
+    type Target = Scope<'static>;
+
+    fn deref(&self) -> &Scope<'static> {
+        &self.scope
+    }
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            // Still wait for the jobs, but a second panic would abort.
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| self.scope.join()));
+        } else {
+            self.scope.join();
+        }
+    }
+}
+
+/// A handle for submitting jobs which all borrow the same shared state.
+///
+/// Created by `Scope::with_shared`.
+pub struct Shared<'a, 'scope, S: Sync + 'scope> {
+    scope: &'a Scope<'scope>,
+    state: &'scope S,
+}
+
+impl<'a, 'scope, S: Sync + 'scope> Shared<'a, 'scope, S> {
+    /// Add a job to the scope, which is given the shared state when it runs.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce(&'scope S) + Send + 'scope,
+    {
+        let state = self.state;
+        self.scope.execute(move || job(state))
+    }
+}
+
+/// What a step of a job added with `Scope::execute_continuation` returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Continuation<S> {
+    /// The job is finished.
+    Done,
+
+    /// Run another step later, on this state.
+    Continue(S),
+}
+
+/// The number of a job added with `Scope::execute_with_id`, counting from
+/// zero in submission order within its scope.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    /// The id as a plain number.
+    #[inline]
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "task {}", self.0)
+    }
+}
+
+/// A handle for submitting jobs which each hold a semaphore permit while
+/// they run.
+///
+/// Created by `Scope::limited`.
+pub struct Limited<'a, 'scope> {
+    scope: &'a Scope<'scope>,
+    semaphore: Arc<Semaphore>,
+}
+
+impl<'a, 'scope> Limited<'a, 'scope> {
+    /// Add a job to the scope, which acquires a permit before it runs and
+    /// releases it once it's done, even by panicking.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let semaphore = self.semaphore.clone();
+        self.scope.execute(move || {
+            let _permit = semaphore.acquire();
+            job()
+        })
+    }
+}
+
+/// A counting semaphore, for bounding how many jobs use a resource at once.
+///
+/// A semaphore isn't tied to any pool, so sharing one between the scopes of
+/// several pools bounds their jobs' use of the resource altogether. See
+/// `Scope::limited`.
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    cond: Condvar,
+}
+
+impl Semaphore {
+    /// Create a semaphore with `permits` permits available.
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: Mutex::new(permits),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// How many permits are available right now.
+    pub fn available(&self) -> usize {
+        *self.permits.lock().unwrap()
+    }
+
+    /// Block until a permit is available, then take it. The permit is
+    /// returned when the guard is dropped.
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.cond.wait(permits).unwrap();
+        }
+
+        *permits -= 1;
+        SemaphorePermit { semaphore: self }
+    }
+
+    /// Take a permit if one is available, without blocking.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut permits = self.permits.lock().unwrap();
+        if *permits == 0 {
+            return None;
+        }
+
+        *permits -= 1;
+        Some(SemaphorePermit { semaphore: self })
+    }
+}
+
+/// A permit taken from a `Semaphore`, returned when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.permits.lock().unwrap() += 1;
+        self.semaphore.cond.notify_one();
+    }
+}
+
+/// A source of threads for a `Pool`.
+///
+/// The scheduling logic of the pool only needs some way to start a thread
+/// running a worker loop; implementing `Spawn` lets the pool run on platforms
+/// with their own thread primitives.
+///
+/// Only starting threads is pluggable. The pool still needs `std`: waits
+/// block on `std::sync` locks and condition variables, panics are caught
+/// with `std::panic`, and workers identify themselves through
+/// `std::thread::current`. A platform without `std` can't use the pool
+/// through this trait alone.
+pub trait Spawn: Send + Sync {
+    /// Start a new thread which runs `main` to completion.
+    ///
+    /// `name` and `stack_size` come from the pool's `ThreadConfig`, and may be
+    /// ignored when the platform does not support them.
+    fn spawn(
+        &self,
+        name: Option<String>,
+        stack_size: Option<usize>,
+        main: Box<dyn FnOnce() + Send>,
+    ) -> io::Result<()>;
+}
+
+/// The default `Spawn` implementation, which starts threads with `std::thread`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdSpawn;
+
+impl Spawn for StdSpawn {
+    fn spawn(
+        &self,
+        name: Option<String>,
+        stack_size: Option<usize>,
+        main: Box<dyn FnOnce() + Send>,
+    ) -> io::Result<()> {
+        let mut builder = thread::Builder::new();
+        if let Some(name) = name {
+            builder = builder.name(name);
+        }
+        if let Some(stack_size) = stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+
+        builder.spawn(main).map(drop)
+    }
+}
+
+/// An execution scope, represents a set of jobs running on a Pool.
+///
+/// ## Understanding Scope lifetimes
+///
+/// Besides `Scope<'static>`, all `Scope` objects are accessed behind a
+/// reference of the form `&'scheduler Scope<'scope>`.
+///
+/// `'scheduler` is the lifetime associated with the *body* of the
+/// "scheduler" function (functions passed to `zoom`/`scoped`).
+///
+/// `'scope` is the lifetime which data captured in `execute` or `recurse`
+/// closures must outlive - in other words, `'scope` is the maximum lifetime
+/// of all jobs scheduler on a `Scope`.
+///
+/// Note that since `'scope: 'scheduler` (`'scope` outlives `'scheduler`)
+/// `&'scheduler Scope<'scope>` can't be captured in an `execute` closure;
+/// this is the reason for the existence of the `recurse` API, which will
+/// inject the same scope with a new `'scheduler` lifetime (this time set
+/// to the body of the function passed to `recurse`).
+///
+/// `Scope` is invariant in `'scope`, so `'scope` can't be shortened to let
+/// jobs borrow data which doesn't live until the join:
+///
+/// ```compile_fail
+/// # use scoped_thread_pool::Scope;
+/// fn shorten<'short, 'long: 'short>(scope: &'short Scope<'long>) -> &'short Scope<'short> {
+///     scope
+/// }
+/// ```
+///
+/// ```compile_fail
+/// # use scoped_thread_pool::Pool;
+/// let pool = Pool::new(1);
+/// pool.scoped(|scope| {
+///     let data = vec![1, 2, 3];
+///     scope.execute(|| println!("{:?}", data));
+/// });
+/// ```
+pub struct Scope<'scope> {
+    pool: Pool,
+    wait: Arc<WaitGroup>,
+    // How many zooms and recurses deep this scope is.
+    depth: usize,
+    // Unique for each scope, and the id of the scope this one was nested in.
+    id: u64,
+    parent: Option<u64>,
+    _scope: Id<'scope>,
+}
+
+// The source of scope ids, shared by every pool.
+static NEXT_SCOPE_ID: AtomicU64 = AtomicU64::new(0);
+
+#[inline]
+fn next_scope_id() -> u64 {
+    NEXT_SCOPE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// Marks a type as invariant in a lifetime, without holding anything.
+#[derive(Clone, Copy, Default)]
+struct Id<'a>(PhantomData<fn(&'a ()) -> &'a ()>);
+
+impl<'scope> Scope<'scope> {
+    /// Create a Scope which lasts forever.
+    #[inline]
+    pub fn forever(pool: Pool) -> Scope<'static> {
+        Scope {
+            wait: pool.inner.wait_group(),
+            pool,
+            depth: 0,
+            id: next_scope_id(),
+            parent: None,
+            _scope: Id::default(),
+        }
+    }
+
+    /// Add a job to this scope.
+    ///
+    /// Subsequent calls to `join` will wait for this job to complete.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        self.execute_tagged(0, job)
+    }
+
+    /// Add a job which runs `step` on `state` repeatedly, queueing itself
+    /// again between steps, until `step` returns `Continuation::Done`.
+    ///
+    /// Each `Continuation::Continue` hands the worker back to the pool and
+    /// queues the next step behind work already waiting, so a long running
+    /// job can share the pool fairly instead of holding a worker throughout.
+    /// The scope's `join` waits for the final step. A panic in any step ends
+    /// the job and propogates as usual.
+    pub fn execute_continuation<S, F>(&self, state: S, step: F)
+    where
+        S: Send + 'scope,
+        F: Fn(S) -> Continuation<S> + Send + Sync + 'scope,
+    {
+        self.continue_with(Lane::Normal, state, Arc::new(step))
+    }
+
+    // Queue the next step of a continuation job.
+    fn continue_with<S, F>(&self, lane: Lane, state: S, step: Arc<F>)
+    where
+        S: Send + 'scope,
+        F: Fn(S) -> Continuation<S> + Send + Sync + 'scope,
+    {
+        // Steps aren't nested, so the clone keeps our depth.
+        let this = unsafe { self.clone() };
+
+        self.submit(0, None, lane, move || {
+            if let Continuation::Continue(next) = step(state) {
+                this.continue_with(Lane::Back, next, step);
+            }
+        })
+    }
+
+    /// Add a job to this scope which runs only after every other job.
+    ///
+    /// The job is held back until the scope is joined, with `join` as
+    /// `scoped` and `zoom` always do, even when unwinding from a panicking
+    /// scheduler, or with `join_timeout` and the other timed joins. It's then queued once all other jobs on the scope have
+    /// finished, whether or not any of them panicked, like a `finally` block
+    /// for the scope's parallel work. Several such jobs may run in parallel
+    /// with each other.
+    ///
+    /// Scopes which are never joined, such as a `Scope::forever` which is
+    /// only ever checkpointed, never run the job. On a subscope from
+    /// `zoom_deferred` it is handed to the parent scope instead.
+    pub fn execute_finally<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let job = unsafe {
+            // Safe because the job is only run by `join`, which happens
+            // before the resolution of `'scope`.
+            mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, FinallyJob>(Box::new(job))
+        };
+
+        self.wait.finally.lock().unwrap().push(job);
+    }
+
+    /// Add a future to this scope, driven to completion on a worker.
+    ///
+    /// The worker blocks on the future, parking whenever it is pending until
+    /// its waker is called, so this is a convenience for occasional async work
+    /// rather than an async runtime: a worker is tied up for as long as the
+    /// future runs. The output is discarded, and panics propogate like those
+    /// of any other job.
+    ///
+    /// Only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn execute_async<F>(&self, future: F)
+    where
+        F: Future + Send + 'scope,
+    {
+        self.execute(move || {
+            block_on(future);
+        })
+    }
+
+    /// A future which resolves once no jobs on this scope are pending.
+    ///
+    /// This lets async code wait for a batch without blocking a thread.
+    /// Unlike `join`, it doesn't wait for deferred subscopes or propogate
+    /// panics, so the scope should still be joined, which by then returns
+    /// straight away. Dropping the future has no effect on the scope.
+    ///
+    /// Only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn completion(&self) -> Completion {
+        Completion {
+            wait: self.wait.clone(),
+        }
+    }
+
+    /// A stream of how many of this scope's jobs have finished, for showing
+    /// progress.
+    ///
+    /// Each item is the count so far, including jobs which panicked. Counts
+    /// which change faster than the stream is polled are skipped, but the
+    /// final count is always yielded. The stream ends the first time it
+    /// finds no jobs pending with the count already yielded.
+    ///
+    /// Only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn progress(&self) -> Progress {
+        Progress {
+            wait: self.wait.clone(),
+            seen: None,
+        }
+    }
+
+    /// Add a job to this scope which takes `input` and produces an output,
+    /// returned through the handle.
+    ///
+    /// The job counts towards `join` like any other. If it panics, the scope
+    /// is poisoned as usual and `JobHandle::join` panics too.
+    pub fn execute_owned<T, U, F>(&self, input: T, f: F) -> JobHandle<U>
+    where
+        T: Send + 'scope,
+        U: Send + 'scope,
+        F: FnOnce(T) -> U + Send + 'scope,
+    {
+        let slot = Arc::new(JobSlot {
+            state: Mutex::new(JobState::Pending),
+            ready: Condvar::new(),
+        });
+
+        let output = slot.clone();
+        self.execute(move || {
+            // Release the handle even if the job panics.
+            let output = scopeguard::guard(output, |slot| slot.finish(JobState::Panicked));
+            let value = f(input);
+            scopeguard::ScopeGuard::into_inner(output).finish(JobState::Done(value));
+        });
+
+        JobHandle { slot }
+    }
+
+    /// Add a job to this scope which runs on another pool's workers.
+    ///
+    /// The job is still tracked by this scope, so `join` waits for it and
+    /// panics in it propogate to this scope's joiner, but it is queued on and
+    /// run by `pool`. This lets, say, blocking I/O in a computation go to a
+    /// separate I/O pool without leaving the scope.
+    pub fn execute_on_pool<F>(&self, pool: &Pool, job: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        // A temporary scope for the other pool, sharing our job set.
+        Scope {
+            pool: pool.clone(),
+            wait: self.wait.clone(),
+            depth: self.depth,
+            id: self.id,
+            parent: self.parent,
+            _scope: Id::<'scope>::default(),
+        }
+        .execute(job)
+    }
+
+    /// Add a job to this scope which is retried if it panics.
+    ///
+    /// The job runs up to `attempts` times, and at least once, on the same
+    /// worker, stopping as soon as an attempt returns without panicking. Only
+    /// if every attempt panics does the last panic propogate, as it would
+    /// from `execute`. Either way the job counts as a single job for `join`.
+    ///
+    /// Side effects of failed attempts are not rolled back, so the job should
+    /// be safe to run again after panicking part way through.
+    pub fn execute_retry<F>(&self, attempts: usize, job: F)
+    where
+        F: Fn() + Send + Sync + 'scope,
+    {
+        self.execute(move || {
+            for _ in 1..attempts {
+                if panic::catch_unwind(AssertUnwindSafe(&job)).is_ok() {
+                    return;
+                }
+            }
+
+            // The final attempt panics through to the scope.
+            job()
+        })
+    }
+
+    /// Run `n` copies of `job` in parallel, returning once all of them are
+    /// done.
+    ///
+    /// This suits stress tests and benchmarks, and redundant runs of
+    /// idempotent work. The copies share `job` rather than cloning it. A
+    /// panic in any copy propogates from here, once the others finish.
+    pub fn execute_replicated<F>(&self, n: usize, job: F)
+    where
+        F: Fn() + Sync,
+    {
+        let job = &job;
+
+        self.zoom(|scope| {
+            for _ in 0..n {
+                scope.execute(job);
+            }
+        });
+    }
+
+    /// Add a job to this scope, tagged with a value for the pool's task
+    /// selector.
+    ///
+    /// The tag is opaque to the pool; it is only visible to a selector
+    /// installed with `PoolBuilder::task_selector`, through `PeekedTask::tag`.
+    /// Otherwise this is identical to `execute`.
+    pub fn execute_tagged<F>(&self, tag: u64, job: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        self.submit(tag, None, Lane::Normal, job)
+    }
+
+    /// Add a job to this scope which is expected to stop once `timeout` has
+    /// passed since it started.
+    ///
+    /// The job is handed a flag which the pool's watchdog thread sets when
+    /// the timeout passes, and should poll it and return early once it's set.
+    /// Running jobs can't be stopped from outside, so a job which doesn't
+    /// check the flag just runs to completion. Either way, a job which was
+    /// still running at its timeout is reported by `timed_out` once it
+    /// finishes.
+    ///
+    /// Returns the job's number among this scope's watchdog jobs, counting
+    /// from zero, as used by `timed_out`.
+    pub fn execute_watchdog<F>(&self, timeout: Duration, job: F) -> usize
+    where
+        F: FnOnce(&AtomicBool) + Send + 'scope,
+    {
+        let id = self.wait.watchdog_jobs.fetch_add(1, Ordering::SeqCst);
+        let pool = self.pool.clone();
+        let wait = self.wait.clone();
+
+        self.execute(move || {
+            let expired = Arc::new(AtomicBool::new(false));
+            pool.arm_watchdog(timeout, &expired);
+
+            defer!({
+                pool.disarm_watchdog(&expired);
+                if expired.load(Ordering::SeqCst) {
+                    wait.timed_out.lock().unwrap().push(id);
+                }
+            });
+
+            job(&expired)
+        });
+
+        id
+    }
+
+    /// The numbers of the `execute_watchdog` jobs which ran past their
+    /// timeouts, in ascending order.
+    ///
+    /// Jobs are only reported once they finish, so `join` the scope first
+    /// to hear about all of them.
+    pub fn timed_out(&self) -> Vec<usize> {
+        let mut timed_out = self.wait.timed_out.lock().unwrap().clone();
+        timed_out.sort_unstable();
+        timed_out
+    }
+
+    /// Add a job to this scope, numbered for telling jobs apart in logs.
+    ///
+    /// Jobs added this way are numbered from zero in the order they are
+    /// submitted to the scope, including through `recurse`d clones of it.
+    /// The job is handed its own id, and the same id is returned, so both
+    /// sides can log it. Ids are purely informational. Jobs added in other
+    /// ways aren't numbered, and cost nothing extra.
+    pub fn execute_with_id<F>(&self, job: F) -> TaskId
+    where
+        F: FnOnce(TaskId) + Send + 'scope,
+    {
+        let id = TaskId(self.wait.next_task_id.fetch_add(1, Ordering::SeqCst));
+        self.execute(move || job(id));
+        id
+    }
+
+    /// Add a job to this scope, labeled for `Pool::current_jobs`.
+    ///
+    /// While the job runs, its worker reports the label, which helps to
+    /// find out what a stuck pool is doing. Otherwise this is identical to
+    /// `execute`.
+    pub fn execute_labeled<F>(&self, label: &'static str, job: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        self.submit(0, Some(label), Lane::Normal, job)
+    }
+
+    // Queue a job in the given lane.
+    fn submit<F>(&self, tag: u64, label: Option<&'static str>, lane: Lane, job: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let bytes = mem::size_of::<F>();
+        self.admit(bytes);
+
+        // Submit the job *before* submitting it to the queue.
+        self.wait.submit();
+        self.enqueue(bytes, tag, label, lane, job)
+    }
+
+    // Check that the pool can take a job of `bytes` bytes, waiting for room.
+    fn admit(&self, bytes: usize) {
+        // Scopes opened before a shutdown can still queue jobs for the
+        // draining workers, but once they're gone nothing would run them.
+        if self.pool.is_dead() {
+            panic!("Pool is dead!")
+        }
+
+        if self.pool.is_shutdown() && self.pool.workers() == 0 {
+            panic!("Pool is shut down!")
+        }
+
+        // Wait for room in the queue, if its memory is limited.
+        if let Some(ref budget) = self.pool.inner.memory_budget {
+            budget.acquire(bytes);
+        }
+    }
+
+    // Queue a job which has been admitted and submitted to our WaitGroup.
+    fn enqueue<F>(&self, bytes: usize, tag: u64, label: Option<&'static str>, lane: Lane, job: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        self.pool.inner.in_flight.fetch_add(1, Ordering::SeqCst);
+        self.pool.inner.queued.fetch_add(1, Ordering::SeqCst);
+
+        // Carry the submitter's context across to the worker.
+        let task: Box<dyn Task + Send + 'scope> = match self.pool.inner.task_context {
+            Some(ref capture) => {
+                let enter = capture();
+                Box::new(move || {
+                    let mut job = Some(job);
+                    enter(&mut || (job.take().unwrap())())
+                })
+            }
+            // Boxing a capture-free closure doesn't allocate.
+            None => Box::new(job),
+        };
+
+        let task = unsafe {
+            // Safe because we will ensure the task finishes executing before
+            // 'scope via joining before the resolution of `'scope`.
+            mem::transmute::<Box<dyn Task + Send + 'scope>, Box<dyn Task + Send + 'static>>(task)
+        };
+
+        // Submit the task to be executed.
+        let message = PoolMessage::Task(Job {
+            task,
+            wait: self.wait.clone(),
+            bytes,
+            tag,
+            label,
+            epoch: self.wait.enter_epoch(),
+            #[cfg(feature = "trace")]
+            seq: self.pool.inner.submitted.fetch_add(1, Ordering::SeqCst),
+        });
+
+        match lane {
+            Lane::Normal => self.pool.inner.queue.push(message),
+            Lane::Urgent => self.pool.inner.queue.push_urgent(message),
+            Lane::Front => self.pool.inner.queue.push_front(message),
+            Lane::Back => self.pool.inner.queue.push_global(message),
+        }
+    }
+
+    /// Share `state` with jobs submitted through the returned handle.
+    ///
+    /// Every job is handed a plain reference, which `'scope` guarantees
+    /// outlives it, so unlike cloning an `Arc` into each job there is no
+    /// reference counting per job.
+    #[inline]
+    pub fn with_shared<'a, S: Sync>(&'a self, state: &'scope S) -> Shared<'a, 'scope, S> {
+        Shared { scope: self, state }
+    }
+
+    /// Submit jobs which each hold a permit from `semaphore` while running.
+    ///
+    /// Sharing the semaphore between scopes, including those of other
+    /// pools, bounds how many of all their jobs run at once, such as to
+    /// share out a scarce resource. A job waiting for a permit blocks the
+    /// worker which took it, so the pool runs fewer jobs meanwhile.
+    #[inline]
+    pub fn limited<'a>(&'a self, semaphore: &Arc<Semaphore>) -> Limited<'a, 'scope> {
+        Limited {
+            scope: self,
+            semaphore: semaphore.clone(),
+        }
+    }
+
+    /// Create a collector for the errors of jobs submitted with
+    /// `execute_collecting`.
+    #[inline]
+    pub fn collect_errors<E: Send>(&self) -> ErrorCollector<E> {
+        ErrorCollector {
+            errors: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// Add a fallible job to this scope, recording any error it returns in
+    /// `errors`.
+    ///
+    /// Unlike a panic, an error does not poison the scope; every error is
+    /// kept, and can be retrieved with `ErrorCollector::into_vec` once the
+    /// scope is joined.
+    pub fn execute_collecting<E, F>(&self, errors: &ErrorCollector<E>, job: F)
+    where
+        E: Send + 'scope,
+        F: FnOnce() -> Result<(), E> + Send + 'scope,
+    {
+        let errors = errors.errors.clone();
+        self.execute(move || {
+            if let Err(err) = job() {
+                errors.get_or_init(Mutex::default).lock().unwrap().push(err);
+            }
+        })
+    }
+
+    /// Map `f` over `items` in parallel, yielding results as they complete,
+    /// with at most `max_buffered` results running or waiting to be taken.
+    ///
+    /// Jobs are scheduled as the returned iterator is consumed, so a slow
+    /// consumer holds back the producers and memory stays bounded however
+    /// many items there are. Results come in the order the jobs finish, not
+    /// the order of `items`. Dropping the iterator early stops scheduling;
+    /// jobs already started still run, and their results are dropped.
+    ///
+    /// A panic in `f` loses that item's result and propogates through the
+    /// scope's `join` as usual. Panics if `max_buffered` is zero.
+    pub fn map_bounded<'a, I, T, U, F>(
+        &'a self,
+        items: I,
+        max_buffered: usize,
+        f: F,
+    ) -> BoundedMap<'a, 'scope, T, U>
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: 'a,
+        T: Send + 'scope,
+        U: Send + 'scope,
+        F: Fn(T) -> U + Send + Sync + 'scope,
+    {
+        assert!(max_buffered > 0, "map_bounded needs room for a result");
+
+        BoundedMap {
+            scope: self,
+            items: Box::new(items.into_iter()),
+            f: Arc::new(f),
+            buffer: Arc::new(MapBuffer {
+                state: Mutex::new(MapState {
+                    results: VecDeque::new(),
+                    running: 0,
+                    closed: false,
+                }),
+                ready: Condvar::new(),
+            }),
+            max_buffered,
+            exhausted: false,
+        }
+    }
+
+    /// Map `f` over `items` in parallel as `map_bounded` does, appending
+    /// the results to `out` as they complete.
+    ///
+    /// At most `max_buffered` jobs are running or have results waiting to
+    /// be moved to `out` at any time, and this returns once every item is
+    /// done. Results are appended in the order the jobs finish, after
+    /// anything already in `out`. To take results while the rest are still
+    /// running, use `map_bounded` directly. Panics if `max_buffered` is zero.
+    pub fn map_into<I, T, U, F>(&self, items: I, out: &mut VecDeque<U>, max_buffered: usize, f: F)
+    where
+        I: IntoIterator<Item = T>,
+        T: Send + 'scope,
+        U: Send + 'scope,
+        F: Fn(T) -> U + Send + Sync + 'scope,
+    {
+        out.extend(self.map_bounded(items, max_buffered, f));
+    }
+
+    /// Map `f` over `items` in parallel, with about one job per worker.
+    ///
+    /// Cheap uniform work gains nothing from a job per item, so the items
+    /// are split into `auto_split(items.len(), workers)` balanced chunks,
+    /// each mapped by one job. The results keep the order of `items`, and
+    /// are returned once every chunk is done. Use `map_split` to choose the
+    /// number of jobs.
+    pub fn map_auto<T, U, F>(&self, items: &[T], f: F) -> Vec<U>
+    where
+        T: Sync,
+        U: Send,
+        F: Fn(&T) -> U + Sync,
+    {
+        self.map_split(items, auto_split(items.len(), self.pool.workers()), f)
+    }
+
+    /// Map `f` over `items` in parallel, as `jobs` jobs mapping balanced
+    /// chunks of the items.
+    ///
+    /// Chunk lengths differ by at most one. The results keep the order of
+    /// `items`, and are returned once every chunk is done; a panic in `f`
+    /// propogates from here. Panics if `jobs` is zero.
+    pub fn map_split<T, U, F>(&self, items: &[T], jobs: usize, f: F) -> Vec<U>
+    where
+        T: Sync,
+        U: Send,
+        F: Fn(&T) -> U + Sync,
+    {
+        assert!(jobs > 0, "map_split needs at least one job");
+
+        let jobs = jobs.min(items.len()).max(1);
+        let mut parts: Vec<Vec<U>> = (0..jobs).map(|_| Vec::new()).collect();
+        let f = &f;
+
+        self.zoom(|scope| {
+            // The first `len % jobs` chunks take one extra item each.
+            let (base, extra) = (items.len() / jobs, items.len() % jobs);
+            let mut rest = items;
+
+            for (i, part) in parts.iter_mut().enumerate() {
+                let (chunk, tail) = rest.split_at(base + (i < extra) as usize);
+                rest = tail;
+                scope.execute(move || *part = chunk.iter().map(f).collect());
+            }
+        });
+
+        parts.into_iter().flatten().collect()
+    }
+
+    /// Add a job to this scope which itself will get access to the scope.
+    ///
+    /// Like with `execute`, subsequent calls to `join` will wait for this
+    /// job (and all jobs scheduled on the scope it receives) to complete.
+    pub fn recurse<F>(&self, job: F)
+    where
+        F: FnOnce(&Self) + Send + 'scope,
+    {
+        // Create another scope with the *same* lifetime.
+        let mut this = unsafe { self.clone() };
+        this.depth = self.nested_depth();
+        this.id = next_scope_id();
+        this.parent = Some(self.id);
+
+        let order = RecursionOrder::from_code(self.wait.recursion_order.load(Ordering::Relaxed))
+            .or(self.pool.inner.recursion_order);
+        let lane = match order {
+            None => Lane::Normal,
+            Some(RecursionOrder::DepthFirst) => Lane::Front,
+            Some(RecursionOrder::BreadthFirst) => Lane::Back,
+        };
+
+        self.submit(0, None, lane, move || job(&this));
+    }
+
+    /// Set where this scope queues jobs added with `recurse`, overriding
+    /// `PoolBuilder::recursion_order`.
+    ///
+    /// The setting is shared with the scopes `recurse` hands out, but not
+    /// with subscopes from `zoom`. Returns the scope, for chaining.
+    pub fn with_recursion_order(&self, order: RecursionOrder) -> &Self {
+        self.wait
+            .recursion_order
+            .store(RecursionOrder::code(Some(order)), Ordering::Relaxed);
+        self
+    }
+
+    /// Set whether a panicking job cancels this scope's jobs which haven't
+    /// started, overriding `PoolBuilder::cancel_on_panic`.
+    ///
+    /// A panic still propogates from `join` as usual, but since the batch is
+    /// doomed anyway, the queued jobs are discarded as if by
+    /// `AbortHandle::abort` rather than run, so it propogates sooner. Jobs
+    /// already running carry on. The setting is shared with the scopes
+    /// `recurse` hands out, but not with subscopes from `zoom`. Returns the
+    /// scope, for chaining.
+    pub fn cancel_on_panic(&self, cancel: bool) -> &Self {
+        self.wait.cancel_on_panic.store(cancel, Ordering::SeqCst);
+        self
+    }
+
+    /// Add a job which runs `first`, then passes its output to `rest` along
+    /// with the scope, to schedule a parallel phase on it.
+    ///
+    /// Like with `recurse`, subsequent calls to `join` wait for both phases
+    /// and everything `rest` schedules.
+    pub fn then<T, F, G>(&self, first: F, rest: G)
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        G: FnOnce(&Self, T) + Send + 'scope,
+    {
+        self.recurse(move |scope| rest(scope, first()));
+    }
+
+    /// Create a new subscope, bound to a lifetime smaller than our existing Scope.
+    ///
+    /// The subscope has a different job set, and is joined before zoom returns.
+    pub fn zoom<'smaller, F, R>(&self, scheduler: F) -> R
+    where
+        F: FnOnce(&Scope<'smaller>) -> R,
+        'scope: 'smaller,
+    {
+        let scope = unsafe { self.refine() };
+
+        // Join the scope either on completion of the scheduler or panic.
+        defer!(scope.join());
+
+        // Schedule all tasks then join all tasks
+        scheduler(&scope)
+    }
+
+    /// Run `f` on every item of `iter` in parallel, pulling items from the
+    /// iterator as workers become free.
+    ///
+    /// Unlike `map_auto`, this works for sources which can't be indexed.
+    /// The iterator is shared behind a lock, one item per lock; use
+    /// `par_iter_batched` where taking the lock costs more than the items
+    /// do. Returns once every item has been processed.
+    pub fn par_iter<I, F>(&self, iter: I, f: F)
+    where
+        I: Iterator + Send,
+        I::Item: Send,
+        F: Fn(I::Item) + Sync,
+    {
+        self.par_iter_batched(iter, 1, f)
+    }
+
+    /// Like `par_iter`, but takes up to `batch` items per lock.
+    ///
+    /// One job per worker takes batches until the iterator is exhausted. If
+    /// `f` panics the other jobs stop taking items, though they finish the
+    /// batches they already have, and the panic propogates from here, as
+    /// does one from the iterator itself. Panics if `batch` is zero.
+    pub fn par_iter_batched<I, F>(&self, iter: I, batch: usize, f: F)
+    where
+        I: Iterator + Send,
+        I::Item: Send,
+        F: Fn(I::Item) + Sync,
+    {
+        assert!(batch > 0, "par_iter_batched needs a batch of at least one");
+
+        let iter = Mutex::new(iter.fuse());
+        let stopped = AtomicBool::new(false);
+
+        self.zoom(|scope| {
+            for _ in 0..self.pool.workers().max(1) {
+                let (iter, stopped, f) = (&iter, &stopped, &f);
+                scope.execute(move || {
+                    // Make the other jobs stop if `f` panics.
+                    let guard = scopeguard::guard((), |_| stopped.store(true, Ordering::SeqCst));
+                    let mut items = Vec::with_capacity(batch);
+
+                    while !stopped.load(Ordering::SeqCst) {
+                        {
+                            // A poisoned lock means the iterator panicked.
+                            let mut iter = match iter.lock() {
+                                Ok(iter) => iter,
+                                Err(_) => break,
+                            };
+                            items.extend(iter.by_ref().take(batch));
+                        }
+
+                        if items.is_empty() {
+                            break;
+                        }
+
+                        for item in items.drain(..) {
+                            f(item);
+                        }
+                    }
+
+                    scopeguard::ScopeGuard::into_inner(guard);
+                });
+            }
+        });
+    }
+
+    /// Run `f` on every item in parallel, stopping at the first error.
+    ///
+    /// Each item becomes a job on a new subscope, which is joined before
+    /// `try_for_each` returns. Once a job returns `Err`, no further items are
+    /// scheduled and queued jobs which have not yet started are skipped; jobs
+    /// which are already running are not interrupted.
+    ///
+    /// If several jobs fail, the error recorded first is returned and the
+    /// others are dropped.
+    pub fn try_for_each<I, T, E, F>(&self, items: I, f: F) -> Result<(), E>
+    where
+        I: IntoIterator<Item = T>,
+        T: Send,
+        E: Send,
+        F: Fn(&T) -> Result<(), E> + Send + Sync,
+    {
+        let failed = AtomicBool::new(false);
+        let error = Mutex::new(None);
+
+        self.zoom(|scope| {
+            for item in items {
+                // Stop scheduling once any job has failed.
+                if failed.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let (f, failed, error) = (&f, &failed, &error);
+                scope.execute(move || {
+                    // Skip jobs which start after a failure.
+                    if failed.load(Ordering::SeqCst) {
+                        return;
+                    }
+
+                    if let Err(err) = f(&item) {
+                        // The first error recorded wins.
+                        let mut error = error.lock().unwrap();
+                        if error.is_none() {
+                            *error = Some(err);
+                        }
+
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+
+        match error.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Create a new subscope whose join is deferred to the returned guard.
+    ///
+    /// Unlike `zoom`, this returns as soon as `scheduler` has run, leaving
+    /// the subscope's jobs running so later stages can be scheduled before
+    /// earlier ones finish. Join them, along with any other deferred
+    /// subscopes merged into the guard, with `DeferredJoin::join_all`.
+    ///
+    /// Since the subscope may outlive this call, its jobs can only borrow
+    /// data which outlives `'scope`. This scope's own `join` always waits for
+    /// its deferred subscopes as well, so no job outlives `'scope` even if
+    /// the guard is leaked.
+    pub fn zoom_deferred<F>(&self, scheduler: F) -> DeferredJoin
+    where
+        F: FnOnce(&Scope<'scope>),
+    {
+        let scope = Scope {
+            pool: self.pool.clone(),
+            wait: self.pool.inner.child_wait_group(&self.wait),
+            depth: self.nested_depth(),
+            id: next_scope_id(),
+            parent: Some(self.id),
+            _scope: Id::default(),
+        };
+
+        self.wait.defer(scope.wait.clone());
+        scheduler(&scope);
+
+        // Nothing calls `join` on the subscope itself, so leave its finally
+        // jobs to ours.
+        let finally = mem::take(&mut *scope.wait.finally.lock().unwrap());
+        self.wait.finally.lock().unwrap().extend(finally);
+
+        DeferredJoin {
+            waits: vec![scope.wait.clone()],
+        }
+    }
+
+    /// Store a value in this scope, to be found by type with `get`.
+    ///
+    /// A scope holds at most one value of each type; if it already has one,
+    /// `value` is handed back. The value is shared with jobs and `recurse`d
+    /// scopes, which see this same storage, and with subscopes created by
+    /// `zoom`, which see it as a fallback for types they don't hold
+    /// themselves. Stored values are kept until the scope's jobs are all
+    /// gone, and are never replaced.
+    pub fn insert<T: Any + Send + Sync>(&self, value: T) -> Result<(), T> {
+        self.wait.locals.insert(value)
+    }
+
+    /// Get the value of type `T` stored in this scope, or in the scope it
+    /// was zoomed from.
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.wait.locals.get()
+    }
+
+    /// Fill `out` in parallel, writing `f(i)` to `out[i]` for every index.
+    ///
+    /// The slice is split into contiguous chunks, each filled by one job on a
+    /// new subscope, which is joined before `execute_indexed` returns. Panics
+    /// in `f` propogate as they would from `zoom`.
+    pub fn execute_indexed<T, F>(&self, out: &mut [T], f: F)
+    where
+        T: Send,
+        F: Fn(usize) -> T + Send + Sync,
+    {
+        if out.is_empty() {
+            return;
+        }
+
+        // A few chunks per worker leaves room to balance uneven jobs.
+        let chunks = self.pool.workers().max(1) * 4;
+        let chunk_len = out.len().div_ceil(chunks);
+
+        self.zoom(|scope| {
+            for (n, chunk) in out.chunks_mut(chunk_len).enumerate() {
+                let f = &f;
+                scope.execute(move || {
+                    let start = n * chunk_len;
+                    for (i, slot) in chunk.iter_mut().enumerate() {
+                        *slot = f(start + i);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Register a callback to run once all jobs on this Scope have completed.
+    ///
+    /// Callbacks run at join time, not when the last job finishes: each runs
+    /// exactly once, on the thread of the first `join` to see the scope
+    /// drained, before that `join` returns. Scopes created by `zoom` or
+    /// `scoped` are always joined before they end, so their callbacks always
+    /// run; those on a `'static` scope run only if it's joined. A callback
+    /// receives `true` if every job completed cleanly, and `false` if any job
+    /// panicked, in which case it runs before the panic is propogated.
+    ///
+    /// Any number of callbacks can be registered, and they run in the order
+    /// they were registered. A panicking callback doesn't stop the others,
+    /// and its panic propogates from the join once they're done.
+    pub fn on_complete<F>(&self, callback: F)
+    where
+        F: FnOnce(bool) + Send + 'scope,
+    {
+        let callback = unsafe {
+            // Safe because the callback is only run by `join`, which happens
+            // before the resolution of `'scope`.
+            mem::transmute::<Box<dyn FnOnce(bool) + Send + 'scope>, CompletionCallback>(Box::new(
+                callback,
+            ))
+        };
+
+        self.wait.on_complete.lock().unwrap().push(callback);
+    }
+
+    /// Register a cleanup to run once all jobs on this Scope have completed,
+    /// cleanly or not.
+    ///
+    /// Any number of cleanups can be registered, and the first `join` to
+    /// see the scope drained runs them all, newest first, after the
+    /// `on_complete` callbacks and before a panic is propogated. Scopes
+    /// created by `zoom` or `scoped` are always joined, so their cleanups
+    /// always run; those on a `'static` scope run only if it's joined. A
+    /// panicking cleanup doesn't stop the others, and its panic propogates
+    /// from the join once they're done.
+    pub fn defer<F>(&self, cleanup: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let cleanup = unsafe {
+            // Safe because cleanups are only run by `join`, which happens
+            // before the resolution of `'scope`.
+            mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Cleanup>(Box::new(cleanup))
+        };
+
+        self.wait.cleanups.lock().unwrap().push(cleanup);
+    }
+
+    /// Make a poisoned Scope usable again once it is idle.
+    ///
+    /// After a job panics, every `join` on the scope panics too. Resetting
+    /// clears the poison so a long-lived scope, such as one created with
+    /// `forever`, can run a fresh batch of jobs after recovering.
+    ///
+    /// Fails without changing anything if jobs are still pending.
+    #[inline]
+    pub fn reset(&self) -> Result<(), ResetError> {
+        self.wait.reset()
+    }
+
+    /// Remove this scope's jobs which have not started yet from the queue,
+    /// returning how many there were.
+    ///
+    /// The removed jobs never run, and count as completed, so a later `join`
+    /// returns once the jobs which are already running finish. Jobs of other
+    /// scopes, including subscopes of this one, are left alone, as are jobs
+    /// sent to another pool with `execute_on_pool`.
+    ///
+    /// Other jobs are taken out of the queue and put back while this runs,
+    /// so idle workers may briefly find nothing to run, though
+    /// `queued_jobs` still counts them. Those taken from a worker's own
+    /// deque lose their place, and run oldest first from the shared queue.
+    pub fn cancel_pending(&self) -> usize {
+        self.pool.inner.cancel(&self.wait)
+    }
+
+    /// Move this scope's jobs which have not started yet ahead of all other
+    /// queued work, returning how many there were.
+    ///
+    /// The jobs go to the urgent lane, as if spawned with `spawn_urgent`, in
+    /// the order they were queued. This lets a latency sensitive joiner
+    /// avoid waiting behind a backlog of less important work, as though its
+    /// jobs inherited its priority. Jobs queued afterwards, and those of
+    /// subscopes, queue normally. Other scopes' jobs are moved meanwhile as
+    /// with `cancel_pending`.
+    pub fn boost(&self) -> usize {
+        self.pool.inner.boost(&self.wait)
+    }
+
+    /// `boost` this scope's jobs, then `join` them.
+    pub fn join_boosted(&self) {
+        self.boost();
+        self.join()
+    }
+
+    /// A handle for stopping this scope's batch of jobs, from any thread.
+    ///
+    /// The handle doesn't borrow the scope, so it can be moved into jobs,
+    /// letting, say, a search stop the batch once any job finds an answer.
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle {
+            pool: self.pool.clone(),
+            wait: self.wait.clone(),
+        }
+    }
+
+    /// A token for cancelling this scope and the subscopes nested in it.
+    ///
+    /// Cancelling a subscope's token leaves this scope running. Like an
+    /// `AbortHandle`, the token doesn't borrow the scope.
+    pub fn cancel_token(&self) -> CancelToken {
+        CancelToken {
+            locals: self.wait.locals.clone(),
+        }
+    }
+
+    /// Combine this scope with `other`, for a single join over both.
+    ///
+    /// This is for waiting on two scopes owned by different parts of a
+    /// program at one barrier. The scopes may have different lifetimes, and
+    /// the merge doesn't change how either is joined otherwise; in
+    /// particular, `execute_finally` jobs are still only released by each
+    /// scope's own `join`.
+    pub fn merge(&self, other: &Scope<'_>) -> MergedJoin {
+        MergedJoin {
+            waits: [self.wait.clone(), other.wait.clone()],
+        }
+    }
+
+    /// The pool this scope's jobs run on.
+    ///
+    /// The pool can be used to inspect workers or to `spawn` work directly,
+    /// which, being `'static`, doesn't interact with this scope's lifetime.
+    #[inline]
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    /// The number of jobs this scope is waiting on, along with the same for
+    /// each of its live subscopes, and theirs.
+    ///
+    /// Jobs added with `recurse` belong to this scope, not a subscope. Each
+    /// count is read separately, so a busy tree may never have been exactly
+    /// in the state reported.
+    ///
+    /// Only available with the `scope-tree` feature. Tracking subscopes
+    /// keeps the pool from reusing their WaitGroups.
+    #[cfg(feature = "scope-tree")]
+    pub fn pending_tree(&self) -> PendingNode {
+        PendingNode::of(&self.wait)
+    }
+
+    /// Whether this scope's jobs run on `pool`, or a clone of it.
+    #[inline]
+    pub fn belongs_to(&self, pool: &Pool) -> bool {
+        Arc::ptr_eq(&self.pool.inner, &pool.inner)
+    }
+
+    /// Whether every job submitted on this Scope has finished.
+    ///
+    /// Unlike `join` this never blocks, so it can be polled between other
+    /// work. Jobs `execute`d concurrently with the call may or may not be
+    /// accounted for, so the answer can be stale as soon as it is returned.
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.wait.waiting() == 0
+    }
+
+    /// Awaits all jobs submitted on this Scope to be completed.
+    ///
+    /// Only guaranteed to join jobs which where `execute`d logically
+    /// prior to `join`. Jobs `execute`d concurrently with `join` may
+    /// or may not be completed before `join` returns.
+    #[inline]
+    pub fn join(&self) {
+        self.wait.release_finally(None, &|job| self.execute(job));
+        self.wait.join()
+    }
+
+    // Like `join`, but gives up waiting at `deadline`, returning false.
+    fn join_deadline(&self, deadline: Instant) -> bool {
+        self.wait
+            .release_finally(Some(deadline), &|job| self.execute(job))
+            && self.wait.join_deadline(deadline)
+    }
+
+    /// Wait for exactly the jobs queued on this Scope before the call, while
+    /// leaving the scope open for more.
+    ///
+    /// Where `join` waits for the scope to have no pending jobs at all, and
+    /// so may or may not wait for jobs `execute`d concurrently, this splits
+    /// jobs at the moment it is called. Every job queued before then has
+    /// finished running, or been discarded, by the time it returns, and no
+    /// job queued afterwards is waited for, including jobs queued by the
+    /// jobs being waited for. This makes it a checkpoint for long-lived
+    /// scopes like `Scope::forever`, which are never joined.
+    ///
+    /// Unlike `join`, this doesn't wait for subscopes, release jobs held by
+    /// `execute_finally`, run completion callbacks or propogate panics.
+    /// Checkpoints take turns, so one called while another waits also waits
+    /// for jobs queued in the meantime.
+    pub fn checkpoint(&self) {
+        self.wait.checkpoint()
+    }
+
+    /// Like `join`, but gives up waiting after `timeout`.
+    ///
+    /// Returns `true` if every job was completed in time, or `false` if
+    /// some are still pending. Timing out leaves the pending jobs running,
+    /// and a later `join` still waits for them. Jobs held back by
+    /// `execute_finally` are released as `join` would, and completion
+    /// callbacks only run once they have finished too.
+    #[inline]
+    pub fn join_timeout(&self, timeout: Duration) -> bool {
+        self.join_deadline(Instant::now() + timeout)
+    }
+
+    /// Like `join`, but returns early once `flag` is set, from any thread.
+    ///
+    /// Returns `true` if every job completed, or `false` if interrupted. The
+    /// flag is checked every millisecond. Interrupting leaves the pending
+    /// jobs running, and a later `join` still waits for them. Jobs held
+    /// back by `execute_finally` are released as with `join_timeout`.
+    pub fn join_interruptible(&self, flag: &AtomicBool) -> bool {
+        // There's nothing to notify us when the flag is set, so poll it.
+        while !flag.load(Ordering::SeqCst) {
+            if self.join_timeout(Duration::from_millis(1)) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// This scope's id, unique among all scopes in the process.
+    ///
+    /// Use it to attribute jobs, logs and metrics to the scope which
+    /// scheduled them.
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The id of the scope this one was created in by `zoom` or `recurse`,
+    /// or `None` for a scope created directly on a pool.
+    #[inline]
+    pub fn parent_id(&self) -> Option<u64> {
+        self.parent
+    }
+
+    #[inline]
+    unsafe fn clone(&self) -> Self {
+        Scope {
+            pool: self.pool.clone(),
+            wait: self.wait.clone(),
+            depth: self.depth,
+            id: self.id,
+            parent: self.parent,
+            _scope: Id::default(),
+        }
+    }
+
+    // The depth of a scope nested in this one, checked against the pool's
+    // maximum.
+    fn nested_depth(&self) -> usize {
+        let depth = self.depth + 1;
+
+        if let Some(max) = self.pool.inner.max_scope_depth {
+            if depth > max {
+                panic!(
+                    "Scope nested {} deep, past the pool's max_scope_depth of {}; \
+                     is a zoom or recurse unbounded?",
+                    depth, max
+                );
+            }
+        }
+
+        depth
+    }
+
+    // Create a new scope with a smaller lifetime on the same pool.
+    #[inline]
+    unsafe fn refine<'other>(&self) -> Scope<'other>
+    where
+        'scope: 'other,
+    {
+        Scope {
+            pool: self.pool.clone(),
+            wait: self.pool.inner.child_wait_group(&self.wait),
+            depth: self.nested_depth(),
+            id: next_scope_id(),
+            parent: Some(self.id),
+            _scope: Id::default(),
+        }
+    }
+}
+
+// Where a job is queued.
+#[derive(Clone, Copy)]
+enum Lane {
+    // On the submitting worker's own deque, or the shared queue.
+    Normal,
+    // Ahead of everything queued normally.
+    Urgent,
+    // As close to the front as the queue allows, behind urgent jobs.
+    Front,
+    // At the back of the shared queue, behind other workers' jobs.
+    Back,
+}
+
+enum PoolMessage {
+    Quit,
+    // Stop one worker, leaving the rest running.
+    Retire,
+    Task(Job),
+}
+
+// Uncounts a waiter from a WaitGroup's sleepers once it's done waiting.
+struct Sleeper<'a>(&'a AtomicUsize);
+
+impl Drop for Sleeper<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// A queued job, along with what the pool needs to know about it.
+struct Job {
+    task: Box<dyn Task + Send>,
+    wait: Arc<WaitGroup>,
+    bytes: usize,
+    tag: u64,
+    label: Option<&'static str>,
+    // The `Scope::checkpoint` epoch the job was counted in.
+    epoch: u64,
+    // The job's submission index on its pool, for traces.
+    #[cfg(feature = "trace")]
+    seq: u64,
+}
+
+/// A synchronization primitive for awaiting a set of actions.
+///
+/// Adding new jobs is done with `submit`, jobs are completed with `complete`,
+/// and any thread may wait for all jobs to be `complete`d with `join`.
+pub struct WaitGroup {
+    // Deliberately a single counter rather than per-thread shards: a round
+    // and its generation end when the total reaches zero, which a sharded
+    // count can't observe without summing every shard under a lock, and
+    // `benches/wait_group_contention.rs` shows no contention cost to shard
+    // away.
+    pending: AtomicUsize,
+    poisoned: AtomicBool,
+    lock: Mutex<()>,
+    cond: Condvar,
+    on_complete: Mutex<Vec<CompletionCallback>>,
+    // Closures from `Scope::defer`, run newest first by the next join.
+    cleanups: Mutex<Vec<Cleanup>>,
+    // Outcome counts since creation or the last reset, for the poison panic.
+    completed: AtomicUsize,
+    failed: AtomicUsize,
+    poison_message: Option<Arc<str>>,
+    // Subscopes whose jobs must also be waited for by our joins.
+    deferred: Mutex<Vec<Arc<WaitGroup>>>,
+    locals: Arc<ScopeLocals>,
+    // Rounds of pending work completed.
+    generation: AtomicU64,
+    // Bumped by each `Scope::checkpoint`, which waits for the jobs of the
+    // epoch before. Jobs are counted by the parity of their epoch, and
+    // checkpoints take turns.
+    epoch: AtomicU64,
+    epoch_jobs: [AtomicUsize; 2],
+    checkpoint: Mutex<()>,
+    // Threads which may be waiting on `cond`.
+    sleepers: AtomicUsize,
+    // Tasks awaiting a completion, and whether there are any.
+    #[cfg(feature = "async")]
+    wakers: Mutex<Vec<Waker>>,
+    #[cfg(feature = "async")]
+    has_wakers: AtomicBool,
+    // Jobs held back by `Scope::execute_finally` until the rest settle.
+    finally: Mutex<Vec<FinallyJob>>,
+    // The joiner to poke when a round ends, for `Scope::on_join`, and
+    // whether there is one.
+    joiner: Mutex<Option<Arc<Joiner>>>,
+    watched: AtomicBool,
+    // Where to record how long each job runs, for `Pool::scoped_timed`.
+    timings: Option<Arc<BatchTimings>>,
+    // Set by `AbortHandle::abort`; jobs not yet started are skipped.
+    aborted: AtomicBool,
+    // The id of the next job added with `Scope::execute_with_id`.
+    next_task_id: AtomicU64,
+    // Set by `Scope::with_recursion_order`, encoded by `RecursionOrder::code`.
+    recursion_order: AtomicU8,
+    // Whether a panicking job aborts the rest, from `Scope::cancel_on_panic`.
+    cancel_on_panic: AtomicBool,
+    // Jobs added with `Scope::execute_watchdog`, and those which timed out.
+    watchdog_jobs: AtomicUsize,
+    timed_out: Mutex<Vec<usize>>,
+    // Subscopes' groups, for `Scope::pending_tree`.
+    #[cfg(feature = "scope-tree")]
+    children: Mutex<Vec<Weak<WaitGroup>>>,
+}
+
+// Values stored with `Scope::insert`, at most one of each type, and the
+// scope's `CancelToken` flag. Linked to the enclosing scope's.
+#[derive(Default)]
+struct ScopeLocals {
+    values: Mutex<Vec<Box<dyn Any + Send + Sync>>>,
+    cancelled: AtomicBool,
+    parent: Option<Arc<ScopeLocals>>,
+}
+
+impl ScopeLocals {
+    // Whether this scope or any scope enclosing it has been cancelled.
+    fn is_cancelled(&self) -> bool {
+        let mut locals = self;
+        loop {
+            if locals.cancelled.load(Ordering::SeqCst) {
+                return true;
+            }
+
+            match locals.parent {
+                Some(ref parent) => locals = parent,
+                None => return false,
+            }
+        }
+    }
+
+    fn insert<T: Any + Send + Sync>(&self, value: T) -> Result<(), T> {
+        if self.get::<T>().is_some() {
+            return Err(value);
+        }
+
+        let mut values = self.values.lock().unwrap();
+
+        // Check again under the lock, in case of a racing insert.
+        if values.iter().any(|v| v.is::<T>()) {
+            return Err(value);
+        }
+
+        values.push(Box::new(value));
+        Ok(())
+    }
+
+    fn get<T: Any>(&self) -> Option<&T> {
+        let found = self
+            .values
+            .lock()
+            .unwrap()
+            .iter()
+            .find_map(|v| v.downcast_ref::<T>())
+            .map(|value| value as *const T);
+
+        match found {
+            // Safe because values are boxed, and never removed or replaced
+            // while the ScopeLocals lives.
+            Some(value) => Some(unsafe { &*value }),
+            None => self.parent.as_ref().and_then(|parent| parent.get()),
+        }
+    }
+}
+
+type CompletionCallback = Box<dyn FnOnce(bool) + Send>;
+type Cleanup = Box<dyn FnOnce() + Send>;
+type FinallyJob = Box<dyn FnOnce() + Send>;
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        WaitGroup {
+            pending: AtomicUsize::new(0),
+            poisoned: AtomicBool::new(false),
+            lock: Mutex::new(()),
+            cond: Condvar::new(),
+            on_complete: Mutex::new(Vec::new()),
+            cleanups: Mutex::new(Vec::new()),
+            completed: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            poison_message: None,
+            deferred: Mutex::new(Vec::new()),
+            locals: Arc::default(),
+            generation: AtomicU64::new(0),
+            epoch: AtomicU64::new(0),
+            epoch_jobs: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            checkpoint: Mutex::new(()),
+            sleepers: AtomicUsize::new(0),
+            #[cfg(feature = "async")]
+            wakers: Mutex::new(Vec::new()),
+            #[cfg(feature = "async")]
+            has_wakers: AtomicBool::new(false),
+            finally: Mutex::new(Vec::new()),
+            joiner: Mutex::new(None),
+            watched: AtomicBool::new(false),
+            timings: None,
+            aborted: AtomicBool::new(false),
+            next_task_id: AtomicU64::new(0),
+            recursion_order: AtomicU8::new(0),
+            cancel_on_panic: AtomicBool::new(false),
+            watchdog_jobs: AtomicUsize::new(0),
+            timed_out: Mutex::new(Vec::new()),
+            #[cfg(feature = "scope-tree")]
+            children: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl WaitGroup {
+    /// Create a new empty WaitGroup.
+    #[inline]
+    pub fn new() -> Self {
+        WaitGroup::default()
+    }
+
+    /// How many submitted tasks are waiting for completion.
+    #[inline]
+    pub fn waiting(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Submit to this WaitGroup, causing `join` to wait
+    /// for an additional `complete`.
+    #[inline]
+    pub fn submit(&self) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Complete a previous `submit`.
+    #[inline]
+    pub fn complete(&self) {
+        self.completed.fetch_add(1, Ordering::SeqCst);
+
+        // Mark the current job complete.
+        let old = self.pending.fetch_sub(1, Ordering::SeqCst);
+
+        #[cfg(feature = "async")]
+        self.wake_tasks();
+
+        // If that was the last job, wake joiners.
+        if old == 1 {
+            self.finish_round();
+        }
+    }
+
+    /// Poison the WaitGroup so all `join`ing threads panic.
+    #[inline]
+    pub fn poison(&self) {
+        // Poison the waitgroup.
+        self.poisoned.store(true, Ordering::SeqCst);
+        self.failed.fetch_add(1, Ordering::SeqCst);
+
+        // Mark the current job complete.
+        let old = self.pending.fetch_sub(1, Ordering::SeqCst);
+
+        #[cfg(feature = "async")]
+        self.wake_tasks();
+
+        // If that was the last job, wake joiners.
+        if old == 1 {
+            self.finish_round();
+        }
+    }
+
+    // Called when pending work reaches zero.
+    //
+    // Waiters count themselves in `sleepers` while holding the lock, before
+    // checking whether to wait. If this reads no sleepers, any waiter counts
+    // itself later and so sees the new state when it checks. Otherwise the
+    // lock orders the notify after the waiter has started waiting. Either
+    // way nobody misses the wakeup, and a round ending with no waiters
+    // doesn't touch the lock.
+    fn finish_round(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.cond.notify_all()
+        }
+
+        // The same goes for the joiner, which marks the group watched
+        // before checking it.
+        if self.watched.load(Ordering::SeqCst) {
+            let joiner = self.joiner.lock().unwrap().clone();
+            if let Some(joiner) = joiner {
+                joiner.poke();
+            }
+        }
+    }
+
+    // Whether this group and those it defers to have no pending work,
+    // watching each so `joiner` is poked when a round of it ends.
+    fn settled_for(&self, joiner: &Arc<Joiner>) -> bool {
+        if !self.watched.load(Ordering::SeqCst) {
+            *self.joiner.lock().unwrap() = Some(joiner.clone());
+            self.watched.store(true, Ordering::SeqCst);
+        }
+
+        self.waiting() == 0
+            && self
+                .deferred
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|wait| wait.settled_for(joiner))
+    }
+
+    // Count a job as queued in the current epoch, returning the epoch.
+    fn enter_epoch(&self) -> u64 {
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            self.epoch_jobs[(epoch & 1) as usize].fetch_add(1, Ordering::SeqCst);
+
+            // A checkpoint starting in between may have missed the count,
+            // so count the job in the new epoch instead.
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                return epoch;
+            }
+            self.leave_epoch(epoch);
+        }
+    }
+
+    // Count a job from `epoch` as done, waking checkpoints as with
+    // `finish_round` if it was the last.
+    fn leave_epoch(&self, epoch: u64) {
+        let old = self.epoch_jobs[(epoch & 1) as usize].fetch_sub(1, Ordering::SeqCst);
+
+        if old == 1 && self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.cond.notify_all()
+        }
+    }
+
+    // Start a new epoch, then wait for the jobs of the previous one.
+    fn checkpoint(&self) {
+        let _turn = self.checkpoint.lock().unwrap();
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst);
+
+        let (mut lock, _sleeper) = self.lock_to_wait();
+        while self.epoch_jobs[(epoch & 1) as usize].load(Ordering::SeqCst) > 0 {
+            lock = self.cond.wait(lock).unwrap();
+        }
+    }
+
+    // Wake every task registered with `register_waker`, after a completion.
+    #[cfg(feature = "async")]
+    fn wake_tasks(&self) {
+        if !self.has_wakers.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let wakers = {
+            let mut wakers = self.wakers.lock().unwrap();
+            self.has_wakers.store(false, Ordering::SeqCst);
+            mem::take(&mut *wakers)
+        };
+
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    // Have the next completion wake a task. As with `sleepers`, the task
+    // must check for the completion after registering, which either sees it
+    // or is sure to be woken.
+    #[cfg(feature = "async")]
+    fn register_waker(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+        self.has_wakers.store(true, Ordering::SeqCst);
+    }
+
+    // Take the lock as a waiter, which must be counted in `sleepers` for
+    // as long as it might wait on `cond`.
+    fn lock_to_wait(&self) -> (MutexGuard<'_, ()>, Sleeper<'_>) {
+        let lock = self.lock.lock().unwrap();
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        (lock, Sleeper(&self.sleepers))
+    }
+
+    /// How many times pending submits have all been completed.
+    ///
+    /// Each time the number of pending submits drops to zero counts as one
+    /// round, so a WaitGroup which is reused for several phases of work can
+    /// act as a barrier between them. The count wraps on overflow.
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Block until `generation` reaches `n`.
+    ///
+    /// Generations are compared with wrapping arithmetic, so this works
+    /// across overflow as long as `n` is within `2^63` rounds of the current
+    /// generation. Unlike `join`, this doesn't run completion callbacks or
+    /// panic if the group was poisoned.
+    pub fn wait_for_generation(&self, n: u64) {
+        let (mut lock, _sleeper) = self.lock_to_wait();
+
+        while (self.generation.load(Ordering::SeqCst).wrapping_sub(n) as i64) < 0 {
+            lock = self.cond.wait(lock).unwrap();
+        }
+    }
+
+    /// Clear the poison from an idle WaitGroup, so it can be reused.
+    ///
+    /// Fails without changing anything if any `submit` has not yet been
+    /// `complete`d.
+    #[inline]
+    pub fn reset(&self) -> Result<(), ResetError> {
+        let _lock = self.lock.lock().unwrap();
+
+        let pending = self.pending.load(Ordering::SeqCst);
+        if pending > 0 {
+            return Err(ResetError { pending });
+        }
+
+        self.poisoned.store(false, Ordering::SeqCst);
+        self.completed.store(0, Ordering::SeqCst);
+        self.failed.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Wait for `submit`s to this WaitGroup to be `complete`d.
+    ///
+    /// Submits occuring completely before joins will always be waited on.
+    ///
+    /// Submits occuring concurrently with a `join` may or may not
+    /// be waited for.
+    ///
+    /// Before submitting, `join` will always return immediately.
+    #[inline]
+    pub fn join(&self) {
+        self.settle();
+
+        for deferred in self.take_deferred() {
+            deferred.join();
+        }
+
+        self.finish_join()
+    }
+
+    /// Like `join`, but gives up waiting after `timeout`.
+    ///
+    /// Returns `true` if every submit was completed in time, having panicked
+    /// as `join` would if the WaitGroup was poisoned, or `false` on timeout.
+    #[inline]
+    pub fn join_timeout(&self, timeout: Duration) -> bool {
+        self.join_deadline(Instant::now() + timeout)
+    }
+
+    /// Like `join`, but gives up waiting once `flag` is set.
+    ///
+    /// Returns `true` if every submit was completed, having panicked as
+    /// `join` would if the WaitGroup was poisoned, or `false` if interrupted.
+    pub fn join_interruptible(&self, flag: &AtomicBool) -> bool {
+        // There's nothing to notify us when the flag is set, so poll it.
+        while !flag.load(Ordering::SeqCst) {
+            if self.join_timeout(Duration::from_millis(1)) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Like `join`, but gives up waiting at `deadline`.
+    ///
+    /// Returns `true` if every submit was completed in time, having panicked
+    /// as `join` would if the WaitGroup was poisoned, or `false` on timeout.
+    pub fn join_deadline(&self, deadline: Instant) -> bool {
+        if !self.settle_deadline(deadline) {
+            return false;
+        }
+
+        // Put back what we didn't get to, so a later join still waits.
+        let mut deferred = self.take_deferred();
+        while let Some(wait) = deferred.pop() {
+            if !wait.join_deadline(deadline) {
+                deferred.push(wait);
+                self.deferred.lock().unwrap().append(&mut deferred);
+                return false;
+            }
+        }
+
+        self.finish_join();
+        true
+    }
+
+    // Wait for pending submits, without joining deferred groups or panicking.
+    fn settle(&self) {
+        let (mut lock, _sleeper) = self.lock_to_wait();
+
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            lock = self.cond.wait(lock).unwrap();
+        }
+    }
+
+    // Like `settle`, but gives up at `deadline`, returning false.
+    fn settle_deadline(&self, deadline: Instant) -> bool {
+        let (mut lock, _sleeper) = self.lock_to_wait();
+
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+
+            lock = self.cond.wait_timeout(lock, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    // Hand jobs held back by `Scope::execute_finally` to `release` once the
+    // rest settle, including any they hold back themselves, so every join
+    // runs them before the completion callbacks and cleanups. Gives up at
+    // `deadline`, if there is one, keeping the jobs for a later join.
+    fn release_finally(&self, deadline: Option<Instant>, release: &dyn Fn(FinallyJob)) -> bool {
+        loop {
+            let mut finally = mem::take(&mut *self.finally.lock().unwrap());
+            if finally.is_empty() {
+                return true;
+            }
+
+            let settled = match deadline {
+                Some(deadline) => self.settle_deadline(deadline),
+                None => {
+                    self.settle();
+                    true
+                }
+            };
+
+            if !settled {
+                let mut held = self.finally.lock().unwrap();
+                finally.append(&mut held);
+                *held = finally;
+                return false;
+            }
+
+            for job in finally {
+                release(job);
+            }
+        }
+    }
+
+    // Reset a WaitGroup nothing else refers to, so it can be reused as if
+    // new. Returns false if it can't be.
+    fn clear(&mut self) -> bool {
+        let locals = match Arc::get_mut(&mut self.locals) {
+            Some(locals) => locals,
+            // Still referenced by a subscope.
+            None => return false,
+        };
+
+        if *self.pending.get_mut() != 0 {
+            return false;
+        }
+
+        locals.values.get_mut().unwrap().clear();
+        *locals.cancelled.get_mut() = false;
+        locals.parent = None;
+
+        *self.poisoned.get_mut() = false;
+        *self.completed.get_mut() = 0;
+        *self.failed.get_mut() = 0;
+        *self.generation.get_mut() = 0;
+        self.on_complete.get_mut().unwrap().clear();
+        self.cleanups.get_mut().unwrap().clear();
+        self.deferred.get_mut().unwrap().clear();
+        self.finally.get_mut().unwrap().clear();
+        *self.joiner.get_mut().unwrap() = None;
+        *self.watched.get_mut() = false;
+        self.timings = None;
+        *self.aborted.get_mut() = false;
+        *self.next_task_id.get_mut() = 0;
+        *self.recursion_order.get_mut() = 0;
+        *self.watchdog_jobs.get_mut() = 0;
+        #[cfg(feature = "async")]
+        {
+            self.wakers.get_mut().unwrap().clear();
+            *self.has_wakers.get_mut() = false;
+        }
+        self.timed_out.get_mut().unwrap().clear();
+        #[cfg(feature = "scope-tree")]
+        self.children.get_mut().unwrap().clear();
+        true
+    }
+
+    // Make our joins also wait for another WaitGroup.
+    fn defer(&self, wait: Arc<WaitGroup>) {
+        let mut deferred = self.deferred.lock().unwrap();
+
+        // Forget groups which finished cleanly, so long lived groups don't
+        // accumulate them.
+        deferred.retain(|wait| wait.waiting() > 0 || wait.poisoned.load(Ordering::SeqCst));
+        deferred.push(wait);
+    }
+
+    fn take_deferred(&self) -> Vec<Arc<WaitGroup>> {
+        mem::take(&mut *self.deferred.lock().unwrap())
+    }
+
+    // Called once a join has seen every submit completed.
+    fn finish_join(&self) {
+        // Run the completion callbacks oldest first, then every cleanup
+        // newest first, all outside of the locks. A panic doesn't stop the
+        // rest, and the first is propogated unless the poison panic takes
+        // precedence.
+        let poisoned = self.poisoned.load(Ordering::SeqCst);
+        let mut failed = None;
+
+        let callbacks = mem::take(&mut *self.on_complete.lock().unwrap());
+        for callback in callbacks {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| callback(!poisoned))) {
+                failed.get_or_insert(payload);
+            }
+        }
+
+        let cleanups = mem::take(&mut *self.cleanups.lock().unwrap());
+        for cleanup in cleanups.into_iter().rev() {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(cleanup)) {
+                failed.get_or_insert(payload);
+            }
+        }
+
+        if poisoned {
+            let message = match self.poison_message {
+                Some(ref message) => message,
+                None => "WaitGroup explicitly poisoned!",
+            };
+
+            panic!(
+                "{} ({} completed, {} failed)",
+                message,
+                self.completed.load(Ordering::SeqCst),
+                self.failed.load(Ordering::SeqCst)
+            )
+        }
+
+        if let Some(payload) = failed {
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+/// The error returned when resetting a `WaitGroup` or `Scope` which still
+/// has pending jobs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetError {
+    pending: usize,
+}
+
+impl ResetError {
+    /// How many jobs were still pending when the reset was attempted.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+}
+
+impl fmt::Display for ResetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot reset with {} jobs still pending", self.pending)
+    }
+}
+
+impl error::Error for ResetError {}
+
+/// How a pool handles panics in detached jobs, set with
+/// `PoolBuilder::detached_panic_policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DetachedPanicPolicy {
+    /// Catch the panic once the panic hook has reported it, as it does for
+    /// any panic, and carry on. The worker keeps running and the pool is
+    /// unaffected.
+    CatchAndLog,
+
+    /// Let the panic unwind the worker, which is restarted, and poison the
+    /// pool's spawned work, so `shutdown` panics.
+    #[default]
+    Propagate,
+}
+
+/// What a worker does when a job panics, set with
+/// `PoolBuilder::panic_behavior`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanicBehavior {
+    /// Unwind the job and poison its scope, so the panic propogates to the
+    /// thread joining it.
+    #[default]
+    Unwind,
+
+    /// Abort the process once the panic hook has reported the panic,
+    /// without running destructors or propogating it.
+    Abort,
+}
+
+/// What an idle worker does before blocking on an empty queue, set with
+/// `PoolBuilder::idle_strategy`.
+///
+/// Blocking is cheapest while idle, but waking a blocked worker adds latency
+/// to the next job. Checking the queue a number of times first saves that
+/// when jobs arrive in quick succession, at the cost of CPU time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdleStrategy {
+    /// Block straight away.
+    #[default]
+    Park,
+
+    /// Check the queue this many times, yielding to the OS scheduler between
+    /// checks, then block. Lets other threads use the core while waiting.
+    Yield(usize),
+
+    /// Check the queue this many times, spinning between checks, then
+    /// block. Reacts fastest, but holds on to the core.
+    Spin(usize),
+}
+
+/// How an idle worker spaces out its checks of the queue, set with
+/// `PoolBuilder::steal_backoff`.
+///
+/// The spins or yields allowed by the `IdleStrategy` are a budget for pauses
+/// between checks, after which the worker blocks, so this changes how often
+/// the worker checks rather than how long it stays awake.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StealBackoff {
+    /// Check after every spin or yield.
+    #[default]
+    Constant,
+
+    /// Double the pause after each failed check, from one spin or yield up
+    /// to `max_pause` of them.
+    Exponential {
+        /// The longest pause between checks.
+        max_pause: usize,
+    },
+}
+
+/// The number of a worker thread, unique within its pool, as passed to
+/// `PoolBuilder::on_worker_start` and `on_worker_stop`.
+///
+/// Workers are numbered from zero in the order they were spawned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WorkerId(usize);
+
+impl WorkerId {
+    /// The id as a plain number.
+    #[inline]
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for WorkerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "worker {}", self.0)
+    }
+}
+
+/// Why a worker thread stopped, as passed to `PoolBuilder::on_worker_stop`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The pool was shut down.
+    Quit,
+
+    /// A job panicked on the worker.
+    Panicked,
+
+    /// The worker was stopped by `Pool::contract`.
+    Retired,
+
+    /// The pool shut itself down after `PoolBuilder::auto_shutdown_after`.
+    IdleTimeout,
+}
+
+/// Where jobs added with `Scope::recurse` are queued, set with
+/// `Scope::with_recursion_order` or `PoolBuilder::recursion_order`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecursionOrder {
+    /// At the front of the queue, so a job's children run before its
+    /// siblings. This keeps the queue short and data hot, but a deep
+    /// recursion can hold up other work.
+    DepthFirst,
+
+    /// At the back of the shared queue, behind work already queued, so
+    /// siblings and other scopes get their turn. The queue grows with the
+    /// width of the recursion.
+    BreadthFirst,
+}
+
+impl RecursionOrder {
+    // Pack an order for storing in an atomic, with zero meaning unset.
+    fn code(order: Option<RecursionOrder>) -> u8 {
+        match order {
+            None => 0,
+            Some(RecursionOrder::DepthFirst) => 1,
+            Some(RecursionOrder::BreadthFirst) => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<RecursionOrder> {
+        match code {
+            1 => Some(RecursionOrder::DepthFirst),
+            2 => Some(RecursionOrder::BreadthFirst),
+            _ => None,
+        }
+    }
+}
+
+/// How `Pool::shutdown_with` treats jobs still in the queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// Discard queued jobs without running them.
+    Immediate,
+
+    /// Run every queued job before stopping, like `Pool::shutdown`.
+    Drain,
+
+    /// Run queued jobs until the timeout, then discard the rest.
+    DrainTimeout(Duration),
+
+    /// Discard queued `Pool::spawn` jobs, which nothing is waiting on, but
+    /// run every other queued job like `Drain`. Spawned jobs which are
+    /// already running finish first.
+    CancelSpawned,
+}
+
+// The workers which have had their turn in a `Pool::broadcast`.
+//
+// Turns are counted under the pool's `workers` lock and waited for on
+// `worker_ready`, which workers exiting also notify, so a worker leaving
+// part way through lowers the target without anyone polling for it.
+struct Broadcast {
+    ran: Mutex<Vec<thread::ThreadId>>,
+    // Workers when the broadcast started.
+    total: usize,
+}
+
+impl Broadcast {
+    fn run(&self, pool: &Pool, f: &dyn Fn()) {
+        let current = thread::current().id();
+
+        {
+            let ran = self.ran.lock().unwrap();
+            if ran.contains(&current) || ran.len() >= self.target(pool) {
+                return;
+            }
+        }
+
+        {
+            // Count the turn even if `f` panics, so nobody waits for it.
+            defer!({
+                let _workers = pool.inner.workers.lock().unwrap();
+                self.ran.lock().unwrap().push(current);
+                pool.inner.worker_ready.notify_all();
+            });
+            f();
+        }
+
+        // Hold this worker until everyone has had a turn.
+        let mut workers = pool.inner.workers.lock().unwrap();
+        while self.ran.lock().unwrap().len() < self.target(pool) {
+            workers = pool.inner.worker_ready.wait(workers).unwrap();
+        }
+    }
+
+    // How many workers need a turn, allowing for workers which have left.
+    fn target(&self, pool: &Pool) -> usize {
+        self.total.min(pool.workers())
+    }
+}
+
+// Per-job running times gathered by `Pool::scoped_timed`.
+struct BatchTimings {
+    tasks: AtomicUsize,
+    total: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for BatchTimings {
+    fn default() -> Self {
+        BatchTimings {
+            tasks: AtomicUsize::new(0),
+            total: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl BatchTimings {
+    fn record(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.tasks.fetch_add(1, Ordering::SeqCst);
+        self.total.fetch_add(nanos, Ordering::SeqCst);
+        self.min.fetch_min(nanos, Ordering::SeqCst);
+        self.max.fetch_max(nanos, Ordering::SeqCst);
+    }
+
+    fn stats(&self, wall: Duration) -> BatchStats {
+        let tasks = self.tasks.load(Ordering::SeqCst);
+        let total = self.total.load(Ordering::SeqCst);
+
+        let (min, mean) = match tasks {
+            0 => (0, 0),
+            n => (self.min.load(Ordering::SeqCst), total / n as u64),
+        };
+
+        BatchStats {
+            wall,
+            tasks,
+            min: Duration::from_nanos(min),
+            max: Duration::from_nanos(self.max.load(Ordering::SeqCst)),
+            mean: Duration::from_nanos(mean),
+        }
+    }
+}
+
+/// The pending jobs of a scope and its subscopes, from `Scope::pending_tree`.
+///
+/// Only available with the `scope-tree` feature.
+#[cfg(feature = "scope-tree")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingNode {
+    pending: usize,
+    children: Vec<PendingNode>,
+}
+
+#[cfg(feature = "scope-tree")]
+impl PendingNode {
+    fn of(wait: &WaitGroup) -> PendingNode {
+        let children: Vec<_> = {
+            let children = wait.children.lock().unwrap();
+            children.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        PendingNode {
+            pending: wait.waiting(),
+            children: children
+                .iter()
+                .map(|child| PendingNode::of(child))
+                .collect(),
+        }
+    }
+
+    /// How many jobs this scope is waiting on, not counting subscopes.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// The live subscopes, oldest first.
+    #[inline]
+    pub fn children(&self) -> &[PendingNode] {
+        &self.children
+    }
+
+    /// The jobs waited on here and in every subscope below.
+    pub fn total(&self) -> usize {
+        self.pending + self.children.iter().map(PendingNode::total).sum::<usize>()
+    }
+}
+
+/// Timings for a batch of jobs run with `Pool::scoped_timed`.
+///
+/// Comparing `max` with `mean` shows how unevenly work was split between
+/// jobs. Every per-job time is zero if no jobs ran.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchStats {
+    wall: Duration,
+    tasks: usize,
+    min: Duration,
+    max: Duration,
+    mean: Duration,
+}
+
+impl BatchStats {
+    /// The time from the start of the batch until every job had finished.
+    #[inline]
+    pub fn wall(&self) -> Duration {
+        self.wall
+    }
+
+    /// How many jobs were timed.
+    #[inline]
+    pub fn tasks(&self) -> usize {
+        self.tasks
+    }
+
+    /// The running time of the quickest job.
+    #[inline]
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    /// The running time of the slowest job.
+    #[inline]
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The average running time of a job.
+    #[inline]
+    pub fn mean(&self) -> Duration {
+        self.mean
+    }
+}
+
+/// What happened to jobs during `Pool::shutdown_with`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    ran: usize,
+    dropped: usize,
+}
+
+impl ShutdownSummary {
+    /// How many jobs finished running during the shutdown, including jobs
+    /// which were already running when it started.
+    #[inline]
+    pub fn ran(&self) -> usize {
+        self.ran
+    }
+
+    /// How many queued jobs were discarded without running.
+    #[inline]
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+/// The error returned by `Pool::scoped_deadline` when jobs did not complete
+/// before the deadline.
+pub struct Timeout<R> {
+    result: R,
+    token: CancelToken,
+}
+
+impl<R> Timeout<R> {
+    /// A token for cancelling the timed out scope, so its jobs which haven't
+    /// started yet are skipped.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.token.clone()
+    }
+
+    /// The scheduler's result.
+    pub fn into_result(self) -> R {
+        self.result
+    }
+}
+
+impl<R> fmt::Debug for Timeout<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Timeout").finish_non_exhaustive()
+    }
+}
+
+impl<R> fmt::Display for Timeout<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("jobs did not complete before the deadline")
+    }
+}
+
+impl<R> error::Error for Timeout<R> {}
+
+// Poisons the given pool on drop unless canceled.
+//
+// Used to ensure panic propogation between jobs and waiting threads.
+struct Sentinel(Option<Arc<WaitGroup>>, PanicBehavior);
+
+impl Sentinel {
+    fn cancel(mut self) {
+        if let Some(wait) = self.0.take() {
+            wait.complete()
+        }
+    }
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if let Some(wait) = self.0.take() {
+            if let PanicBehavior::Abort = self.1 {
+                eprintln!("a pool job panicked with PanicBehavior::Abort set; aborting");
+                std::process::abort();
+            }
+
+            wait.poison()
+        }
+    }
+}
+
+// Marks a worker as exited, restarting it first if it panicked, unless it's
+// a thread donated with `run_on_current_thread`.
+struct ThreadSentinel(Option<Pool>, bool);
+
+impl ThreadSentinel {
+    fn cancel(&mut self) {
+        if let Some(pool) = self.0.take() {
+            pool.wait.complete();
+            pool.inner.worker_exited();
+        }
+    }
+}
+
+// How many times a panicked worker tries to start its replacement.
+const RESTART_ATTEMPTS: usize = 5;
+
+impl Drop for ThreadSentinel {
+    fn drop(&mut self) {
+        if let Some(pool) = self.0.take() {
+            // NOTE: We restart the thread first so we don't accidentally
+            // hit zero threads before restarting.
+
+            // Restart the thread, backing off while spawning fails, as it
+            // can at the process's thread limit. If it never succeeds the
+            // pool runs a thread short, which shows as `workers` falling
+            // behind `configured_workers`.
+            // Donated threads aren't replaced, and nor is anything in a
+            // `fail_fast` pool, which dies instead. Nor is a thread lost
+            // while shutting down, as the replacement would only wait for
+            // a Quit and hold up the shutdown.
+            if pool.inner.fail_fast {
+                pool.kill();
+            }
+
+            let restart = self.1 && !pool.inner.fail_fast && !pool.is_shutdown();
+            let attempts = if restart { RESTART_ATTEMPTS } else { 0 };
+            let mut backoff = Duration::from_millis(1);
+            for _ in 0..attempts {
+                if pool.spawn_worker().is_ok() {
+                    break;
+                }
+
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+
+            // Poison the pool.
+            pool.wait.poison();
+            pool.inner.worker_exited();
+        }
+    }
+}
+
+/// A future resolving once a scope has no pending jobs, from
+/// `Scope::completion`.
+///
+/// Only available with the `async` feature.
+#[cfg(feature = "async")]
+pub struct Completion {
+    wait: Arc<WaitGroup>,
+}
+
+#[cfg(feature = "async")]
+impl Future for Completion {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.wait.waiting() == 0 {
+            return Poll::Ready(());
+        }
+
+        self.wait.register_waker(cx.waker());
+        if self.wait.waiting() == 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// A future resolving with the output of a job from `Pool::spawn_blocking`.
+///
+/// Only available with the `async` feature.
+#[cfg(feature = "async")]
+pub struct SpawnBlocking<T> {
+    slot: Arc<Mutex<BlockingSlot<T>>>,
+}
+
+#[cfg(feature = "async")]
+struct BlockingSlot<T> {
+    output: Option<thread::Result<T>>,
+    // The task to wake once the output is in.
+    waker: Option<Waker>,
+}
+
+#[cfg(feature = "async")]
+impl<T> Future for SpawnBlocking<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut slot = self.slot.lock().unwrap();
+
+        match slot.output.take() {
+            Some(Ok(output)) => Poll::Ready(output),
+            Some(Err(payload)) => {
+                drop(slot);
+                panic::resume_unwind(payload)
+            }
+            None => {
+                match slot.waker {
+                    Some(ref waker) if waker.will_wake(cx.waker()) => {}
+                    _ => slot.waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A stream of a scope's finished job counts, from `Scope::progress`.
+///
+/// There is no `Stream` trait in `std`, so this offers `poll_next` in the
+/// shape of one, along with `next` to await items directly. Only available
+/// with the `async` feature.
+#[cfg(feature = "async")]
+pub struct Progress {
+    wait: Arc<WaitGroup>,
+    // The count last yielded.
+    seen: Option<usize>,
+}
+
+#[cfg(feature = "async")]
+impl Progress {
+    /// Poll for the next count, or `None` once the scope has drained.
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<usize>> {
+        if let Poll::Ready(item) = self.check() {
+            return Poll::Ready(item);
+        }
+
+        self.wait.register_waker(cx.waker());
+        self.check()
+    }
+
+    /// Wait for the next count, or `None` once the scope has drained.
+    pub async fn next(&mut self) -> Option<usize> {
+        std::future::poll_fn(|cx| self.poll_next(cx)).await
+    }
+
+    fn check(&mut self) -> Poll<Option<usize>> {
+        // Jobs are counted as done before they stop being pending, so once
+        // nothing is pending the count includes every job.
+        let drained = self.wait.waiting() == 0;
+        let done =
+            self.wait.completed.load(Ordering::SeqCst) + self.wait.failed.load(Ordering::SeqCst);
+
+        if self.seen != Some(done) && (done > 0 || !drained) {
+            self.seen = Some(done);
+            Poll::Ready(Some(done))
+        } else if drained {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// Run a future to completion on the current thread, parking while it is
+// pending.
+#[cfg(feature = "async")]
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct Unparker(thread::Thread);
+
+    impl Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unparker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            // Spurious unparks just mean an extra poll.
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// How many jobs `Scope::map_auto` splits `len` items into, on a pool with
+/// `workers` threads.
+///
+/// This is one job per worker, but never more jobs than items, and at least
+/// one job. Pass a different count to `Scope::map_split` to override it.
+#[inline]
+pub fn auto_split(len: usize, workers: usize) -> usize {
+    workers.min(len).max(1)
+}
+
+/// A task which can be turned into bytes, to run in another process.
+///
+/// Serialization is left to the implementation, so any format works. The
+/// receiving side finds the decoder by `NAME` in a `TaskRegistry`. Only
+/// available with the `remote` feature.
+#[cfg(feature = "remote")]
+pub trait SerializableTask: Send + 'static {
+    /// A name for the task type, unique among the types a registry knows.
+    const NAME: &'static str;
+
+    /// Encode the task's data.
+    fn serialize(&self) -> Vec<u8>;
+
+    /// Rebuild a task from the output of `serialize`.
+    fn deserialize(bytes: &[u8]) -> io::Result<Self>
+    where
+        Self: Sized;
+
+    /// Do the work.
+    fn run(self);
+}
+
+/// A serialized task, as handed to a `Transport`.
+///
+/// Only available with the `remote` feature.
+#[cfg(feature = "remote")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncodedTask {
+    name: String,
+    payload: Vec<u8>,
+}
+
+#[cfg(feature = "remote")]
+impl EncodedTask {
+    /// Rebuild an encoded task received from elsewhere.
+    pub fn new(name: String, payload: Vec<u8>) -> EncodedTask {
+        EncodedTask { name, payload }
+    }
+
+    /// The `SerializableTask::NAME` of the task's type.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The serialized task.
+    #[inline]
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+/// Carries encoded tasks to wherever they are run, set with
+/// `PoolBuilder::remote_transport`.
+///
+/// Embedders implement this over their own channel, such as a socket. Only
+/// available with the `remote` feature.
+#[cfg(feature = "remote")]
+pub trait Transport: Send + Sync {
+    /// Send a task, or hand it back if it can't be sent, in which case the
+    /// pool runs it locally.
+    fn send(&self, task: EncodedTask) -> Result<(), EncodedTask>;
+}
+
+/// Decodes and runs tasks received through a `Transport`.
+///
+/// Only available with the `remote` feature.
+#[cfg(feature = "remote")]
+#[derive(Default)]
+pub struct TaskRegistry {
+    decoders: HashMap<&'static str, Decoder>,
+}
+
+#[cfg(feature = "remote")]
+type Decoder = fn(&[u8]) -> io::Result<Box<dyn FnOnce() + Send>>;
+
+#[cfg(feature = "remote")]
+impl TaskRegistry {
+    /// Create a registry which knows no task types.
+    pub fn new() -> TaskRegistry {
+        TaskRegistry::default()
+    }
+
+    /// Make tasks of type `T` decodable.
+    pub fn register<T: SerializableTask>(&mut self) {
+        fn decode<T: SerializableTask>(bytes: &[u8]) -> io::Result<Box<dyn FnOnce() + Send>> {
+            let task = T::deserialize(bytes)?;
+            Ok(Box::new(move || task.run()))
+        }
+
+        self.decoders.insert(T::NAME, decode::<T>);
+    }
+
+    /// Decode a task, ready to run on any thread or pool.
+    ///
+    /// Fails if the task's type isn't registered or it doesn't decode.
+    pub fn decode(&self, task: &EncodedTask) -> io::Result<Box<dyn FnOnce() + Send>> {
+        match self.decoders.get(task.name()) {
+            Some(decode) => decode(task.payload()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown task type {:?}", task.name()),
+            )),
+        }
+    }
+}
+
+/// A `Transport` to a receiver in the same process, for testing.
+///
+/// Only available with the `remote` feature.
+#[cfg(feature = "remote")]
+pub struct LoopbackTransport {
+    sender: crossbeam::channel::Sender<EncodedTask>,
+}
+
+/// The receiving end of a `LoopbackTransport`.
+///
+/// Only available with the `remote` feature.
+#[cfg(feature = "remote")]
+pub struct LoopbackReceiver {
+    receiver: crossbeam::channel::Receiver<EncodedTask>,
+}
+
+#[cfg(feature = "remote")]
+impl LoopbackTransport {
+    /// Create a connected transport and receiver.
+    pub fn new() -> (LoopbackTransport, LoopbackReceiver) {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        (LoopbackTransport { sender }, LoopbackReceiver { receiver })
+    }
+}
+
+#[cfg(feature = "remote")]
+impl Transport for LoopbackTransport {
+    fn send(&self, task: EncodedTask) -> Result<(), EncodedTask> {
+        self.sender.send(task).map_err(|err| err.into_inner())
+    }
+}
+
+#[cfg(feature = "remote")]
+impl LoopbackReceiver {
+    /// Take the next task, blocking until one is sent. Returns `None` once
+    /// the transport is dropped and every task has been taken.
+    pub fn recv(&self) -> Option<EncodedTask> {
+        self.receiver.recv().ok()
+    }
+
+    /// Take a task if one is waiting.
+    pub fn try_recv(&self) -> Option<EncodedTask> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+/// The order a pool started its jobs in, recorded with
+/// `PoolBuilder::record_trace` and replayed with `PoolBuilder::replay_trace`.
+///
+/// Jobs are identified by their submission index on the pool, so a trace can
+/// be saved as a plain list of numbers with `order` and rebuilt with `From`.
+/// Only available with the `trace` feature.
+#[cfg(feature = "trace")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    order: Vec<u64>,
+}
+
+#[cfg(feature = "trace")]
+impl Trace {
+    /// The submission index of each job, in the order they started.
+    #[inline]
+    pub fn order(&self) -> &[u64] {
+        &self.order
+    }
+}
+
+#[cfg(feature = "trace")]
+impl From<Vec<u64>> for Trace {
+    fn from(order: Vec<u64>) -> Trace {
+        Trace { order }
+    }
+}
+
+// The replay of a trace, holding back jobs which arrive ahead of their turn.
+#[cfg(feature = "trace")]
+struct Replay {
+    order: Vec<u64>,
+    state: Mutex<ReplayState>,
+}
+
+#[cfg(feature = "trace")]
+struct ReplayState {
+    // Position in the trace of the next job to run.
+    next: usize,
+    held: BTreeMap<u64, Job>,
+}
+
+#[cfg(feature = "trace")]
+impl Replay {
+    fn new(trace: &Trace) -> Replay {
+        Replay {
+            order: trace.order.clone(),
+            state: Mutex::new(ReplayState {
+                next: 0,
+                held: BTreeMap::new(),
+            }),
+        }
+    }
+
+    fn hold(&self, job: Job) {
+        self.state.lock().unwrap().held.insert(job.seq, job);
+    }
+
+    // The next job to run, if it has been submitted.
+    fn next(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap();
+
+        match self.order.get(state.next) {
+            Some(seq) => {
+                let job = state.held.remove(seq)?;
+                state.next += 1;
+                Some(job)
+            }
+            // Past the end of the trace, go in submission order.
+            None => state.held.pop_first().map(|(_, job)| job),
+        }
+    }
+
+    // Give up on the rest of the trace, releasing every held job.
+    fn finish(&self) {
+        self.state.lock().unwrap().next = self.order.len();
+    }
+}
+
+trait Task {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce()> Task for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use std::hint::black_box;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+    use std::{io, mem, thread};
+
+    use {
+        crate::Autoscale, crate::DetachedPanicPolicy, crate::IdleStrategy, crate::PeekedTask,
+        crate::Pool, crate::PoolBuilder, crate::PoolMessage, crate::Scope, crate::ShutdownPolicy,
+        crate::Spawn, crate::StdSpawn, crate::ThreadConfig, crate::WaitGroup,
+    };
+
+    #[test]
+    fn test_simple_use() {
+        let pool = Pool::new(4);
+
+        let mut buf = [0, 0, 0, 0];
+
+        pool.scoped(|scope| {
+            for i in &mut buf {
+                scope.execute(move || *i += 1);
+            }
+        });
+
+        assert_eq!(&buf, &[1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_zoom() {
+        let pool = Pool::new(4);
+
+        let mut outer = 0;
+
+        pool.scoped(|scope| {
+            let mut inner = 0;
+            scope.zoom(|scope2| scope2.execute(|| inner = 1));
+            assert_eq!(inner, 1);
+
+            outer = 1;
+        });
+
+        assert_eq!(outer, 1);
+    }
+
+    #[test]
+    fn test_recurse() {
+        let pool = Pool::new(12);
+
+        let mut buf = [0, 0, 0, 0];
+
+        pool.scoped(|next| {
+            next.recurse(|next| {
+                buf[0] = 1;
+
+                next.execute(|| {
+                    buf[1] = 1;
+                });
+            });
+        });
+
+        assert_eq!(&buf, &[1, 1, 0, 0]);
+    }
+
+    #[test]
+    #[allow(clippy::empty_loop)]
+    fn test_spawn_doesnt_hang() {
+        let pool = Pool::new(1);
+        pool.spawn(move || loop {});
+    }
+
+    #[test]
+    fn test_forever_zoom() {
+        let pool = Pool::new(16);
+        let forever = Scope::forever(pool.clone());
+
+        let ran = AtomicBool::new(false);
+
+        forever.zoom(|scope| scope.execute(|| ran.store(true, Ordering::SeqCst)));
+
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let pool = Pool::new(4);
+        pool.shutdown();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_scheduler_panic() {
+        let pool = Pool::new(4);
+        pool.scoped(|_| panic!());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_scoped_execute_panic() {
+        let pool = Pool::new(4);
+        pool.scoped(|scope| scope.execute(|| panic!()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_pool_panic() {
+        let _pool = Pool::new(1);
+        panic!();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zoomed_scoped_execute_panic() {
+        let pool = Pool::new(4);
+        pool.scoped(|scope| scope.zoom(|scope2| scope2.execute(|| panic!())));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_recurse_scheduler_panic() {
+        let pool = Pool::new(4);
+        pool.scoped(|scope| scope.recurse(|_| panic!()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_recurse_execute_panic() {
+        let pool = Pool::new(4);
+        pool.scoped(|scope| scope.recurse(|scope2| scope2.execute(|| panic!())));
+    }
+
+    struct Canary<'a> {
+        drops: DropCounter<'a>,
+        expected: usize,
+    }
+
+    #[derive(Clone)]
+    struct DropCounter<'a>(&'a AtomicUsize);
+
+    impl<'a> Drop for DropCounter<'a> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl<'a> Drop for Canary<'a> {
+        fn drop(&mut self) {
+            let drops = self.drops.0.load(Ordering::SeqCst);
+            assert_eq!(drops, self.expected);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_scoped_panic_waits_for_all_tasks() {
+        let tasks = 50;
+        let panicking_task_fraction = 10;
+        let panicking_tasks = tasks / panicking_task_fraction;
+        let expected_drops = tasks + panicking_tasks;
+
+        let counter = Box::new(AtomicUsize::new(0));
+        let drops = DropCounter(&counter);
+
+        // Actual check occurs on drop of this during unwinding.
+        let _canary = Canary {
+            drops: drops.clone(),
+            expected: expected_drops,
+        };
+
+        let pool = Pool::new(12);
+
+        pool.scoped(|scope| {
+            for task in 0..tasks {
+                let drop_counter = drops.clone();
+
+                scope.execute(move || {
+                    sleep(Duration::from_millis(10));
+
+                    drop::<DropCounter>(drop_counter);
+                });
+
+                if task % panicking_task_fraction == 0 {
+                    let drop_counter = drops.clone();
+
+                    scope.execute(move || {
+                        // Just make sure we capture it.
+                        let _drops = drop_counter;
+                        panic!();
+                    });
+                }
+            }
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_scheduler_panic_waits_for_tasks() {
+        let tasks = 50;
+        let counter = Box::new(AtomicUsize::new(0));
+        let drops = DropCounter(&counter);
+
+        let _canary = Canary {
+            drops: drops.clone(),
+            expected: tasks,
+        };
+
+        let pool = Pool::new(12);
+
+        pool.scoped(|scope| {
+            for _ in 0..tasks {
+                let drop_counter = drops.clone();
+
+                scope.execute(move || {
+                    sleep(Duration::from_millis(25));
+                    drop::<DropCounter>(drop_counter);
+                });
+            }
+
+            panic!();
+        });
+    }
+
+    #[test]
+    fn test_no_thread_config() {
+        let pool = Pool::new(1);
+
+        pool.scoped(|scope| {
+            scope.execute(|| {
+                assert!(::std::thread::current().name().is_none());
+            });
+        });
+    }
+
+    #[test]
+    fn test_with_thread_config() {
+        let config = ThreadConfig::new().prefix("pool-");
+
+        let pool = Pool::with_thread_config(1, config);
+
+        pool.scoped(|scope| {
+            scope.execute(|| {
+                assert_eq!(::std::thread::current().name().unwrap(), "pool-1");
+            });
+        });
+    }
+
+    #[test]
+    fn test_queue_memory_limit() {
+        let pool = PoolBuilder::new().queue_memory_limit(64).build();
+
+        let payload = [0u8; 48];
+        let submitted = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                pool.scoped(|scope| {
+                    // Admitted into the empty queue.
+                    scope.execute(move || {
+                        black_box(payload);
+                    });
+
+                    // Blocks until the first job leaves the queue.
+                    scope.execute(move || {
+                        black_box(payload);
+                    });
+                    submitted.store(true, Ordering::SeqCst);
+                });
+            });
+
+            // Wait for the submitter to block on the budget.
+            let budget = pool.inner.memory_budget.as_ref().unwrap();
+            let mut blocked = budget.blocked.lock().unwrap();
+            while *blocked == 0 {
+                blocked = budget.cond.wait(blocked).unwrap();
+            }
+            drop(blocked);
+            assert!(!submitted.load(Ordering::SeqCst));
+
+            // A worker drains the queue, making room for the second job.
+            pool.expand();
+        });
+
+        assert!(submitted.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_queue_memory_limit_admits_oversized_job() {
+        let pool = PoolBuilder::new().size(1).queue_memory_limit(8).build();
+
+        let mut buf = [0u8; 128];
+
+        pool.scoped(|scope| {
+            let out = &mut buf;
+            let payload = [1u8; 128];
+            scope.execute(move || *out = payload);
+        });
+
+        assert_eq!(buf, [1u8; 128]);
+    }
+
+    #[test]
+    fn test_try_for_each() {
+        let pool = Pool::new(4);
+
+        let sum = AtomicUsize::new(0);
+
+        let result: Result<(), ()> = pool.scoped(|scope| {
+            scope.try_for_each(1..=100, |i| {
+                sum.fetch_add(*i, Ordering::SeqCst);
+                Ok(())
+            })
+        });
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(sum.load(Ordering::SeqCst), 5050);
+    }
+
+    #[test]
+    fn test_try_for_each_stops_on_error() {
+        let pool = Pool::new(1);
+
+        let ran = AtomicUsize::new(0);
+
+        let result = pool.scoped(|scope| {
+            scope.try_for_each(0..100, |i| {
+                ran.fetch_add(1, Ordering::SeqCst);
+                if *i >= 10 {
+                    Err(*i)
+                } else {
+                    Ok(())
+                }
+            })
+        });
+
+        // A single worker runs jobs in order, so nothing after the first
+        // failure is started.
+        assert_eq!(result, Err(10));
+        assert_eq!(ran.load(Ordering::SeqCst), 11);
+    }
+
+    struct CountingSpawn(Arc<AtomicUsize>);
+
+    impl Spawn for CountingSpawn {
+        fn spawn(
+            &self,
+            name: Option<String>,
+            stack_size: Option<usize>,
+            main: Box<dyn FnOnce() + Send>,
+        ) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            StdSpawn.spawn(name, stack_size, main)
+        }
+    }
+
+    // Spawns threads while it has any left to spawn.
+    struct LimitedSpawn(Arc<AtomicUsize>);
+
+    impl Spawn for LimitedSpawn {
+        fn spawn(
+            &self,
+            name: Option<String>,
+            stack_size: Option<usize>,
+            main: Box<dyn FnOnce() + Send>,
+        ) -> io::Result<()> {
+            let take = |left: usize| left.checked_sub(1);
+            match self
+                .0
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, take)
+            {
+                Ok(_) => StdSpawn.spawn(name, stack_size, main),
+                Err(_) => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_try_expand() {
+        let left = Arc::new(AtomicUsize::new(1));
+        let pool = PoolBuilder::new()
+            .size(1)
+            .spawner(LimitedSpawn(left.clone()))
+            .build();
+
+        assert!(pool.try_expand().is_err());
+        assert_eq!(pool.configured_workers(), 1);
+        assert_eq!(pool.workers(), 1);
+
+        // A panicked worker can't be replaced, which leaves the pool short.
+        pool.spawn(|| panic!());
+        assert!(
+            pool.wait_workers_timeout(0, Duration::from_secs(10)),
+            "the worker never exited"
+        );
+        assert_eq!(pool.configured_workers(), 1);
+
+        left.store(1, Ordering::SeqCst);
+        assert!(pool.try_expand().is_ok());
+        assert_eq!(pool.workers(), 1);
+    }
+
+    #[test]
+    fn test_custom_spawner() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+
+        let pool = PoolBuilder::new()
+            .size(3)
+            .spawner(CountingSpawn(spawned.clone()))
+            .build();
+
+        assert_eq!(spawned.load(Ordering::SeqCst), 3);
+
+        let mut buf = [0, 0, 0, 0];
+        pool.scoped(|scope| {
+            for i in &mut buf {
+                scope.execute(move || *i += 1);
+            }
+        });
+        assert_eq!(&buf, &[1, 1, 1, 1]);
+
+        pool.expand();
+        assert_eq!(spawned.load(Ordering::SeqCst), 4);
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_is_complete() {
+        let pool = Pool::new(1);
+
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+
+        pool.scoped(|scope| {
+            assert!(scope.is_complete());
+
+            scope.execute(move || rx.recv().unwrap());
+            assert!(!scope.is_complete());
+
+            tx.send(()).unwrap();
+            scope.join();
+            assert!(scope.is_complete());
+        });
+    }
+
+    #[test]
+    fn test_is_spawned_work_complete() {
+        let pool = Pool::new(1);
+        assert!(pool.is_spawned_work_complete());
+
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+        pool.spawn(move || rx.recv().unwrap());
+        assert!(!pool.is_spawned_work_complete());
+
+        tx.send(()).unwrap();
+        while !pool.is_spawned_work_complete() {
+            thread::yield_now();
+        }
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_subpool() {
+        let pool = Pool::new(4);
+        let first = pool.subpool();
+        let second = pool.subpool();
+
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let counter = counter.clone();
+            first.spawn(move || {
+                sleep(Duration::from_millis(5));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        first.shutdown();
+        assert!(first.is_shutdown());
+        assert_eq!(counter.load(Ordering::SeqCst), 10);
+
+        // The shared workers are still available to other subpools.
+        assert!(!second.is_shutdown());
+        let mut buf = [0, 0];
+        second.scoped(|scope| {
+            for i in &mut buf {
+                scope.execute(move || *i += 1);
+            }
+        });
+        assert_eq!(buf, [1, 1]);
+
+        pool.shutdown();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_subpool_rejects_work_after_shutdown() {
+        let pool = Pool::new(1);
+        let subpool = pool.subpool();
+
+        subpool.shutdown();
+        subpool.spawn(|| {});
+    }
+
+    #[test]
+    fn test_on_complete() {
+        let pool = Pool::new(4);
+
+        let counter = AtomicUsize::new(0);
+        let mut observed = None;
+
+        let order = Mutex::new(Vec::new());
+
+        pool.scoped(|scope| {
+            scope.on_complete(|clean| {
+                observed = Some((clean, counter.load(Ordering::SeqCst)));
+            });
+
+            // Later callbacks run too, in the order they were registered.
+            for i in 0..3 {
+                let order = &order;
+                scope.on_complete(move |_| order.lock().unwrap().push(i));
+            }
+
+            for _ in 0..10 {
+                scope.execute(|| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert_eq!(observed, Some((true, 10)));
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_on_complete_after_panic() {
+        let pool = Pool::new(4);
+
+        let clean = Mutex::new(None);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| {
+                scope.on_complete(|was_clean| *clean.lock().unwrap() = Some(was_clean));
+                scope.execute(|| panic!());
+            })
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(*clean.lock().unwrap(), Some(false));
+    }
+
+    #[test]
+    fn test_try_scoped() {
+        let pool = Pool::new(4);
+
+        assert_eq!(pool.try_scoped(|_| 5).unwrap(), 5);
+        assert!(pool.try_scoped(|scope| scope.execute(|| panic!())).is_err());
+
+        // The pool is still usable afterwards.
+        assert_eq!(pool.try_scoped(|_| 6).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_reset() {
+        let pool = Pool::new(2);
+        let scope = Scope::forever(pool.clone());
+
+        scope.execute(|| panic!());
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| scope.join())).is_err());
+
+        // Still poisoned until reset.
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| scope.join())).is_err());
+        assert_eq!(scope.reset(), Ok(()));
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        scope.execute(move || flag.store(true, Ordering::SeqCst));
+        scope.join();
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_reset_refuses_pending() {
+        let pool = Pool::new(1);
+        let scope = Scope::forever(pool.clone());
+
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+        scope.execute(move || rx.recv().unwrap());
+
+        assert_eq!(scope.reset().unwrap_err().pending(), 1);
+
+        tx.send(()).unwrap();
+        scope.join();
+        assert_eq!(scope.reset(), Ok(()));
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_task_selector() {
+        // Always run the task with the smallest tag first.
+        let pool = PoolBuilder::new()
+            .task_selector(Box::new(|tasks: &[PeekedTask]| {
+                (0..tasks.len()).min_by_key(|&i| tasks[i].tag()).unwrap()
+            }))
+            .build();
+
+        let order = Mutex::new(Vec::new());
+
+        pool.scoped(|scope| {
+            for tag in [5, 3, 7, 1, 4] {
+                let order = &order;
+                scope.execute_tagged(tag, move || order.lock().unwrap().push(tag));
+            }
+
+            // Only start a worker once everything is queued.
+            scope.pool.expand();
+        });
+
+        assert_eq!(*order.lock().unwrap(), vec![1, 3, 4, 5, 7]);
+    }
+
+    #[test]
+    fn test_task_selector_out_of_range() {
+        let pool = PoolBuilder::new()
+            .task_selector(Box::new(|_: &[PeekedTask]| usize::MAX))
+            .build();
+
+        let order = Mutex::new(Vec::new());
+
+        pool.scoped(|scope| {
+            for i in 0..5 {
+                let order = &order;
+                scope.execute(move || order.lock().unwrap().push(i));
+            }
+
+            scope.pool.expand();
+        });
+
+        // Falls back to FIFO.
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_shutdown_during_scoped() {
+        for _ in 0..50 {
+            let pool = Pool::new(4);
+            let counter = AtomicUsize::new(0);
+
+            let (tx, rx) = crossbeam::channel::unbounded::<()>();
+
+            thread::scope(|s| {
+                pool.scoped(|scope| {
+                    // Keep one worker alive until the whole batch is queued.
+                    scope.execute(move || rx.recv().unwrap());
+
+                    for i in 0..100 {
+                        // Shut down part way through scheduling the batch.
+                        if i == 50 {
+                            s.spawn(|| pool.shutdown());
+                        }
+
+                        scope.execute(|| {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                        });
+                    }
+
+                    tx.send(()).unwrap();
+                });
+            });
+
+            assert_eq!(counter.load(Ordering::SeqCst), 100);
+            assert_eq!(pool.workers(), 0);
+        }
+    }
+
+    #[test]
+    fn test_spin_before_park() {
+        let pool = PoolBuilder::new().size(2).spin_before_park(1000).build();
+
+        for _ in 0..10 {
+            let counter = AtomicUsize::new(0);
+
+            pool.scoped(|scope| {
+                for _ in 0..10 {
+                    scope.execute(|| {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            });
+
+            assert_eq!(counter.load(Ordering::SeqCst), 10);
+            sleep(Duration::from_millis(1));
+        }
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_clear_queue() {
+        let pool = Pool::empty();
+
+        let ran = AtomicUsize::new(0);
+
+        pool.scoped(|scope| {
+            for _ in 0..10 {
+                scope.execute(|| {
+                    ran.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+
+            // With no workers, everything is still queued.
+            assert_eq!(pool.clear_queue(), 10);
+            assert_eq!(pool.clear_queue(), 0);
+        });
+
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_clear_queue_preserves_shutdown() {
+        use crossbeam::channel::unbounded;
+
+        let pool = Pool::new(1);
+        let (release, released) = unbounded::<()>();
+        let ran = Arc::new(AtomicBool::new(false));
+
+        // Hold the worker, with a job queued behind it.
+        pool.spawn(move || {
+            let _ = released.recv();
+        });
+        let flag = ran.clone();
+        pool.spawn(move || flag.store(true, Ordering::SeqCst));
+        while pool.queued_jobs() != 1 {
+            thread::yield_now();
+        }
+
+        thread::scope(|s| {
+            let shutdown = s.spawn(|| pool.shutdown());
+            while !pool.is_shutdown() {
+                thread::yield_now();
+            }
+            sleep(Duration::from_millis(10));
+
+            // The job goes, but the worker still gets its Quit.
+            assert_eq!(pool.clear_queue(), 1);
+            drop(release);
+            shutdown.join().unwrap();
+        });
+
+        assert!(!ran.load(Ordering::SeqCst));
+        assert!(pool.is_spawned_work_complete());
+        assert_eq!(pool.workers(), 0);
+    }
+
+    #[test]
+    fn test_queued_jobs_during_removal() {
+        use crossbeam::channel::unbounded;
+
+        let pool = Pool::new(1);
+        let (release, released) = unbounded::<()>();
+        let ran = AtomicUsize::new(0);
+        let (cancelled, cleared) = (AtomicBool::new(false), AtomicBool::new(false));
+
+        // Sample `queued_jobs` until `done` is set.
+        let sample = |done: &AtomicBool| {
+            let mut samples = Vec::new();
+            while !done.load(Ordering::SeqCst) {
+                samples.push(pool.queued_jobs());
+            }
+            samples
+        };
+
+        thread::scope(|s| {
+            // Hold the only worker, so the rest stay queued.
+            let busy = s.spawn(|| {
+                pool.scoped(|scope| {
+                    scope.execute(|| {
+                        let _ = released.recv();
+                    });
+                    for _ in 0..100 {
+                        scope.execute(|| {
+                            ran.fetch_add(1, Ordering::SeqCst);
+                        });
+                    }
+                })
+            });
+            while pool.queued_jobs() != 100 {
+                thread::yield_now();
+            }
+
+            // Cancelling another scope takes these out and puts them back,
+            // without them ever going uncounted.
+            let sampler = s.spawn(|| sample(&cancelled));
+            for _ in 0..50 {
+                let other = Scope::forever(pool.clone());
+                for _ in 0..10 {
+                    other.execute(|| {});
+                }
+                assert_eq!(other.cancel_pending(), 10);
+            }
+            cancelled.store(true, Ordering::SeqCst);
+            assert!(sampler.join().unwrap().iter().all(|&n| n >= 100));
+
+            // Clearing only ever lowers the count, one job at a time.
+            let sampler = s.spawn(|| sample(&cleared));
+            assert_eq!(pool.clear_queue(), 100);
+            cleared.store(true, Ordering::SeqCst);
+            let samples = sampler.join().unwrap();
+            assert!(samples.windows(2).all(|pair| pair[0] >= pair[1]));
+            assert_eq!(pool.queued_jobs(), 0);
+
+            drop(release);
+            busy.join().unwrap();
+        });
+
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_collect_errors() {
+        let pool = Pool::new(4);
+
+        let errors = pool.scoped(|scope| {
+            let errors = scope.collect_errors();
+
+            for i in 0..20 {
+                scope.execute_collecting(&errors, move || if i % 3 == 0 { Err(i) } else { Ok(()) });
+            }
+
+            errors
+        });
+
+        let mut errors = errors.into_vec();
+        errors.sort();
+        assert_eq!(errors, vec![0, 3, 6, 9, 12, 15, 18]);
+
+        // Jobs which all succeed never set up the storage.
+        let errors = pool.scoped(|scope| {
+            let errors = scope.collect_errors::<usize>();
+            for _ in 0..20 {
+                scope.execute_collecting(&errors, || Ok(()));
+            }
+            errors
+        });
+        assert!(errors.errors.get().is_none());
+        assert!(errors.into_vec().is_empty());
+    }
+
+    #[test]
+    fn test_join_timeout() {
+        let pool = Pool::new(1);
+
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+
+        pool.scoped(|scope| {
+            scope.execute(move || rx.recv().unwrap());
+            assert!(!scope.join_timeout(Duration::from_millis(10)));
+
+            tx.send(()).unwrap();
+            assert!(scope.join_timeout(Duration::from_secs(10)));
+        });
+    }
+
+    #[test]
+    fn test_scoped_deadline() {
+        let pool = Pool::new(2);
+
+        let result = pool.scoped_deadline(Instant::now() + Duration::from_secs(10), |scope| {
+            scope.execute(|| {});
+            5
+        });
+        assert_eq!(result.unwrap(), 5);
+
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+        let result = pool.scoped_deadline(Instant::now() + Duration::from_millis(10), |scope| {
+            scope.execute(move || rx.recv().unwrap());
+            6
+        });
+        let timeout = result.unwrap_err();
+        assert!(!timeout.cancel_token().is_cancelled());
+        assert_eq!(timeout.into_result(), 6);
+
+        // The job is still running, and finishes once released.
+        tx.send(()).unwrap();
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_scoped_deadline_cancel() {
+        let pool = Pool::new(1);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+
+        let result = pool.scoped_deadline(Instant::now() + Duration::from_millis(10), |scope| {
+            scope.execute(move || rx.recv().unwrap());
+            for _ in 0..5 {
+                let ran = ran.clone();
+                scope.execute(move || {
+                    ran.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        // Cancelling after the timeout skips the jobs still queued behind
+        // the blocked one.
+        result.unwrap_err().cancel_token().cancel();
+        tx.send(()).unwrap();
+        pool.shutdown();
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_local_jobs_are_stolen() {
+        let pool = Pool::new(2);
+        let (tx, rx) = crossbeam::channel::unbounded();
+
+        pool.scoped(|scope| {
+            scope.recurse(move |scope| {
+                // Queued on this worker's own deque, so the only way it can
+                // run while we block is if the other worker steals it.
+                scope.execute(move || tx.send(()).unwrap());
+                rx.recv().unwrap();
+            });
+        });
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_lifo_local_queue() {
+        let pool = Pool::new(1);
+        let order = Mutex::new(Vec::new());
+
+        pool.scoped(|scope| {
+            scope.recurse(|scope| {
+                for i in 0..3 {
+                    let order = &order;
+                    scope.execute(move || order.lock().unwrap().push(i));
+                }
+            });
+        });
+
+        assert_eq!(*order.lock().unwrap(), vec![2, 1, 0]);
+        pool.shutdown();
+    }
+
+    #[cfg(feature = "utilization")]
+    #[test]
+    fn test_worker_utilization() {
+        let pool = Pool::new(2);
+
+        // Workers register once their threads start.
+        pool.wait_ready(2);
+
+        // Keep one worker busy for most of a window.
+        pool.scoped(|scope| scope.execute(|| sleep(Duration::from_millis(50))));
+        let utilization = pool.worker_utilization();
+
+        assert!(utilization.iter().all(|&u| (0.0..=1.0).contains(&u)));
+        assert!(utilization.iter().any(|&u| u > 0.5), "{:?}", utilization);
+
+        // Nothing ran since the previous sample.
+        sleep(Duration::from_millis(10));
+        assert!(pool.worker_utilization().iter().all(|&u| u < 0.5));
+
+        pool.shutdown();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_execute_async() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        // Pending until woken from another thread.
+        struct Delayed(Option<Arc<AtomicBool>>);
+
+        impl Future for Delayed {
+            type Output = ();
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+                match self.0 {
+                    Some(ref done) if done.load(Ordering::SeqCst) => Poll::Ready(()),
+                    Some(_) => Poll::Pending,
+                    None => {
+                        let done = Arc::new(AtomicBool::new(false));
+                        let waker = cx.waker().clone();
+                        self.0 = Some(done.clone());
+
+                        thread::spawn(move || {
+                            sleep(Duration::from_millis(10));
+                            done.store(true, Ordering::SeqCst);
+                            waker.wake();
+                        });
+                        Poll::Pending
+                    }
+                }
+            }
+        }
+
+        let pool = Pool::new(2);
+        let counter = AtomicUsize::new(0);
+
+        pool.scoped(|scope| {
+            for _ in 0..4 {
+                let counter = &counter;
+                scope.execute_async(async move {
+                    Delayed(None).await;
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert_eq!(counter.load(Ordering::SeqCst), 4);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_poison_message() {
+        let pool = PoolBuilder::new()
+            .size(2)
+            .poison_message("batch import failed")
+            .build();
+
+        let error = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| {
+                scope.execute(|| {});
+                scope.execute(|| panic!());
+            })
+        }))
+        .unwrap_err();
+
+        let message = error.downcast::<String>().unwrap();
+        assert_eq!(*message, "batch import failed (1 completed, 1 failed)");
+    }
+
+    #[test]
+    fn test_execute_indexed() {
+        let pool = Pool::new(3);
+
+        for len in [0, 1, 7, 100, 1001] {
+            let mut out = vec![0; len];
+            pool.scoped(|scope| scope.execute_indexed(&mut out, |i| i * i));
+
+            assert!(out.iter().enumerate().all(|(i, &x)| x == i * i));
+        }
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_with_shared() {
+        let pool = Pool::new(2);
+        let total = AtomicUsize::new(0);
+
+        pool.scoped(|scope| {
+            let shared = scope.with_shared(&total);
+            for i in 0..10 {
+                shared.execute(move |total| {
+                    total.fetch_add(i, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert_eq!(total.load(Ordering::SeqCst), 45);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_task_context() {
+        use std::cell::Cell;
+
+        thread_local! {
+            // Stands in for something like the current tracing span.
+            static CONTEXT: Cell<usize> = const { Cell::new(0) };
+        }
+
+        let pool = PoolBuilder::new()
+            .size(2)
+            .task_context(Box::new(|| {
+                let context = CONTEXT.with(Cell::get);
+                Box::new(move |job| {
+                    let previous = CONTEXT.with(|c| c.replace(context));
+                    job();
+                    CONTEXT.with(|c| c.set(previous));
+                })
+            }))
+            .build();
+
+        let seen = Mutex::new(Vec::new());
+        pool.scoped(|scope| {
+            for context in 1..4 {
+                CONTEXT.with(|c| c.set(context));
+                let seen = &seen;
+                scope.execute(move || seen.lock().unwrap().push(CONTEXT.with(Cell::get)));
+            }
+        });
+
+        let mut seen = seen.into_inner().unwrap();
+        seen.sort();
+        assert_eq!(seen, vec![1, 2, 3]);
+        pool.shutdown();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_spawn_future() {
+        let pool = Pool::new(2);
+        let (tx, rx) = crossbeam::channel::unbounded();
+
+        for i in 0..3 {
+            let tx = tx.clone();
+            pool.spawn_future(async move { tx.send(i).unwrap() });
+        }
+
+        let mut received = rx.iter().take(3).collect::<Vec<_>>();
+        received.sort();
+        assert_eq!(received, vec![0, 1, 2]);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_zoom_deferred() {
+        let pool = Pool::new(2);
+        let (first, second) = (AtomicUsize::new(0), AtomicUsize::new(0));
+
+        pool.scoped(|scope| {
+            let mut stages = scope.zoom_deferred(|stage| {
+                for _ in 0..5 {
+                    stage.execute(|| {
+                        first.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            });
+
+            stages.merge(scope.zoom_deferred(|stage| {
+                for _ in 0..5 {
+                    stage.execute(|| {
+                        second.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            }));
+
+            stages.join_all();
+            assert_eq!(first.load(Ordering::SeqCst), 5);
+            assert_eq!(second.load(Ordering::SeqCst), 5);
+        });
+
+        // Leaking the guard still leaves the parent scope waiting.
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let done = AtomicBool::new(false);
+        pool.scoped(|scope| {
+            mem::forget(scope.zoom_deferred(|stage| {
+                stage.execute(|| {
+                    rx.recv().unwrap();
+                    done.store(true, Ordering::SeqCst);
+                })
+            }));
+            tx.send(()).unwrap();
+        });
+        assert!(done.load(Ordering::SeqCst));
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_ordered_start() {
+        let pool = PoolBuilder::new().size(1).ordered_start(true).build();
+        let order = Mutex::new(Vec::new());
+
+        // Unlike test_lifo_local_queue, jobs from a worker stay in order.
+        pool.scoped(|scope| {
+            scope.recurse(|scope| {
+                for i in 0..3 {
+                    let order = &order;
+                    scope.execute(move || order.lock().unwrap().push(i));
+                }
+            });
+        });
+
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_ordered_start_workers() {
+        use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+
+        let pool = PoolBuilder::new().size(3).ordered_start(true).build();
+        let (started_tx, started) = unbounded();
+        let (mut gates, waits): (Vec<Sender<()>>, Vec<Receiver<()>>) =
+            (0..8).map(|_| bounded(0)).unzip();
+
+        // Start a job which says so, then holds its worker until its gate
+        // opens.
+        let hold = |scope: &Scope<'_>, id: Option<usize>, wait: Receiver<()>| {
+            let started_tx = started_tx.clone();
+            scope.execute(move || {
+                started_tx.send(id).unwrap();
+                let _ = wait.recv();
+            });
+        };
+
+        let order = pool.scoped(|scope| {
+            for wait in &waits[..2] {
+                hold(scope, None, wait.clone());
+            }
+            assert_eq!(started.recv().unwrap(), None);
+            assert_eq!(started.recv().unwrap(), None);
+
+            // The last worker queues jobs from inside the pool, then takes
+            // the first of them.
+            let waits = &waits;
+            scope.recurse(move |scope| {
+                for i in 0..6 {
+                    hold(scope, Some(i), waits[i + 2].clone());
+                }
+            });
+
+            // Free one worker at a time, each taking the next job.
+            let mut order = vec![started.recv().unwrap()];
+            for gate in &gates[..5] {
+                gate.send(()).unwrap();
+                order.push(started.recv().unwrap());
+            }
+
+            gates.clear();
+            order
+        });
+
+        assert_eq!(order, (0..6).map(Some).collect::<Vec<_>>());
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_on_idle() {
+        let pool = Pool::new(2);
+        let (tx, rx) = crossbeam::channel::unbounded();
+        pool.on_idle(Box::new(move || tx.send(()).unwrap()));
+
+        for _ in 0..3 {
+            // Hold the pool busy until every job is queued.
+            let (gate, wait) = crossbeam::channel::unbounded::<()>();
+            pool.scoped(|scope| {
+                scope.execute(move || wait.recv().unwrap());
+                for _ in 0..10 {
+                    scope.execute(|| {});
+                }
+                gate.send(()).unwrap();
+            });
+
+            // One notification per busy period.
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert!(rx.try_recv().is_err());
+        }
+
+        // Discarded jobs count towards idleness too.
+        let empty = Pool::empty();
+        let idle = Arc::new(AtomicUsize::new(0));
+        let counter = idle.clone();
+        empty.on_idle(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        empty.spawn(|| {});
+        empty.clear_queue();
+        assert_eq!(idle.load(Ordering::SeqCst), 1);
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_execute_retry() {
+        let pool = Pool::new(2);
+        let attempts = AtomicUsize::new(0);
+
+        // Succeeds on the third attempt.
+        pool.scoped(|scope| {
+            scope.execute_retry(5, || {
+                if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    panic!("transient");
+                }
+            });
+        });
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // Never succeeds, so the scope is poisoned after every attempt.
+        attempts.store(0, Ordering::SeqCst);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| {
+                scope.execute_retry(3, || {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    panic!("permanent");
+                });
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_execute_owned() {
+        let pool = Pool::new(2);
+
+        pool.scoped(|scope| {
+            let handles = (0..4)
+                .map(|i| scope.execute_owned(vec![i; 3], |v| v.iter().sum::<i32>()))
+                .collect::<Vec<_>>();
+
+            let sums = handles.into_iter().map(|h| h.join()).collect::<Vec<_>>();
+            assert_eq!(sums, vec![0, 3, 6, 9]);
+        });
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| {
+                let handle = scope.execute_owned((), |()| -> u8 { panic!() });
+                let joined = panic::catch_unwind(AssertUnwindSafe(|| handle.join()));
+                assert!(joined.is_err());
+            })
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_shutdown_with() {
+        // Queue jobs behind one which blocks the only worker.
+        fn blocked_pool(jobs: usize) -> (Pool, crossbeam::channel::Sender<()>) {
+            let pool = Pool::new(1);
+            let (tx, rx) = crossbeam::channel::unbounded();
+            let (started_tx, started_rx) = crossbeam::channel::unbounded();
+
+            pool.spawn(move || {
+                started_tx.send(()).unwrap();
+                rx.recv().unwrap();
+            });
+            started_rx.recv().unwrap();
+
+            for _ in 0..jobs {
+                pool.spawn(|| {});
+            }
+
+            (pool, tx)
+        }
+
+        // Release the blocking job once the shutdown has started.
+        fn release_later(release: crossbeam::channel::Sender<()>) -> thread::JoinHandle<()> {
+            thread::spawn(move || {
+                sleep(Duration::from_millis(50));
+                release.send(()).unwrap();
+            })
+        }
+
+        let (pool, release) = blocked_pool(5);
+        let releaser = release_later(release);
+        let summary = pool.shutdown_with(ShutdownPolicy::Drain);
+        assert_eq!((summary.ran(), summary.dropped()), (6, 0));
+        releaser.join().unwrap();
+
+        let (pool, release) = blocked_pool(5);
+        let releaser = release_later(release);
+        let summary = pool.shutdown_with(ShutdownPolicy::Immediate);
+        assert_eq!((summary.ran(), summary.dropped()), (1, 5));
+        assert!(pool.is_spawned_work_complete());
+        releaser.join().unwrap();
+
+        // The blocked job outlasts the timeout, so the queue is discarded.
+        let (pool, release) = blocked_pool(5);
+        let releaser = release_later(release);
+        let summary = pool.shutdown_with(ShutdownPolicy::DrainTimeout(Duration::from_millis(10)));
+        assert_eq!((summary.ran(), summary.dropped()), (1, 5));
+        assert!(pool.is_spawned_work_complete());
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn test_scope_locals() {
+        struct Config(usize);
+
+        let pool = Pool::new(2);
+        let total = AtomicUsize::new(0);
+
+        pool.scoped(|scope| {
+            assert!(scope.get::<Config>().is_none());
+            assert!(scope.insert(Config(3)).is_ok());
+            assert!(scope.insert(Config(4)).is_err());
+
+            // Visible from recursed jobs and zoomed subscopes.
+            scope.recurse(|scope| {
+                let step = scope.get::<Config>().unwrap().0;
+                total.fetch_add(step, Ordering::SeqCst);
+            });
+            scope.zoom(|inner| {
+                assert_eq!(inner.get::<Config>().unwrap().0, 3);
+                assert!(inner.insert(0usize).is_ok());
+            });
+            assert!(scope.get::<usize>().is_none());
+        });
+
+        assert_eq!(total.load(Ordering::SeqCst), 3);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_contract() {
+        let pool = Pool::new(3);
+
+        pool.contract();
+        pool.contract();
+        while pool.workers() > 1 {
+            thread::yield_now();
+        }
+
+        // The remaining worker still runs jobs.
+        let counter = AtomicUsize::new(0);
+        pool.scoped(|scope| {
+            for _ in 0..10 {
+                scope.execute(|| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(counter.load(Ordering::SeqCst), 10);
+
+        pool.shutdown();
+        assert_eq!(pool.workers(), 0);
+    }
+
+    #[test]
+    fn test_autoscale() {
+        let pool = PoolBuilder::new()
+            .autoscale(Autoscale::new(1, 4).interval(Duration::from_millis(5)))
+            .build();
+        assert_eq!(pool.workers(), 1);
+
+        // A burst of slow jobs grows the pool.
+        for _ in 0..40 {
+            pool.spawn(|| sleep(Duration::from_millis(5)));
+        }
+        let start = Instant::now();
+        while pool.workers() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(10), "pool never grew");
+            sleep(Duration::from_millis(1));
+        }
+
+        // Once idle it shrinks back to the minimum.
+        let start = Instant::now();
+        while !pool.is_spawned_work_complete() || pool.workers() > 1 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "pool never shrank"
+            );
+            sleep(Duration::from_millis(1));
+        }
+
+        pool.shutdown();
+        assert_eq!(pool.workers(), 0);
+    }
+
+    #[test]
+    fn test_join_interruptible() {
+        let pool = Pool::new(2);
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+        let interrupt = AtomicBool::new(false);
+
+        pool.scoped(|scope| {
+            scope.execute(move || rx.recv().unwrap());
+
+            thread::scope(|s| {
+                s.spawn(|| {
+                    sleep(Duration::from_millis(10));
+                    interrupt.store(true, Ordering::SeqCst);
+                });
+                assert!(!scope.join_interruptible(&interrupt));
+            });
+
+            // Still accounted for, so a full join waits for it.
+            tx.send(()).unwrap();
+            interrupt.store(false, Ordering::SeqCst);
+            assert!(scope.join_interruptible(&interrupt));
+        });
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_execute_on_pool() {
+        let cpu = PoolBuilder::new()
+            .size(1)
+            .thread_config(ThreadConfig::new().prefix("cpu-"))
+            .build();
+        let io = PoolBuilder::new()
+            .size(1)
+            .thread_config(ThreadConfig::new().prefix("io-"))
+            .build();
+
+        let name = Mutex::new(None);
+        cpu.scoped(|scope| {
+            scope.execute_on_pool(&io, || {
+                sleep(Duration::from_millis(10));
+                *name.lock().unwrap() = thread::current().name().map(String::from);
+            });
+        });
+
+        // Joining the cpu scope waited for the job on the io pool.
+        assert_eq!(name.into_inner().unwrap().as_deref(), Some("io-1"));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            cpu.scoped(|scope| scope.execute_on_pool(&io, || panic!()));
+        }));
+        assert!(result.is_err());
+        cpu.shutdown();
+    }
+
+    #[test]
+    fn test_max_scope_depth() {
+        let pool = PoolBuilder::new().size(2).max_scope_depth(3).build();
+
+        fn nest(scope: &Scope, levels: usize) {
+            if levels > 0 {
+                scope.zoom(|inner| nest(inner, levels - 1));
+            }
+        }
+
+        // The scope from scoped is the first level.
+        pool.scoped(|scope| nest(scope, 2));
+
+        let error = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| nest(scope, 3));
+        }))
+        .unwrap_err();
+        let message = error.downcast::<String>().unwrap();
+        assert!(message.contains("max_scope_depth of 3"), "{}", message);
+
+        // Unbounded recursion is caught in the job, poisoning the scope.
+        fn recurse_forever(scope: &Scope) {
+            scope.recurse(recurse_forever);
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| recurse_forever(scope));
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_wait_group_generation() {
+        let wait = Arc::new(WaitGroup::new());
+        assert_eq!(wait.generation(), 0);
+
+        thread::scope(|s| {
+            // Three phases, each of two workers.
+            s.spawn(|| {
+                for round in 1..=3 {
+                    wait.submit();
+                    wait.submit();
+                    thread::scope(|phase| {
+                        for _ in 0..2 {
+                            phase.spawn(|| {
+                                sleep(Duration::from_millis(5));
+                                wait.complete();
+                            });
+                        }
+                    });
+                    assert!(wait.generation() >= round);
+                }
+            });
+
+            wait.wait_for_generation(3);
+            assert_eq!(wait.generation(), 3);
+        });
+    }
+
+    #[test]
+    fn test_scope_guard() {
+        let pool = Pool::new(2);
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        {
+            let scope = pool.scope_guard();
+            for _ in 0..10 {
+                let counter = counter.clone();
+                scope.execute(move || {
+                    sleep(Duration::from_millis(1));
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+
+        // Dropping the guard joined every job.
+        assert_eq!(counter.load(Ordering::SeqCst), 10);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_configured_workers() {
+        let pool = Pool::new(3);
+        assert_eq!(pool.configured_workers(), 3);
+
+        pool.expand();
+        pool.contract();
+        pool.contract();
+        assert_eq!(pool.configured_workers(), 2);
+
+        // A panicking job restarts its thread, leaving the target alone.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| scope.execute(|| panic!()));
+        }));
+        assert_eq!(pool.configured_workers(), 2);
+
+        while pool.workers() != 2 {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_recycled_wait_group_is_clean() {
+        let pool = Pool::new(1);
+
+        let poisoned = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| {
+                scope.insert(7usize).unwrap();
+                scope.execute(|| panic!("Job panic"));
+            })
+        }));
+        assert!(poisoned.is_err());
+
+        // The next scope reuses the group, with no leftover state.
+        pool.scoped(|scope| {
+            assert_eq!(scope.wait.generation(), 0);
+            assert!(scope.get::<usize>().is_none());
+            scope.execute(|| ());
+        });
+    }
+
+    #[test]
+    fn test_execute_finally() {
+        let pool = Pool::new(2);
+        let done = AtomicUsize::new(0);
+        let seen = AtomicUsize::new(usize::MAX);
+
+        pool.scoped(|scope| {
+            scope.execute_finally(|| seen.store(done.load(Ordering::SeqCst), Ordering::SeqCst));
+            for _ in 0..8 {
+                scope.execute(|| {
+                    thread::sleep(Duration::from_millis(2));
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(seen.load(Ordering::SeqCst), 8);
+
+        // Still runs when the scheduler panics part way through.
+        let ran = AtomicBool::new(false);
+        let result = pool.try_scoped(|scope| {
+            scope.execute_finally(|| ran.store(true, Ordering::SeqCst));
+            scope.execute(|| ());
+            panic!("Scheduler panic");
+        });
+        assert!(result.is_err());
+        assert!(ran.load(Ordering::SeqCst));
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_execute_finally_join_timeout() {
+        use crossbeam::channel::unbounded;
+
+        let pool = Pool::new(2);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (release_job, job_released) = unbounded::<()>();
+        let (release_finally, finally_released) = unbounded::<()>();
+
+        let scope = Scope::forever(pool.clone());
+        scope.execute(move || {
+            let _ = job_released.recv();
+        });
+        let finally_log = log.clone();
+        scope.execute_finally(move || {
+            let _ = finally_released.recv();
+            finally_log.lock().unwrap().push("finally");
+        });
+        let complete_log = log.clone();
+        scope.on_complete(move |clean| {
+            assert!(clean);
+            complete_log.lock().unwrap().push("complete");
+        });
+        let cleanup_log = log.clone();
+        scope.defer(move || cleanup_log.lock().unwrap().push("cleanup"));
+
+        // Neither the job nor the held back job has run.
+        assert!(!scope.join_timeout(Duration::from_millis(10)));
+
+        // The job is done, and the held back job released but still running.
+        drop(release_job);
+        assert!(!scope.join_timeout(Duration::from_millis(10)));
+        assert!(log.lock().unwrap().is_empty());
+
+        drop(release_finally);
+        assert!(scope.join_timeout(Duration::from_secs(10)));
+        assert_eq!(*log.lock().unwrap(), vec!["finally", "complete", "cleanup"]);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_scope_belongs_to() {
+        let pool = Pool::new(2);
+        let other = Pool::new(1);
+
+        pool.scoped(|scope| {
+            assert!(scope.belongs_to(&pool));
+            assert!(scope.belongs_to(&pool.clone()));
+            assert!(!scope.belongs_to(&other));
+            assert_eq!(scope.pool().workers(), 2);
+
+            scope.execute_on_pool(&other, || ());
+        });
+
+        pool.shutdown();
+        other.shutdown();
+    }
+
+    #[test]
+    fn test_cancel_pending() {
+        let pool = Pool::new(1);
+        let (gate_tx, gate_rx) = crossbeam::channel::unbounded::<()>();
+        let started = AtomicBool::new(false);
+        let ran = AtomicUsize::new(0);
+
+        pool.scoped(|scope| {
+            // Hold the only worker so everything else stays queued.
+            scope.execute(|| {
+                started.store(true, Ordering::SeqCst);
+                let _ = gate_rx.recv();
+            });
+            while !started.load(Ordering::SeqCst) {
+                thread::yield_now();
+            }
+            for _ in 0..5 {
+                scope.execute(|| {
+                    ran.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+
+            let other = pool.scope_guard();
+            other.execute(|| ());
+
+            assert_eq!(scope.cancel_pending(), 5);
+            assert_eq!(pool.queued_jobs(), 1);
+            drop(gate_tx);
+            drop(other);
+        });
+
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+        assert_eq!(pool.queued_jobs(), 0);
+        pool.shutdown();
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn test_trace_record_and_replay() {
+        fn run(pool: &Pool) -> Vec<usize> {
+            let log = Mutex::new(Vec::new());
+            pool.scoped(|scope| {
+                for i in 0..6 {
+                    let log = &log;
+                    scope.execute(move || log.lock().unwrap().push(i));
+                }
+            });
+            log.into_inner().unwrap()
+        }
+
+        let pool = PoolBuilder::new().size(3).record_trace(true).build();
+        let ran = run(&pool);
+        let trace = pool.take_trace().unwrap();
+        assert_eq!(trace.order().len(), 6);
+        assert_eq!(pool.take_trace().unwrap().order().len(), 0);
+        pool.shutdown();
+
+        // Replaying runs jobs in the trace's order, whatever the size.
+        let replay = PoolBuilder::new()
+            .size(4)
+            .replay_trace(trace.clone())
+            .build();
+        assert_eq!(replay.workers(), 1);
+        let order: Vec<usize> = trace.order().iter().map(|&seq| seq as usize).collect();
+        assert_eq!(run(&replay), order);
+        assert_eq!(ran.len(), order.len());
+        replay.shutdown();
+
+        let reversed = crate::Trace::from(vec![5, 4, 3, 2, 1, 0]);
+        let replay = PoolBuilder::new().replay_trace(reversed).build();
+        assert_eq!(run(&replay), [5, 4, 3, 2, 1, 0]);
+        // Once the trace runs out, jobs go in submission order.
+        assert_eq!(run(&replay), [0, 1, 2, 3, 4, 5]);
+        replay.shutdown();
+    }
+
+    #[test]
+    fn test_scoped_timed() {
+        let pool = Pool::new(2);
+
+        let (result, stats) = pool.scoped_timed(|scope| {
+            for i in 0..4 {
+                scope.execute(move || sleep(Duration::from_millis(5 * i)));
+            }
+            scope.zoom(|inner| inner.execute(|| ()));
+            7
+        });
+
+        assert_eq!(result, 7);
+        assert_eq!(stats.tasks(), 4);
+        assert!(stats.min() <= stats.mean() && stats.mean() <= stats.max());
+        assert!(stats.max() >= Duration::from_millis(15));
+        assert!(stats.wall() >= stats.max());
+
+        let (_, empty) = pool.scoped_timed(|_| ());
+        assert_eq!((empty.tasks(), empty.max()), (0, Duration::ZERO));
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_broadcast() {
+        use std::cell::Cell;
+        use std::collections::HashSet;
+
+        thread_local!(static TURNS: Cell<usize> = const { Cell::new(0) });
+
+        let pool = Pool::new(4);
+        let threads = Mutex::new(HashSet::new());
+
+        pool.broadcast(|| {
+            TURNS.with(|turns| turns.set(turns.get() + 1));
+            threads.lock().unwrap().insert(thread::current().id());
+        });
+        assert_eq!(threads.lock().unwrap().len(), 4);
+
+        // Every worker got exactly one turn, including from inside a job.
+        pool.scoped(|scope| {
+            scope.execute(|| pool.broadcast(|| TURNS.with(|turns| turns.set(turns.get() + 1))));
+        });
+        pool.broadcast(|| assert_eq!(TURNS.with(Cell::get), 2));
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_broadcast_contract() {
+        use std::sync::Barrier;
+
+        let pool = Pool::new(4);
+        let started = Barrier::new(5);
+        let (release, released) = crossbeam::channel::unbounded::<()>();
+        let turns = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            // Hold every worker until the broadcast is queued.
+            let busy = s.spawn(|| {
+                pool.scoped(|scope| {
+                    for _ in 0..4 {
+                        scope.execute(|| {
+                            started.wait();
+                            let _ = released.recv();
+                        });
+                    }
+                })
+            });
+            started.wait();
+
+            // All four are counted in, but one retires before its turn.
+            pool.contract();
+            let broadcast = s.spawn(|| {
+                pool.broadcast(|| {
+                    turns.fetch_add(1, Ordering::SeqCst);
+                })
+            });
+            while pool.queued_jobs() < 4 {
+                thread::yield_now();
+            }
+
+            drop(release);
+            broadcast.join().unwrap();
+            busy.join().unwrap();
+        });
+
+        assert_eq!(turns.load(Ordering::SeqCst), 3);
+        assert_eq!(pool.workers(), 3);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_spawn_urgent() {
+        for ordered in [false, true] {
+            let pool = PoolBuilder::new().size(1).ordered_start(ordered).build();
+            let (gate_tx, gate_rx) = crossbeam::channel::unbounded::<()>();
+            let (tx, rx) = crossbeam::channel::unbounded();
+
+            // Hold the worker while the queue fills up.
+            pool.spawn(move || gate_rx.recv().unwrap());
+            for i in 0..3 {
+                let tx = tx.clone();
+                pool.spawn(move || tx.send(i).unwrap());
+            }
+            for i in 10..13 {
+                let tx = tx.clone();
+                pool.spawn_urgent(move || tx.send(i).unwrap());
+            }
+
+            gate_tx.send(()).unwrap();
+            pool.shutdown();
+            drop(tx);
+
+            let order: Vec<i32> = rx.iter().collect();
+            assert_eq!(order, [10, 11, 12, 0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn test_wait_ready() {
+        let pool = Pool::new(3);
+        pool.wait_ready(3);
+        assert_eq!(pool.inner.workers.lock().unwrap().len(), 3);
+
+        pool.expand();
+        pool.wait_ready(4);
+        assert_eq!(pool.inner.workers.lock().unwrap().len(), 4);
+
+        // Already satisfied.
+        pool.wait_ready(0);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_scope_merge() {
+        let pool = Pool::new(2);
+        let other = Pool::new(1);
+        let done = AtomicUsize::new(0);
+
+        pool.scoped(|left| {
+            other.scoped(|right| {
+                left.execute(|| {
+                    sleep(Duration::from_millis(10));
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+                right.execute(|| {
+                    sleep(Duration::from_millis(10));
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+
+                left.merge(right).join();
+                assert_eq!(done.load(Ordering::SeqCst), 2);
+            });
+        });
+
+        // Both sides are waited for before a panic propogates.
+        let slow_done = Arc::new(AtomicBool::new(false));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let left = pool.scope_guard();
+            let right = pool.scope_guard();
+            left.execute(|| panic!("Job panic"));
+            let slow_done = slow_done.clone();
+            right.execute(move || {
+                sleep(Duration::from_millis(10));
+                slow_done.store(true, Ordering::SeqCst);
+            });
+            left.merge(&right).join();
+        }));
+        assert!(result.is_err());
+        assert!(slow_done.load(Ordering::SeqCst));
+        other.shutdown();
+    }
+
+    #[test]
+    fn test_boosted() {
+        let pool = Pool::new(1);
+
+        let workers = pool.boosted(2, || {
+            assert_eq!(pool.configured_workers(), 3);
+            pool.workers()
+        });
+        assert_eq!(workers, 3);
+        assert_eq!(pool.configured_workers(), 1);
+
+        // Balanced even when `f` panics.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| pool.boosted(1, || panic!("Boost"))));
+        assert!(result.is_err());
+        assert_eq!(pool.configured_workers(), 1);
+
+        // The extra threads retire.
+        pool.wait_workers(1);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_abort_handle() {
+        let pool = Pool::new(2);
+        let started = AtomicUsize::new(0);
+        let found = AtomicUsize::new(usize::MAX);
+
+        pool.scoped(|scope| {
+            for i in 0..1000 {
+                let abort = scope.abort_handle();
+                let (started, found) = (&started, &found);
+                scope.execute(move || {
+                    // Running jobs see the flag cooperatively.
+                    if abort.is_aborted() {
+                        return;
+                    }
+
+                    started.fetch_add(1, Ordering::SeqCst);
+                    if i == 10 {
+                        found.store(i, Ordering::SeqCst);
+                        abort.abort();
+                    } else {
+                        sleep(Duration::from_millis(1));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(found.load(Ordering::SeqCst), 10);
+        assert!(started.load(Ordering::SeqCst) < 1000);
+
+        // Other scopes are unaffected.
+        pool.scoped(|scope| scope.execute(|| started.store(0, Ordering::SeqCst)));
+        assert_eq!(started.load(Ordering::SeqCst), 0);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_detached_panic_policy() {
+        let pool = PoolBuilder::new()
+            .size(1)
+            .detached_panic_policy(DetachedPanicPolicy::CatchAndLog)
+            .build();
+        let (tx, rx) = crossbeam::channel::unbounded();
+
+        pool.spawn(|| panic!("Detached panic"));
+        pool.spawn(move || tx.send(thread::current().id()).unwrap());
+        rx.recv().unwrap();
+
+        // Nothing was restarted or poisoned.
+        assert_eq!(pool.inner.thread_counter.load(Ordering::SeqCst), 2);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_map_into() {
+        use std::collections::VecDeque;
+
+        let pool = Pool::new(3);
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let mut out = VecDeque::from([usize::MAX]);
+
+        pool.scoped(|scope| {
+            scope.map_into(0..50, &mut out, 4, |i| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                sleep(Duration::from_millis(1));
+                running.fetch_sub(1, Ordering::SeqCst);
+                i * 2
+            });
+        });
+
+        assert!(peak.load(Ordering::SeqCst) <= 4);
+        assert_eq!(out.pop_front(), Some(usize::MAX));
+        let mut results: Vec<usize> = out.into_iter().collect();
+        results.sort();
+        assert_eq!(results, (0..50).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_map_bounded() {
+        let pool = Pool::new(3);
+        let started = AtomicUsize::new(0);
+
+        pool.scoped(|scope| {
+            let mut taken = 0;
+            let mut results: Vec<usize> = scope
+                .map_bounded(0..50, 4, |i| {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    i * 2
+                })
+                .inspect(|_| {
+                    taken += 1;
+                    // Producers never get more than the bound ahead.
+                    assert!(started.load(Ordering::SeqCst) <= taken + 4);
+                    sleep(Duration::from_millis(1));
+                })
+                .collect();
+
+            results.sort();
+            assert_eq!(results, (0..50).map(|i| i * 2).collect::<Vec<_>>());
+        });
+
+        // Dropping the consumer early stops scheduling.
+        started.store(0, Ordering::SeqCst);
+        pool.scoped(|scope| {
+            let first: Vec<usize> = scope
+                .map_bounded(0..1000, 2, |i| {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    i
+                })
+                .take(3)
+                .collect();
+            assert_eq!(first.len(), 3);
+        });
+        assert!(started.load(Ordering::SeqCst) <= 5);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_idle_strategy() {
+        use crate::StealBackoff;
+
+        let backoff = StealBackoff::Exponential { max_pause: 16 };
+        for (strategy, backoff) in [
+            (IdleStrategy::Park, StealBackoff::Constant),
+            (IdleStrategy::Yield(100), StealBackoff::Constant),
+            (IdleStrategy::Spin(100), StealBackoff::Constant),
+            (IdleStrategy::Yield(100), backoff),
+            (IdleStrategy::Spin(100), backoff),
+        ] {
+            let pool = PoolBuilder::new()
+                .size(2)
+                .idle_strategy(strategy)
+                .steal_backoff(backoff)
+                .build();
+            let counter = AtomicUsize::new(0);
+
+            for _ in 0..5 {
+                pool.scoped(|scope| {
+                    for _ in 0..10 {
+                        scope.execute(|| {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                        });
+                    }
+                });
+                sleep(Duration::from_millis(1));
+            }
+
+            assert_eq!(counter.load(Ordering::SeqCst), 50);
+            pool.shutdown();
+        }
+    }
+
+    #[test]
+    fn test_scoped_reuse() {
+        let pool = Pool::new(2);
+        let group = Arc::new(WaitGroup::new());
+        let mut total = 0;
+
+        for i in 0..10 {
+            let mut value = 0;
+            pool.scoped_reuse(&group, |scope| scope.execute(|| value = i));
+            total += value;
+            assert_eq!(group.waiting(), 0);
+        }
+
+        assert_eq!(total, 45);
+        assert_eq!(group.generation(), 10);
+        pool.shutdown();
+    }
+
+    #[cfg(feature = "remote")]
+    #[test]
+    fn test_spawn_remote() {
+        use crate::{LoopbackTransport, SerializableTask, TaskRegistry};
+
+        static TOTAL: AtomicUsize = AtomicUsize::new(0);
+
+        struct Add(u32);
+
+        impl SerializableTask for Add {
+            const NAME: &'static str = "add";
+
+            fn serialize(&self) -> Vec<u8> {
+                self.0.to_le_bytes().to_vec()
+            }
+
+            fn deserialize(bytes: &[u8]) -> io::Result<Add> {
+                let bytes = bytes.try_into().map_err(|_| io::ErrorKind::InvalidData)?;
+                Ok(Add(u32::from_le_bytes(bytes)))
+            }
+
+            fn run(self) {
+                TOTAL.fetch_add(self.0 as usize, Ordering::SeqCst);
+            }
+        }
+
+        let (transport, receiver) = LoopbackTransport::new();
+        let pool = PoolBuilder::new()
+            .size(1)
+            .remote_transport(Box::new(transport))
+            .build();
+
+        pool.spawn_remote(Add(3));
+        pool.spawn_remote(Add(4));
+        assert_eq!(TOTAL.load(Ordering::SeqCst), 0);
+
+        // The other side decodes and runs them on its own pool.
+        let mut registry = TaskRegistry::new();
+        registry.register::<Add>();
+        let remote = Pool::new(1);
+        while let Some(task) = receiver.try_recv() {
+            remote.spawn(registry.decode(&task).unwrap());
+        }
+        remote.shutdown();
+        assert_eq!(TOTAL.load(Ordering::SeqCst), 7);
+
+        let unknown = crate::EncodedTask::new("sub".to_string(), Vec::new());
+        assert!(registry.decode(&unknown).is_err());
+
+        // Without a transport, or when it's gone, tasks run locally.
+        let local = Pool::new(1);
+        local.spawn_remote(Add(1));
+        local.shutdown();
+        assert_eq!(TOTAL.load(Ordering::SeqCst), 8);
+
+        drop(receiver);
+        pool.spawn_remote(Add(10));
+        pool.shutdown();
+        assert_eq!(TOTAL.load(Ordering::SeqCst), 18);
+    }
+
+    #[test]
+    fn test_then() {
+        let pool = Pool::new(4);
+        let input = [3, 1, 2];
+        let mut out = [0; 3];
+
+        pool.scoped(|scope| {
+            scope.then(
+                || {
+                    let mut sorted = input;
+                    sorted.sort();
+                    sorted
+                },
+                |scope, sorted| {
+                    for (slot, value) in out.iter_mut().zip(sorted) {
+                        scope.execute(move || *slot = value * 10);
+                    }
+                },
+            );
+        });
+
+        assert_eq!(out, [10, 20, 30]);
+    }
+
+    #[test]
+    fn test_current_jobs() {
+        let pool = Pool::new(2);
+        pool.wait_ready(2);
+        assert_eq!(pool.current_jobs(), vec![None, None]);
+
+        let (started_tx, started_rx) = crossbeam::channel::unbounded();
+        let (release_tx, release_rx) = crossbeam::channel::unbounded::<()>();
+
+        pool.scoped(|scope| {
+            scope.execute_labeled("compact", || {
+                started_tx.send(()).unwrap();
+                let _ = release_rx.recv();
+            });
+
+            started_rx.recv().unwrap();
+            let mut current = pool.current_jobs();
+            current.sort();
+            assert_eq!(current, vec![None, Some("compact")]);
+
+            release_tx.send(()).unwrap();
+        });
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_map_auto() {
+        use crate::auto_split;
+
+        assert_eq!(auto_split(100, 4), 4);
+        assert_eq!(auto_split(3, 4), 3);
+        assert_eq!(auto_split(0, 4), 1);
+        assert_eq!(auto_split(10, 0), 1);
+
+        let pool = Pool::new(3);
+        let items: Vec<usize> = (0..10).collect();
+
+        pool.scoped(|scope| {
+            let expected: Vec<usize> = items.iter().map(|x| x * 2).collect();
+            assert_eq!(scope.map_auto(&items, |x| x * 2), expected);
+
+            for jobs in 1..12 {
+                assert_eq!(scope.map_split(&items, jobs, |x| x * 2), expected);
+            }
+
+            assert!(scope.map_auto(&[] as &[usize], |x| *x).is_empty());
+        });
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_schedule_after_shutdown() {
+        let pool = Pool::new(2);
+        let scope = Scope::forever(pool.clone());
+        assert!(!pool.is_shutdown());
+        pool.shutdown();
+        assert!(pool.is_shutdown());
+
+        assert!(pool.try_scoped(|_| ()).is_err());
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| pool.scoped(|_| ()))).is_err());
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| pool.spawn(|| ()))).is_err());
+
+        // A scope opened before the shutdown outlived the workers.
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| scope.execute(|| ()))).is_err());
+    }
+
+    #[cfg(feature = "scope-tree")]
+    #[test]
+    fn test_pending_tree() {
+        let pool = Pool::new(2);
+        let (release_tx, release_rx) = crossbeam::channel::unbounded::<()>();
+
+        pool.scoped(|outer| {
+            assert_eq!(outer.pending_tree().total(), 0);
+
+            outer.execute(|| {
+                let _ = release_rx.recv();
+            });
+
+            outer.zoom(|inner| {
+                let tree = outer.pending_tree();
+                assert_eq!(tree.pending(), 1);
+                assert_eq!(tree.children().len(), 1);
+                assert_eq!(tree.children()[0].pending(), 0);
+
+                inner.execute(|| sleep(Duration::from_millis(50)));
+                assert_eq!(outer.pending_tree().total(), 2);
+            });
+
+            // The subscope is gone once zoom returns.
+            assert!(outer.pending_tree().children().is_empty());
+            release_tx.send(()).unwrap();
+        });
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_auto_shutdown_after() {
+        let pool = PoolBuilder::new()
+            .size(2)
+            .auto_shutdown_after(Duration::from_millis(20))
+            .build();
+
+        let counter = AtomicUsize::new(0);
+        pool.scoped(|scope| {
+            for _ in 0..10 {
+                scope.execute(|| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(counter.load(Ordering::SeqCst), 10);
+
+        assert!(
+            pool.wait_workers_timeout(0, Duration::from_secs(10)),
+            "the idle pool never shut down"
+        );
+        assert!(pool.is_shutdown());
+
+        // An explicit shutdown afterwards is harmless.
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_execute_watchdog() {
+        let pool = Pool::new(2);
+
+        pool.scoped(|scope| {
+            let slow = scope.execute_watchdog(Duration::from_millis(20), |expired| {
+                while !expired.load(Ordering::SeqCst) {
+                    sleep(Duration::from_millis(1));
+                }
+            });
+            let quick = scope.execute_watchdog(Duration::from_secs(60), |_| {});
+            assert_eq!((slow, quick), (0, 1));
+
+            scope.join();
+            assert_eq!(scope.timed_out(), vec![slow]);
+        });
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_wait_group_no_missed_wakeups() {
+        // Joiners race against the last complete of each round, which only
+        // takes the lock when it sees a sleeper.
+        for _ in 0..200 {
+            let wait = Arc::new(WaitGroup::new());
+            wait.submit();
+
+            let joiners: Vec<_> = (0..3)
+                .map(|_| {
+                    let wait = wait.clone();
+                    thread::spawn(move || wait.join())
+                })
+                .collect();
+
+            let waiter = {
+                let wait = wait.clone();
+                thread::spawn(move || wait.wait_for_generation(1))
+            };
+
+            thread::yield_now();
+            wait.complete();
+
+            for joiner in joiners {
+                joiner.join().unwrap();
+            }
+            waiter.join().unwrap();
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_completion_and_progress() {
+        use crate::block_on;
+
+        let pool = Pool::new(2);
+        let scope = pool.scope_guard();
+        let (release_tx, release_rx) = crossbeam::channel::unbounded::<()>();
+
+        for _ in 0..4 {
+            let release_rx = release_rx.clone();
+            scope.execute(move || {
+                let _ = release_rx.recv();
+            });
+        }
+
+        // Dropping an unfinished future leaves the scope as it was.
+        drop(scope.completion());
+
+        let mut progress = scope.progress();
+        let releaser = thread::spawn(move || {
+            for _ in 0..4 {
+                sleep(Duration::from_millis(5));
+                release_tx.send(()).unwrap();
+            }
+        });
+
+        let counts = block_on(async {
+            let mut counts = Vec::new();
+            while let Some(done) = progress.next().await {
+                counts.push(done);
+            }
+            counts
+        });
+
+        assert_eq!(counts.last(), Some(&4));
+        assert!(counts.windows(2).all(|pair| pair[0] < pair[1]));
+
+        block_on(scope.completion());
+        releaser.join().unwrap();
+        drop(scope);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_limited() {
+        use crate::Semaphore;
+
+        let semaphore = Arc::new(Semaphore::new(2));
+        let (first, second) = (Pool::new(3), Pool::new(3));
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+
+        let job = || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            sleep(Duration::from_millis(2));
+            running.fetch_sub(1, Ordering::SeqCst);
+        };
+
+        first.scoped(|a| {
+            second.scoped(|b| {
+                let (a, b) = (a.limited(&semaphore), b.limited(&semaphore));
+                for _ in 0..10 {
+                    a.execute(job);
+                    b.execute(job);
+                }
+            });
+        });
+
+        assert!(most.load(Ordering::SeqCst) <= 2);
+        assert_eq!(semaphore.available(), 2);
+
+        // A panicking job gives its permit back.
+        let result = first.try_scoped(|scope| scope.limited(&semaphore).execute(|| panic!()));
+        assert!(result.is_err());
+        assert_eq!(semaphore.available(), 2);
+
+        assert!(semaphore.try_acquire().is_some());
+        let _held = (semaphore.acquire(), semaphore.acquire());
+        assert!(semaphore.try_acquire().is_none());
+    }
+
+    #[test]
+    fn test_is_overloaded() {
+        let pool = Pool::empty();
+        assert!(!pool.is_overloaded(0));
+
+        for _ in 0..3 {
+            pool.spawn(|| {});
+        }
+
+        assert!(pool.is_overloaded(2));
+        assert!(!pool.is_overloaded(3));
+
+        pool.expand();
+        pool.shutdown();
+        assert!(!pool.is_overloaded(0));
+    }
+
+    #[test]
+    fn test_par_iter() {
+        let pool = Pool::new(3);
+        let total = AtomicUsize::new(0);
+
+        pool.scoped(|scope| {
+            // A source which can't be indexed.
+            let numbers = (1..=100).filter(|n| n % 2 == 0);
+            scope.par_iter(numbers, |n| {
+                total.fetch_add(n, Ordering::SeqCst);
+            });
+            assert_eq!(total.load(Ordering::SeqCst), 2550);
+
+            scope.par_iter_batched(1..=100, 7, |n| {
+                total.fetch_add(n, Ordering::SeqCst);
+            });
+            assert_eq!(total.load(Ordering::SeqCst), 2550 + 5050);
+        });
+
+        // A panic stops the other jobs taking items.
+        let taken = AtomicUsize::new(0);
+        let result = pool.try_scoped(|scope| {
+            let items = (0..10_000).inspect(|_| {
+                taken.fetch_add(1, Ordering::SeqCst);
+            });
+            // Slow items, so the others can't all be taken while the
+            // panicking job is preempted.
+            scope.par_iter(items, |n| {
+                if n == 10 {
+                    panic!();
+                }
+                thread::sleep(Duration::from_micros(1));
+            });
+        });
+        assert!(result.is_err());
+        assert!(taken.load(Ordering::SeqCst) < 10_000);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_panic_behavior_abort() {
+        use crate::PanicBehavior;
+        use std::process::Command;
+
+        // Run the panic in a copy of this test binary, which it aborts.
+        if std::env::var_os("POOL_ABORT_CHILD").is_some() {
+            let pool = PoolBuilder::new()
+                .size(1)
+                .panic_behavior(PanicBehavior::Abort)
+                .build();
+            pool.scoped(|scope| scope.execute(|| panic!("expected")));
+            unreachable!("the panic should have aborted");
+        }
+
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "test::test_panic_behavior_abort", "--nocapture"])
+            .env("POOL_ABORT_CHILD", "1")
+            .output()
+            .unwrap();
+
+        // Killed by SIGABRT, rather than exiting with a test failure.
+        assert_eq!(output.status.code(), None);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("aborting"));
+    }
+
+    #[test]
+    fn test_execute_with_id() {
+        let pool = Pool::new(2);
+        let log = Mutex::new(Vec::new());
+
+        let ids = pool.scoped(|scope| {
+            let ids: Vec<_> = (0..4)
+                .map(|_| scope.execute_with_id(|id| log.lock().unwrap().push(id)))
+                .collect();
+
+            scope.recurse(|scope| {
+                assert_eq!(scope.execute_with_id(|_| {}).get(), 4);
+            });
+
+            ids
+        });
+
+        assert_eq!(
+            ids.iter().map(|id| id.get()).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+        assert_eq!(ids[2].to_string(), "task 2");
+
+        let mut log = log.into_inner().unwrap();
+        log.sort();
+        assert_eq!(log, ids);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_expand_if_busy() {
+        let pool = Pool::new(2);
+        pool.wait_ready(2);
+
+        // Nothing is running.
+        assert!(!pool.expand_if_busy(0.0));
+        assert_eq!(pool.workers(), 2);
+
+        let (started_tx, started_rx) = crossbeam::channel::unbounded();
+        let (release_tx, release_rx) = crossbeam::channel::unbounded::<()>();
+
+        pool.scoped(|scope| {
+            scope.execute(|| {
+                started_tx.send(()).unwrap();
+                let _ = release_rx.recv();
+            });
+            started_rx.recv().unwrap();
+
+            // Half the threads are busy.
+            assert!(!pool.expand_if_busy(0.5));
+            assert!(pool.expand_if_busy(0.25));
+            assert_eq!(pool.workers(), 3);
+
+            release_tx.send(()).unwrap();
+        });
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_execute_continuation() {
+        use crate::Continuation;
+
+        let pool = Pool::new(1);
+        let log = Mutex::new(Vec::new());
+        let (queued_tx, queued_rx) = crossbeam::channel::unbounded::<()>();
+
+        pool.scoped(|scope| {
+            scope.execute_continuation(0, |step| {
+                if step == 0 {
+                    let _ = queued_rx.recv();
+                }
+                log.lock().unwrap().push(step);
+                if step < 3 {
+                    Continuation::Continue(step + 1)
+                } else {
+                    Continuation::Done
+                }
+            });
+
+            // A job queued behind the first step runs before the second.
+            scope.execute(|| log.lock().unwrap().push(100));
+            queued_tx.send(()).unwrap();
+        });
+
+        assert_eq!(*log.lock().unwrap(), vec![0, 100, 1, 2, 3]);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_run_on_current_thread() {
+        let pool = Pool::empty();
+        let sum = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                pool.wait_ready(1);
+                pool.scoped(|scope| {
+                    for i in 0..10 {
+                        let sum = &sum;
+                        scope.execute(move || {
+                            sum.fetch_add(i, Ordering::SeqCst);
+                        });
+                    }
+                });
+                pool.shutdown();
+            });
+
+            // This thread is the only worker, until the shutdown.
+            pool.run_on_current_thread();
+        });
+
+        assert_eq!(sum.load(Ordering::SeqCst), 45);
+        assert_eq!(pool.workers(), 0);
+    }
+
+    #[test]
+    fn test_boost() {
+        let pool = Pool::new(1);
+        let (gate_tx, gate_rx) = crossbeam::channel::unbounded::<()>();
+        let low = Arc::new(AtomicUsize::new(0));
+
+        // Hold the only worker while a backlog builds up.
+        pool.spawn(move || {
+            let _ = gate_rx.recv();
+        });
+        for _ in 0..50 {
+            let low = low.clone();
+            pool.spawn(move || {
+                sleep(Duration::from_micros(200));
+                low.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let low_done = pool.scoped(|scope| {
+            for _ in 0..3 {
+                scope.execute(|| {});
+            }
+
+            assert_eq!(scope.boost(), 3);
+            gate_tx.send(()).unwrap();
+            scope.join();
+            low.load(Ordering::SeqCst)
+        });
+
+        // The scope's jobs ran ahead of the backlog.
+        assert_eq!(low_done, 0);
+        pool.shutdown();
+        assert_eq!(low.load(Ordering::SeqCst), 50);
+    }
+
+    #[test]
+    fn test_join_boosted() {
+        // Join a scope queued behind a backlog of slow spawned jobs,
+        // returning how long the join took and how much of the backlog
+        // finished first.
+        fn run(boosted: bool) -> (Duration, usize) {
+            let pool = Pool::new(1);
+            let (gate_tx, gate_rx) = crossbeam::channel::unbounded::<()>();
+            let low = Arc::new(AtomicUsize::new(0));
+
+            // Hold the only worker while the backlog builds up, letting it
+            // go once the join has had time to start.
+            pool.spawn(move || {
+                let _ = gate_rx.recv();
+            });
+            for _ in 0..50 {
+                let low = low.clone();
+                pool.spawn(move || {
+                    sleep(Duration::from_millis(1));
+                    low.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            let opener = thread::spawn(move || {
+                sleep(Duration::from_millis(20));
+                gate_tx.send(()).unwrap();
+            });
+
+            let result = pool.scoped(|scope| {
+                for _ in 0..3 {
+                    scope.execute(|| {});
+                }
+
+                let start = Instant::now();
+                if boosted {
+                    scope.join_boosted();
+                } else {
+                    scope.join();
+                }
+                (start.elapsed(), low.load(Ordering::SeqCst))
+            });
+
+            opener.join().unwrap();
+            pool.shutdown();
+            assert_eq!(low.load(Ordering::SeqCst), 50);
+            result
+        }
+
+        // A plain join waits for the whole backlog, a boosted one for none
+        // of it.
+        let (plain, plain_low) = run(false);
+        let (boosted, boosted_low) = run(true);
+        assert_eq!(plain_low, 50);
+        assert_eq!(boosted_low, 0);
+        assert!(plain >= Duration::from_millis(50), "{:?}", plain);
+        assert!(boosted < plain, "{:?} vs {:?}", boosted, plain);
+    }
+
+    #[test]
+    fn test_scope_ids() {
+        let pool = Pool::new(2);
+        let (tx, rx) = crossbeam::channel::unbounded();
+
+        pool.scoped(|outer| {
+            let other = Pool::new(1);
+            other.scoped(|scope| assert_ne!(scope.id(), outer.id()));
+            other.shutdown();
+
+            assert_eq!(outer.parent_id(), None);
+
+            outer.zoom(|inner| {
+                assert_ne!(inner.id(), outer.id());
+                assert_eq!(inner.parent_id(), Some(outer.id()));
+            });
+
+            let tx = tx.clone();
+            let id = outer.id();
+            outer.recurse(move |nested| {
+                tx.send((id, nested.id(), nested.parent_id())).unwrap();
+            });
+        });
+
+        let (outer, nested, parent) = rx.recv().unwrap();
+        assert_ne!(nested, outer);
+        assert_eq!(parent, Some(outer));
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_job_handle_join_timeout() {
+        let pool = Pool::new(2);
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+
+        pool.scoped(|scope| {
+            let handle = scope.execute_owned(rx, |rx| {
+                let _ = rx.recv();
+                7
+            });
+
+            let handle = match handle.join_timeout(Duration::from_millis(20)) {
+                Ok(_) => panic!("job finished before being released"),
+                Err(handle) => handle,
+            };
+            assert!(!handle.is_finished());
+
+            tx.send(()).unwrap();
+            assert_eq!(handle.join_timeout(Duration::from_secs(5)).ok(), Some(7));
+        });
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_compact() {
+        let pool = PoolBuilder::new().size(3).min_workers(1).build();
+
+        pool.scoped(|scope| {
+            for _ in 0..100 {
+                scope.execute(|| thread::sleep(Duration::from_micros(10)));
+            }
+        });
+        pool.scoped(|scope| scope.zoom(|_| ()));
+        assert!(!pool.inner.free_wait_groups.is_empty());
+
+        pool.compact();
+        assert_eq!(pool.queued_jobs(), 0);
+        assert_eq!(pool.configured_workers(), 1);
+        assert!(pool.inner.free_wait_groups.is_empty());
+
+        pool.wait_workers(1);
+
+        // The remaining worker still runs jobs.
+        let ran = AtomicBool::new(false);
+        pool.scoped(|scope| scope.execute(|| ran.store(true, Ordering::SeqCst)));
+        assert!(ran.load(Ordering::SeqCst));
+
+        pool.shutdown();
+
+        // With no workers to drain the queue, it returns without waiting.
+        let pool = PoolBuilder::new().build();
+        let scope = Scope::forever(pool.clone());
+        scope.execute(|| ());
+
+        pool.compact();
+        assert_eq!(pool.queued_jobs(), 1);
+    }
+
+    #[test]
+    fn test_shutdown_quits_every_worker() {
+        let pool = Pool::new(8);
+        pool.wait_ready(8);
+
+        pool.scoped(|scope| {
+            for _ in 0..100 {
+                scope.execute(|| ());
+            }
+        });
+
+        pool.shutdown();
+        assert_eq!(pool.workers(), 0);
+
+        // Each worker used up exactly one Quit, leaving nothing queued.
+        assert!(pool.inner.queue.try_pop().is_none());
+    }
+
+    #[test]
+    fn test_fail_fast() {
+        fn panic_message(result: thread::Result<()>) -> String {
+            let payload = result.unwrap_err();
+            match payload.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => payload.downcast_ref::<String>().unwrap().clone(),
+            }
+        }
+
+        let pool = PoolBuilder::new().size(1).fail_fast(true).build();
+        let ran = AtomicBool::new(false);
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+
+        // The job queued behind the panic is dropped, and the join unblocks.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| {
+                scope.execute(move || {
+                    let _ = rx.recv();
+                    panic!("boom");
+                });
+                scope.execute(|| ran.store(true, Ordering::SeqCst));
+                tx.send(()).unwrap();
+            })
+        }));
+        assert!(result.is_err());
+        assert!(pool.is_dead());
+        assert!(!ran.load(Ordering::SeqCst));
+
+        pool.wait_workers(0);
+        assert_eq!(pool.configured_workers(), 0);
+
+        let scoped = panic::catch_unwind(AssertUnwindSafe(|| pool.scoped(|_| ())));
+        assert_eq!(panic_message(scoped), "Pool is dead!");
+
+        let spawn = panic::catch_unwind(AssertUnwindSafe(|| pool.spawn(|| ())));
+        assert_eq!(panic_message(spawn), "Pool is dead!");
+
+        let scope = Scope::forever(pool.clone());
+        let execute = panic::catch_unwind(AssertUnwindSafe(|| scope.execute(|| ())));
+        assert_eq!(panic_message(execute), "Pool is dead!");
+    }
+
+    #[test]
+    fn test_execute_replicated() {
+        let pool = Pool::new(4);
+        let count = AtomicUsize::new(0);
+
+        pool.scoped(|scope| {
+            scope.execute_replicated(10, || {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+
+            // Every copy is done on return.
+            assert_eq!(count.load(Ordering::SeqCst), 10);
+        });
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| scope.execute_replicated(3, || panic!()))
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_worker_lifecycle_hooks() {
+        use crate::StopReason;
+
+        fn hooked(
+            builder: PoolBuilder,
+        ) -> (Pool, crossbeam::channel::Receiver<Option<StopReason>>) {
+            let (tx, rx) = crossbeam::channel::unbounded();
+            let start = tx.clone();
+
+            let pool = builder
+                .on_worker_start(Box::new(move |_| start.send(None).unwrap()))
+                .on_worker_stop(Box::new(move |_, reason| tx.send(Some(reason)).unwrap()))
+                .build();
+            (pool, rx)
+        }
+
+        let (pool, events) = hooked(PoolBuilder::new().size(2));
+        pool.wait_ready(2);
+        pool.contract();
+        assert_eq!(events.recv().unwrap(), None);
+        assert_eq!(events.recv().unwrap(), None);
+        assert_eq!(events.recv().unwrap(), Some(StopReason::Retired));
+        pool.shutdown();
+        assert_eq!(events.recv().unwrap(), Some(StopReason::Quit));
+
+        // A panicked worker stops before its replacement starts.
+        let (pool, events) = hooked(PoolBuilder::new().size(1));
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| scope.execute(|| panic!()))
+        }));
+        assert_eq!(events.recv().unwrap(), None);
+        assert_eq!(events.recv().unwrap(), Some(StopReason::Panicked));
+        assert_eq!(events.recv().unwrap(), None);
+
+        let builder = PoolBuilder::new()
+            .size(1)
+            .auto_shutdown_after(Duration::from_millis(20));
+        let (_pool, events) = hooked(builder);
+        assert_eq!(events.recv().unwrap(), None);
+        assert_eq!(events.recv().unwrap(), Some(StopReason::IdleTimeout));
+    }
+
+    #[test]
+    fn test_scoped_find() {
+        let pool = Pool::new(1);
+        let ran = AtomicUsize::new(0);
+
+        // The first job to run reports, and the rest are skipped.
+        let found = pool.scoped_find(|scope, finder| {
+            for i in 0..100 {
+                let (finder, ran) = (finder.clone(), &ran);
+                scope.execute(move || {
+                    ran.fetch_add(1, Ordering::SeqCst);
+                    finder.report(i);
+                });
+            }
+        });
+        assert_eq!(found, Some(0));
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+
+        let none = pool.scoped_find(|scope, finder| {
+            let finder = finder.clone();
+            scope.execute(move || assert!(!finder.is_found()));
+        });
+        assert_eq!(none, None::<u8>);
+
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_on_join() {
+        let pool = Pool::new(2);
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let (release, released) = crossbeam::channel::unbounded::<()>();
+
+        let scope = Scope::forever(pool.clone());
+        scope.execute(move || {
+            let _ = released.recv();
+        });
+
+        let done = tx.clone();
+        scope
+            .on_join(move |clean| done.send(clean).unwrap())
+            .unwrap();
+
+        // The callback waits for the job, without blocking this thread.
+        assert!(rx.try_recv().is_err());
+        release.send(()).unwrap();
+        assert!(rx.recv().unwrap());
+
+        let scope = Scope::forever(pool.clone());
+        scope.execute(|| panic!());
+        scope.on_join(move |clean| tx.send(clean).unwrap()).unwrap();
+        assert!(!rx.recv().unwrap());
+    }
+
+    #[test]
+    fn test_on_join_reuses_thread() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let pool = PoolBuilder::new()
+            .size(2)
+            .spawner(CountingSpawn(spawned.clone()))
+            .build();
+        let (tx, rx) = crossbeam::channel::unbounded();
+
+        // Scopes finishing in any order are each called back, including
+        // their held back jobs, all from the one thread.
+        for i in 0..10 {
+            let scope = Scope::forever(pool.clone());
+            let ran = Arc::new(AtomicUsize::new(0));
+            let counted = ran.clone();
+            scope.execute(move || {
+                sleep(Duration::from_millis((10 - i) * 2));
+                counted.fetch_add(1, Ordering::SeqCst);
+            });
+            let counted = ran.clone();
+            scope.execute_finally(move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+            });
+
+            let done = tx.clone();
+            let callback = move |clean| done.send((clean, ran.load(Ordering::SeqCst))).unwrap();
+            scope.on_join(callback).unwrap();
+        }
 
-impl ThreadSentinel {
-    fn cancel(&mut self) {
-        if let Some(pool) = self.0.take() {
-            pool.wait.complete();
+        for _ in 0..10 {
+            assert_eq!(rx.recv().unwrap(), (true, 2));
         }
+        assert_eq!(spawned.load(Ordering::SeqCst), 3);
+
+        // A spawner error comes back instead of a callback.
+        let pool = PoolBuilder::new()
+            .size(1)
+            .spawner(LimitedSpawn(Arc::new(AtomicUsize::new(1))))
+            .build();
+        let scope = Scope::forever(pool.clone());
+        assert!(scope.on_join(move |_| tx.send((true, 0)).unwrap()).is_err());
+        assert!(rx.recv().is_err());
+        pool.shutdown();
     }
-}
 
-impl Drop for ThreadSentinel {
-    fn drop(&mut self) {
-        if let Some(pool) = self.0.take() {
-            // NOTE: We restart the thread first so we don't accidentally
-            // hit zero threads before restarting.
+    #[test]
+    fn test_recursion_order() {
+        use crate::RecursionOrder;
+
+        // Fan out a binary tree, recording each node's level and the
+        // longest the queue gets.
+        fn visit<'a>(
+            scope: &Scope<'a>,
+            level: usize,
+            log: &'a Mutex<Vec<usize>>,
+            peak: &'a AtomicUsize,
+        ) {
+            log.lock().unwrap().push(level);
+            peak.fetch_max(scope.pool().queued_jobs(), Ordering::SeqCst);
+
+            if level < 3 {
+                for _ in 0..2 {
+                    scope.recurse(move |scope| visit(scope, level + 1, log, peak));
+                }
+            }
+        }
 
-            // Restart the thread.
-            pool.expand();
+        fn run(builder: PoolBuilder, order: RecursionOrder) -> (Vec<usize>, usize) {
+            let pool = builder.size(1).build();
+            let (log, peak) = (Mutex::new(Vec::new()), AtomicUsize::new(0));
 
-            // Poison the pool.
-            pool.wait.poison();
+            pool.scoped(|scope| {
+                let (log, peak) = (&log, &peak);
+                scope
+                    .with_recursion_order(order)
+                    .recurse(move |scope| visit(scope, 0, log, peak));
+            });
+            pool.shutdown();
+
+            (log.into_inner().unwrap(), peak.into_inner())
         }
-    }
-}
 
-trait Task {
-    fn run(self: Box<Self>);
-}
+        for builder in [PoolBuilder::new, || PoolBuilder::new().ordered_start(true)] {
+            let (breadth, wide) = run(builder(), RecursionOrder::BreadthFirst);
+            assert_eq!(breadth, [0, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 3, 3]);
 
-impl<F: FnOnce()> Task for F {
-    fn run(self: Box<Self>) {
-        (*self)()
+            let (depth, narrow) = run(builder(), RecursionOrder::DepthFirst);
+            assert_eq!(depth, [0, 1, 2, 3, 3, 2, 3, 3, 1, 2, 3, 3, 2, 3, 3]);
+            assert!(narrow < wide, "{} !< {}", narrow, wide);
+        }
     }
-}
-
-#[cfg(test)]
-mod test {
-    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-    use std::thread::sleep;
-    use std::time::Duration;
-
-    use {crate::Pool, crate::Scope, crate::ThreadConfig};
 
     #[test]
-    fn test_simple_use() {
-        let pool = Pool::new(4);
-
-        let mut buf = [0, 0, 0, 0];
+    fn test_max_concurrent_tasks() {
+        use std::sync::Barrier;
 
+        // Without a limit, every worker can be busy at once.
+        let pool = Pool::new(3);
+        let barrier = Barrier::new(3);
         pool.scoped(|scope| {
-            for i in &mut buf {
-                scope.execute(move || *i += 1);
+            for _ in 0..3 {
+                scope.execute(|| {
+                    barrier.wait();
+                });
             }
         });
+        assert_eq!(pool.peak_concurrency(), 3);
+        pool.shutdown();
 
-        assert_eq!(&buf, &[1, 1, 1, 1]);
+        let pool = PoolBuilder::new().size(4).max_concurrent_tasks(2).build();
+        let (running, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        pool.scoped(|scope| {
+            for _ in 0..16 {
+                scope.execute(|| {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(1));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+        assert!((1..=2).contains(&pool.peak_concurrency()));
+        pool.shutdown();
     }
 
     #[test]
-    fn test_zoom() {
-        let pool = Pool::new(4);
-
-        let mut outer = 0;
-
-        pool.scoped(|scope| {
-            let mut inner = 0;
-            scope.zoom(|scope2| scope2.execute(|| inner = 1));
-            assert_eq!(inner, 1);
+    fn test_submit_raw() {
+        let pool = Pool::new(2);
+        let wait = Arc::new(WaitGroup::new());
+        let count = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..4 {
+            wait.submit();
+            let count = count.clone();
+            pool.submit_raw(
+                Box::new(move || {
+                    count.fetch_add(1, Ordering::SeqCst);
+                }),
+                wait.clone(),
+            );
+        }
 
-            outer = 1;
-        });
+        wait.join();
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+        assert_eq!(wait.waiting(), 0);
 
-        assert_eq!(outer, 1);
+        pool.shutdown();
     }
 
     #[test]
-    fn test_recurse() {
-        let pool = Pool::new(12);
-
-        let mut buf = [0, 0, 0, 0];
+    fn test_cancel_token_tree() {
+        let pool = Pool::new(1);
+        let (outer_ran, inner_ran) = (AtomicBool::new(false), AtomicBool::new(false));
+
+        // Cancelling the parent skips its subscope's queued jobs.
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+        pool.scoped(|outer| {
+            let token = outer.cancel_token();
+            outer.execute(move || {
+                let _ = rx.recv();
+            });
 
-        pool.scoped(|next| {
-            next.recurse(|next| {
-                buf[0] = 1;
+            outer.zoom(|inner| {
+                inner.execute(|| inner_ran.store(true, Ordering::SeqCst));
+                token.cancel();
+                assert!(inner.cancel_token().is_cancelled());
+                tx.send(()).unwrap();
+            });
+            outer.execute(|| outer_ran.store(true, Ordering::SeqCst));
+        });
+        assert!(!inner_ran.load(Ordering::SeqCst));
+        assert!(!outer_ran.load(Ordering::SeqCst));
+
+        // Cancelling the subscope leaves the parent running.
+        let (tx, rx) = crossbeam::channel::unbounded::<()>();
+        pool.scoped(|outer| {
+            outer.execute(move || {
+                let _ = rx.recv();
+            });
+            outer.execute(|| outer_ran.store(true, Ordering::SeqCst));
 
-                next.execute(|| {
-                    buf[1] = 1;
-                });
+            outer.zoom(|inner| {
+                inner.execute(|| inner_ran.store(true, Ordering::SeqCst));
+                inner.cancel_token().cancel();
+                assert!(!outer.cancel_token().is_cancelled());
+                tx.send(()).unwrap();
             });
         });
+        assert!(!inner_ran.load(Ordering::SeqCst));
+        assert!(outer_ran.load(Ordering::SeqCst));
 
-        assert_eq!(&buf, &[1, 1, 0, 0]);
+        pool.shutdown();
     }
 
     #[test]
-    fn test_spawn_doesnt_hang() {
-        let pool = Pool::new(1);
-        pool.spawn(move || loop {});
+    fn test_wait_workers() {
+        let pool = Pool::new(3);
+        pool.wait_ready(3);
+
+        pool.contract();
+        pool.contract();
+        pool.wait_workers(1);
+        assert_eq!(pool.workers(), 1);
+
+        // Already at the target.
+        pool.wait_workers(2);
+
+        pool.shutdown();
+        pool.wait_workers(0);
     }
 
     #[test]
-    fn test_forever_zoom() {
-        let pool = Pool::new(16);
-        let forever = Scope::forever(pool.clone());
+    fn test_scope_defer() {
+        let pool = Pool::new(2);
+        let order = Mutex::new(Vec::new());
 
-        let ran = AtomicBool::new(false);
+        pool.scoped(|scope| {
+            scope.zoom(|inner| {
+                for i in 0..3 {
+                    let order = &order;
+                    inner.defer(move || order.lock().unwrap().push(i));
+                }
+                inner.execute(|| order.lock().unwrap().push(10));
+            });
 
-        forever.zoom(|scope| scope.execute(|| ran.store(true, Ordering::SeqCst)));
+            // The inner cleanups ran LIFO once its jobs had finished.
+            assert_eq!(*order.lock().unwrap(), [10, 2, 1, 0]);
+        });
 
-        assert!(ran.load(Ordering::SeqCst));
+        // Cleanups run on the panic path too.
+        let cleaned = AtomicBool::new(false);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| {
+                scope.defer(|| cleaned.store(true, Ordering::SeqCst));
+                scope.execute(|| panic!());
+            })
+        }));
+        assert!(result.is_err());
+        assert!(cleaned.load(Ordering::SeqCst));
     }
 
     #[test]
-    fn test_shutdown() {
-        let pool = Pool::new(4);
+    fn test_typed_pool() {
+        let pool = Pool::new(2);
+        let typed = pool.typed::<u64>();
+
+        for i in 0..10 {
+            typed.submit(move || i * i);
+        }
+        let results = typed.submit(|| 100);
+
+        let mut squares: Vec<u64> = results.iter().take(11).collect();
+        squares.sort_unstable();
+        assert_eq!(squares, [0, 1, 4, 9, 16, 25, 36, 49, 64, 81, 100]);
+        assert!(typed.results().try_recv().is_err());
+
         pool.shutdown();
     }
 
     #[test]
-    #[should_panic]
-    fn test_scheduler_panic() {
-        let pool = Pool::new(4);
-        pool.scoped(|_| panic!());
-    }
+    fn test_shutdown_cancel_spawned() {
+        use crate::ShutdownPolicy;
+        use crossbeam::channel::unbounded;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let pool = Pool::new(2);
+        let (release_tx, release_rx) = unbounded::<()>();
+        let (started_tx, started_rx) = unbounded();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        // Hold a worker, so the running job must finish during shutdown.
+        pool.spawn(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().ok();
+        });
+        started_rx.recv().unwrap();
 
-    #[test]
-    #[should_panic]
-    fn test_scoped_execute_panic() {
-        let pool = Pool::new(4);
-        pool.scoped(|scope| scope.execute(|| panic!()));
-    }
+        for _ in 0..1000 {
+            let ran = ran.clone();
+            pool.spawn(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
 
-    #[test]
-    #[should_panic]
-    fn test_pool_panic() {
-        let _pool = Pool::new(1);
-        panic!();
+        drop(release_tx);
+        let summary = pool.shutdown_with(ShutdownPolicy::CancelSpawned);
+
+        // Every spawned job either ran or was discarded, and nothing
+        // restarted.
+        assert_eq!(ran.load(Ordering::SeqCst) + summary.dropped(), 1000);
+        assert!(pool.is_spawned_work_complete());
+        assert_eq!(pool.workers(), 0);
     }
 
     #[test]
-    #[should_panic]
-    fn test_zoomed_scoped_execute_panic() {
-        let pool = Pool::new(4);
-        pool.scoped(|scope| scope.zoom(|scope2| scope2.execute(|| panic!())));
+    fn test_shutdown_spawned_panic_does_not_hang() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let pool = Pool::new(2);
+        for i in 0..200 {
+            pool.spawn(move || {
+                if i % 50 == 49 {
+                    panic!("spawned job failed");
+                }
+            });
+        }
+
+        // The panics propogate out of the shutdown, rather than losing the
+        // Quits and leaving it waiting.
+        let result = catch_unwind(AssertUnwindSafe(|| pool.shutdown()));
+        assert!(result.is_err());
+        assert_eq!(pool.workers(), 0);
+
+        // A worker which panicked before taking its Quit isn't replaced,
+        // so that Quit may legitimately stay queued; nothing else does.
+        let mut quits = 0;
+        while let Some(message) = pool.inner.queue.try_pop() {
+            assert!(matches!(message, PoolMessage::Quit));
+            quits += 1;
+        }
+        assert!(quits <= 2);
     }
 
     #[test]
-    #[should_panic]
-    fn test_recurse_scheduler_panic() {
+    fn test_shutdown_leaves_queue_empty() {
         let pool = Pool::new(4);
-        pool.scoped(|scope| scope.recurse(|_| panic!()));
+
+        for _ in 0..100 {
+            pool.spawn(|| sleep(Duration::from_micros(10)));
+        }
+        pool.shutdown();
+
+        // Quits taken while draining were handed back and used up too.
+        assert_eq!(pool.workers(), 0);
+        assert!(pool.inner.queue.try_pop().is_none());
     }
 
+    #[cfg(feature = "async")]
     #[test]
-    #[should_panic]
-    fn test_recurse_execute_panic() {
-        let pool = Pool::new(4);
-        pool.scoped(|scope| scope.recurse(|scope2| scope2.execute(|| panic!())));
-    }
+    fn test_spawn_blocking() {
+        use crate::block_on;
+        use crossbeam::channel::unbounded;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
 
-    struct Canary<'a> {
-        drops: DropCounter<'a>,
-        expected: usize,
-    }
+        let pool = Pool::new(2);
 
-    #[derive(Clone)]
-    struct DropCounter<'a>(&'a AtomicUsize);
+        let sum = block_on(pool.spawn_blocking(|| (1..=100).sum::<u32>()));
+        assert_eq!(sum, 5050);
 
-    impl<'a> Drop for DropCounter<'a> {
-        fn drop(&mut self) {
-            self.0.fetch_add(1, Ordering::SeqCst);
-        }
-    }
+        // Panics come out of the poll, and leave the pool usable.
+        let failed = pool.spawn_blocking(|| -> u32 { panic!("job failed") });
+        assert!(catch_unwind(AssertUnwindSafe(|| block_on(failed))).is_err());
 
-    impl<'a> Drop for Canary<'a> {
-        fn drop(&mut self) {
-            let drops = self.drops.0.load(Ordering::SeqCst);
-            assert_eq!(drops, self.expected);
-        }
+        // Dropping the future leaves the job to run.
+        let (tx, rx) = unbounded();
+        drop(pool.spawn_blocking(move || tx.send(7).unwrap()));
+        assert_eq!(rx.recv().unwrap(), 7);
+
+        pool.shutdown();
     }
 
     #[test]
-    #[should_panic]
-    fn test_scoped_panic_waits_for_all_tasks() {
-        let tasks = 50;
-        let panicking_task_fraction = 10;
-        let panicking_tasks = tasks / panicking_task_fraction;
-        let expected_drops = tasks + panicking_tasks;
-
-        let counter = Box::new(AtomicUsize::new(0));
-        let drops = DropCounter(&*counter);
-
-        // Actual check occurs on drop of this during unwinding.
-        let _canary = Canary {
-            drops: drops.clone(),
-            expected: expected_drops,
-        };
+    fn test_slow_task_threshold() {
+        use crossbeam::channel::unbounded;
 
-        let pool = Pool::new(12);
+        let (tx, rx) = unbounded();
+        let pool = PoolBuilder::new()
+            .size(2)
+            .slow_task_threshold(
+                Duration::from_millis(20),
+                Box::new(move |label, elapsed| tx.send((label, elapsed)).unwrap()),
+            )
+            .build();
 
         pool.scoped(|scope| {
-            for task in 0..tasks {
-                let drop_counter = drops.clone();
+            scope.execute_labeled("slow", || sleep(Duration::from_millis(100)));
+        });
 
-                scope.execute(move || {
-                    sleep(Duration::from_millis(10));
+        // Reported while running, and only once despite being checked again.
+        let reports = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].0, Some("slow"));
+        assert!(reports[0].1 >= Duration::from_millis(20));
 
-                    drop::<DropCounter>(drop_counter);
-                });
+        pool.shutdown();
+    }
 
-                if task % panicking_task_fraction == 0 {
-                    let drop_counter = drops.clone();
+    #[test]
+    fn test_scope_structured() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-                    scope.execute(move || {
-                        // Just make sure we capture it.
-                        let _drops = drop_counter;
-                        panic!();
-                    });
-                }
+        let pool = Pool::new(2);
+        let counter = AtomicUsize::new(0);
+
+        let total = pool.scope_structured(|scope| {
+            for _ in 0..10 {
+                scope.execute(|| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
             }
+            "scheduled"
         });
+        assert_eq!(total, "scheduled");
+        assert_eq!(counter.load(Ordering::SeqCst), 10);
+
+        // A panicking scheduler still waits for its jobs.
+        let finished = AtomicBool::new(false);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            pool.scope_structured(|scope| {
+                scope.execute(|| {
+                    sleep(Duration::from_millis(20));
+                    finished.store(true, Ordering::SeqCst);
+                });
+                panic!("scheduler failed");
+            })
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"scheduler failed"));
+        assert!(finished.load(Ordering::SeqCst));
+
+        // With a job panicking too, the scheduler's panic wins instead of
+        // aborting.
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            pool.scope_structured(|scope| {
+                scope.execute(|| panic!("job failed"));
+                panic!("scheduler failed");
+            })
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"scheduler failed"));
     }
 
     #[test]
-    #[should_panic]
-    fn test_scheduler_panic_waits_for_tasks() {
-        let tasks = 50;
-        let counter = Box::new(AtomicUsize::new(0));
-        let drops = DropCounter(&*counter);
-
-        let _canary = Canary {
-            drops: drops.clone(),
-            expected: tasks,
-        };
+    fn test_contract_keeps_local_jobs() {
+        use crate::BlockingQueue;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // A worker leaving hands its deque back to the shared queue.
+        let queue = BlockingQueue::new();
+        {
+            let _worker = queue.register();
+            for i in 0..3 {
+                queue.push(i);
+            }
+        }
+        let left = std::iter::from_fn(|| queue.try_pop()).collect::<Vec<_>>();
+        assert_eq!(left, vec![0, 1, 2]);
 
-        let pool = Pool::new(12);
+        // Contracting while workers hold jobs on their deques loses none.
+        let pool = Pool::new(4);
+        let counter = AtomicUsize::new(0);
 
         pool.scoped(|scope| {
-            for _ in 0..tasks {
-                let drop_counter = drops.clone();
-
-                scope.execute(move || {
-                    sleep(Duration::from_millis(25));
-                    drop::<DropCounter>(drop_counter);
+            for worker in 0..4 {
+                let (pool, counter) = (&pool, &counter);
+                scope.recurse(move |scope| {
+                    for _ in 0..25 {
+                        scope.execute(move || {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                        });
+                    }
+                    if worker == 0 {
+                        for _ in 0..3 {
+                            pool.contract();
+                        }
+                    }
                 });
             }
-
-            panic!();
         });
+
+        assert_eq!(counter.load(Ordering::SeqCst), 100);
+        pool.wait_workers(1);
+        pool.shutdown();
     }
 
     #[test]
-    fn test_no_thread_config() {
-        let pool = Pool::new(1);
+    fn test_checkpoint() {
+        use crossbeam::channel::unbounded;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let pool = Pool::new(2);
+        let scope = Scope::forever(pool.clone());
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..10 {
+            let counter = counter.clone();
+            scope.execute(move || {
+                sleep(Duration::from_millis(1));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
 
-        pool.scoped(|scope| {
-            scope.execute(|| {
-                assert!(::std::thread::current().name().is_none());
+        // A job queued after the checkpoint isn't waited for, even while
+        // it's still held up.
+        let (release_tx, release_rx) = unbounded::<()>();
+        thread::scope(|threads| {
+            let checkpointed = threads.spawn(|| {
+                scope.checkpoint();
+                counter.load(Ordering::SeqCst)
             });
+
+            while scope.wait.epoch.load(Ordering::SeqCst) == 0 {
+                thread::yield_now();
+            }
+            scope.execute(move || {
+                release_rx.recv().ok();
+            });
+
+            assert_eq!(checkpointed.join().unwrap(), 10);
         });
+        assert!(!scope.is_complete());
+
+        // The scope is still usable afterwards.
+        drop(release_tx);
+        scope.checkpoint();
+        assert!(scope.is_complete());
+
+        pool.shutdown();
     }
 
     #[test]
-    fn test_with_thread_config() {
-        let config = ThreadConfig::new().prefix("pool-");
-
-        let pool = Pool::with_thread_config(1, config);
+    fn test_cancel_on_panic() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // With one worker, the rest are still queued when the first panics.
+        let run = |pool: &Pool, cancel: Option<bool>| {
+            let counter = AtomicUsize::new(0);
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                pool.scoped(|scope| {
+                    if let Some(cancel) = cancel {
+                        scope.cancel_on_panic(cancel);
+                    }
+                    scope.execute(|| panic!("job failed"));
+                    for _ in 0..20 {
+                        scope.execute(|| {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                        });
+                    }
+                })
+            }));
+            assert!(result.is_err());
+            counter.load(Ordering::SeqCst)
+        };
 
-        pool.scoped(|scope| {
-            scope.execute(|| {
-                assert_eq!(::std::thread::current().name().unwrap(), "pool-1");
+        let pool = Pool::new(1);
+        assert_eq!(run(&pool, None), 20);
+        assert_eq!(run(&pool, Some(true)), 0);
+
+        let pool = PoolBuilder::new().size(1).cancel_on_panic(true).build();
+        assert_eq!(run(&pool, None), 0);
+        assert_eq!(run(&pool, Some(false)), 20);
+
+        // Spawned jobs carry on regardless.
+        let counter = Arc::new(AtomicUsize::new(0));
+        pool.spawn(|| panic!("spawn failed"));
+        for _ in 0..20 {
+            let counter = counter.clone();
+            pool.spawn(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
             });
-        });
+        }
+        while counter.load(Ordering::SeqCst) < 20 {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_prewarm() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let started = Arc::new(AtomicUsize::new(0));
+        let counter = started.clone();
+        let pool = PoolBuilder::new()
+            .size(3)
+            .on_worker_start(Box::new(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }))
+            .build();
+
+        // Every worker has started and taken a job by the time it returns.
+        pool.prewarm();
+        assert_eq!(started.load(Ordering::SeqCst), 3);
+        assert_eq!(pool.current_jobs().len(), 3);
+
+        pool.shutdown();
     }
 }